@@ -0,0 +1,765 @@
+use crate::ui::*;
+
+use bincode::{config, Decode, Encode};
+use chrono::{Local, TimeZone};
+use lazy_static::lazy_static;
+use slint::{ComponentHandle, VecModel};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+};
+
+use crate::caw::{
+    history,
+    protocols::{discover::type_name, timesync::host_time},
+};
+
+/// 告警规则在设置表中的键
+const ALARM_RULES_KEY: &str = "alarm_rules";
+/// 告警日志保留的最大条数
+const MAX_LOG_ENTRIES: usize = 200;
+
+lazy_static! {
+    static ref ALARM_ENGINE: Mutex<AlarmEngine> = Mutex::new(AlarmEngine::new(load_rules()));
+}
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+#[derive(Debug, Clone)]
+pub enum AlarmError {
+    Field(i32),
+    Index(i32),
+    Condition(i32),
+}
+
+impl std::fmt::Display for AlarmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            AlarmError::Field(field) => {
+                write!(f, "invalid alarm field: {}", field)
+            }
+            AlarmError::Index(index) => {
+                write!(f, "invalid alarm field index: {}", index)
+            }
+            AlarmError::Condition(condition) => {
+                write!(f, "invalid alarm condition: {}", condition)
+            }
+        }
+    }
+}
+
+impl std::error::Error for AlarmError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            AlarmError::Field(_) => None,
+            AlarmError::Index(_) => None,
+            AlarmError::Condition(_) => None,
+        }
+    }
+}
+
+/// 可设置告警的遥测字段，顺序与界面的选项一致，新增字段只能加在末尾
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq)]
+pub enum AlarmField {
+    Voltage,
+    Current,
+    Soc,
+    Soh,
+    Temperature,
+    MinCellVoltage,
+    MaxCellVoltage,
+    CellSpread,
+    /// 单节电芯电压，序号从0开始
+    CellVoltage(u8),
+    /// 单个温度传感器，序号从0开始
+    TempSensor(u8),
+    MotorSpeed,
+    /// 三相电流绝对值的最大值
+    MotorPhaseCurrent,
+    MotorBusVoltage,
+    MotorTemperature,
+}
+
+pub const ALARM_FIELDS: [AlarmField; 14] = [
+    AlarmField::Voltage,
+    AlarmField::Current,
+    AlarmField::Soc,
+    AlarmField::Soh,
+    AlarmField::Temperature,
+    AlarmField::MinCellVoltage,
+    AlarmField::MaxCellVoltage,
+    AlarmField::CellSpread,
+    AlarmField::CellVoltage(0),
+    AlarmField::TempSensor(0),
+    AlarmField::MotorSpeed,
+    AlarmField::MotorPhaseCurrent,
+    AlarmField::MotorBusVoltage,
+    AlarmField::MotorTemperature,
+];
+
+impl TryFrom<i32> for AlarmField {
+    type Error = AlarmError;
+
+    fn try_from(index: i32) -> std::result::Result<Self, Self::Error> {
+        usize::try_from(index)
+            .ok()
+            .and_then(|i| ALARM_FIELDS.get(i).copied())
+            .ok_or(AlarmError::Field(index))
+    }
+}
+
+impl AlarmField {
+    /// 界面选项与序号组成字段，序号从1开始，只用于电芯与温度传感器
+    pub fn from_option(option: i32, index: i32) -> std::result::Result<Self, AlarmError> {
+        let index = u8::try_from(index.max(1) - 1).map_err(|_| AlarmError::Index(index))?;
+        Ok(match AlarmField::try_from(option)? {
+            AlarmField::CellVoltage(_) => AlarmField::CellVoltage(index),
+            AlarmField::TempSensor(_) => AlarmField::TempSensor(index),
+            field => field,
+        })
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            AlarmField::Voltage => "Voltage".into(),
+            AlarmField::Current => "Current".into(),
+            AlarmField::Soc => "SOC".into(),
+            AlarmField::Soh => "SOH".into(),
+            AlarmField::Temperature => "Temperature".into(),
+            AlarmField::MinCellVoltage => "Min cell voltage".into(),
+            AlarmField::MaxCellVoltage => "Max cell voltage".into(),
+            AlarmField::CellSpread => "Cell spread".into(),
+            AlarmField::CellVoltage(i) => format!("Cell {} voltage", i + 1),
+            AlarmField::TempSensor(i) => format!("Temp sensor {}", i + 1),
+            AlarmField::MotorSpeed => "Motor speed".into(),
+            AlarmField::MotorPhaseCurrent => "Motor phase current".into(),
+            AlarmField::MotorBusVoltage => "Motor bus voltage".into(),
+            AlarmField::MotorTemperature => "Motor temperature".into(),
+        }
+    }
+
+    pub fn unit(&self) -> &'static str {
+        match self {
+            AlarmField::Voltage
+            | AlarmField::MinCellVoltage
+            | AlarmField::MaxCellVoltage
+            | AlarmField::CellVoltage(_)
+            | AlarmField::MotorBusVoltage => "V",
+            AlarmField::Current | AlarmField::MotorPhaseCurrent => "A",
+            AlarmField::Soc | AlarmField::Soh => "%",
+            AlarmField::Temperature | AlarmField::TempSensor(_) | AlarmField::MotorTemperature => {
+                "℃"
+            }
+            AlarmField::CellSpread => "mV",
+            AlarmField::MotorSpeed => "rpm",
+        }
+    }
+}
+
+/// 触发条件
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq)]
+pub enum AlarmCondition {
+    Above = 0,
+    Below,
+}
+
+impl TryFrom<i32> for AlarmCondition {
+    type Error = AlarmError;
+
+    fn try_from(index: i32) -> std::result::Result<Self, Self::Error> {
+        match index {
+            0 => Ok(AlarmCondition::Above),
+            1 => Ok(AlarmCondition::Below),
+            _ => Err(AlarmError::Condition(index)),
+        }
+    }
+}
+
+impl AlarmCondition {
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            AlarmCondition::Above => ">",
+            AlarmCondition::Below => "<",
+        }
+    }
+}
+
+/// 告警规则
+///
+/// 字段值越过阈值并持续duration秒后触发，
+/// 回到阈值另一侧超过hysteresis后解除
+#[derive(Encode, Decode, Debug, Clone, PartialEq)]
+pub struct AlarmRule {
+    pub id: u32,
+    pub name: String,
+    pub field: AlarmField,
+    pub condition: AlarmCondition,
+    pub threshold: f32,
+    pub hysteresis: f32,
+    pub duration: f32, // s
+    /// 为None时对所有设备生效
+    pub device_id: Option<u32>,
+    pub enabled: bool,
+}
+
+impl AlarmRule {
+    fn is_triggered(&self, value: f32) -> bool {
+        match self.condition {
+            AlarmCondition::Above => value > self.threshold,
+            AlarmCondition::Below => value < self.threshold,
+        }
+    }
+
+    fn is_released(&self, value: f32) -> bool {
+        match self.condition {
+            AlarmCondition::Above => value < self.threshold - self.hysteresis,
+            AlarmCondition::Below => value > self.threshold + self.hysteresis,
+        }
+    }
+
+    fn applies_to(&self, device_id: u32) -> bool {
+        self.enabled && self.device_id.is_none_or(|id| id == device_id)
+    }
+
+    pub fn describe(&self) -> String {
+        format!(
+            "{} {} {}{}",
+            self.field.name(),
+            self.condition.symbol(),
+            self.threshold,
+            self.field.unit()
+        )
+    }
+}
+
+/// 告警日志条目
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlarmEntry {
+    pub id: u32,
+    pub rule_id: u32,
+    pub name: String,
+    pub type_id: u32,
+    pub device_id: u32,
+    pub field: AlarmField,
+    pub value: f32,
+    pub raised: u64, // us
+    pub cleared: Option<u64>,
+    pub acked: bool,
+}
+
+impl AlarmEntry {
+    pub fn is_active(&self) -> bool {
+        self.cleared.is_none()
+    }
+
+    pub fn message(&self) -> String {
+        format!(
+            "{}: {}-{} {} {}{}",
+            self.name,
+            type_name(self.type_id),
+            self.device_id,
+            self.field.name(),
+            self.value,
+            self.field.unit()
+        )
+    }
+}
+
+/// 告警状态变化
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlarmEvent {
+    pub entry: AlarmEntry,
+    pub raised: bool,
+}
+
+#[derive(Debug, Default)]
+struct AlarmState {
+    pending: Option<u64>,
+    active: Option<u32>,
+}
+
+/// 告警规则判定与日志
+#[derive(Debug)]
+pub struct AlarmEngine {
+    rules: Vec<AlarmRule>,
+    /// 以(rule_id, type_id, device_id)为键
+    states: HashMap<(u32, u32, u32), AlarmState>,
+    log: VecDeque<AlarmEntry>,
+    next_entry_id: u32,
+}
+
+impl AlarmEngine {
+    pub fn new(rules: Vec<AlarmRule>) -> Self {
+        Self {
+            rules,
+            states: HashMap::new(),
+            log: VecDeque::new(),
+            next_entry_id: 0,
+        }
+    }
+
+    pub fn get_rules(&self) -> &[AlarmRule] {
+        &self.rules
+    }
+
+    pub fn get_log(&self) -> &VecDeque<AlarmEntry> {
+        &self.log
+    }
+
+    pub fn add_rule(&mut self, mut rule: AlarmRule) {
+        rule.id = self.rules.iter().map(|r| r.id + 1).max().unwrap_or(0);
+        self.rules.push(rule);
+    }
+
+    /// 删除或停用规则时一并解除其告警
+    fn release_rule(&mut self, rule_id: u32, time: u64) {
+        let log = &mut self.log;
+        self.states.retain(|&(id, _, _), state| {
+            if id != rule_id {
+                return true;
+            }
+            if let Some(entry) = state
+                .active
+                .and_then(|e| log.iter_mut().find(|x| x.id == e))
+            {
+                entry.cleared = Some(time);
+            }
+            false
+        });
+    }
+
+    pub fn remove_rule(&mut self, rule_id: u32, time: u64) {
+        self.rules.retain(|r| r.id != rule_id);
+        self.release_rule(rule_id, time);
+    }
+
+    pub fn set_rule_enabled(&mut self, rule_id: u32, enabled: bool, time: u64) {
+        if let Some(rule) = self.rules.iter_mut().find(|r| r.id == rule_id) {
+            rule.enabled = enabled;
+        }
+        if !enabled {
+            self.release_rule(rule_id, time);
+        }
+    }
+
+    /// 用设备的最新数据判定各规则，value返回字段的当前值，无数据时返回None
+    pub fn evaluate(
+        &mut self,
+        type_id: u32,
+        device_id: u32,
+        time: u64,
+        value: impl Fn(AlarmField) -> Option<f32>,
+    ) -> Vec<AlarmEvent> {
+        let mut events = vec![];
+        for rule in self.rules.iter().filter(|r| r.applies_to(device_id)) {
+            let Some(v) = value(rule.field) else {
+                continue;
+            };
+            let state = self
+                .states
+                .entry((rule.id, type_id, device_id))
+                .or_default();
+            match state.active {
+                Some(entry_id) => {
+                    if !rule.is_released(v) {
+                        continue;
+                    }
+                    state.active = None;
+                    state.pending = None;
+                    if let Some(entry) = self.log.iter_mut().find(|e| e.id == entry_id) {
+                        entry.cleared = Some(time);
+                        events.push(AlarmEvent {
+                            entry: entry.clone(),
+                            raised: false,
+                        });
+                    }
+                }
+                None => {
+                    if !rule.is_triggered(v) {
+                        state.pending = None;
+                        continue;
+                    }
+                    let since = *state.pending.get_or_insert(time);
+                    if time.saturating_sub(since) < (rule.duration.max(0.0) * 1_000_000.0) as u64 {
+                        continue;
+                    }
+                    let entry = AlarmEntry {
+                        id: self.next_entry_id,
+                        rule_id: rule.id,
+                        name: rule.name.clone(),
+                        type_id,
+                        device_id,
+                        field: rule.field,
+                        value: v,
+                        raised: time,
+                        cleared: None,
+                        acked: false,
+                    };
+                    self.next_entry_id += 1;
+                    state.active = Some(entry.id);
+                    events.push(AlarmEvent {
+                        entry: entry.clone(),
+                        raised: true,
+                    });
+                    self.log.push_back(entry);
+                }
+            }
+        }
+        // 只丢弃已解除的最早记录，未解除的告警始终保留
+        while self.log.len() > MAX_LOG_ENTRIES {
+            match self.log.iter().position(|e| !e.is_active()) {
+                Some(index) => self.log.remove(index),
+                None => break,
+            };
+        }
+        events
+    }
+
+    pub fn ack(&mut self, entry_id: u32) {
+        if let Some(entry) = self.log.iter_mut().find(|e| e.id == entry_id) {
+            entry.acked = true;
+        }
+    }
+
+    pub fn ack_all(&mut self) {
+        self.log.iter_mut().for_each(|e| e.acked = true);
+    }
+
+    /// 清除已解除的记录，未解除的告警保留
+    pub fn clear_log(&mut self) {
+        self.log.retain(|e| e.is_active());
+    }
+
+    /// 最近一条未确认且未解除的告警
+    pub fn banner(&self) -> Option<&AlarmEntry> {
+        self.log.iter().rev().find(|e| e.is_active() && !e.acked)
+    }
+}
+
+fn default_rules() -> Vec<AlarmRule> {
+    vec![
+        AlarmRule {
+            id: 0,
+            name: "Cell under voltage".into(),
+            field: AlarmField::MinCellVoltage,
+            condition: AlarmCondition::Below,
+            threshold: 3.0,
+            hysteresis: 0.1,
+            duration: 5.0,
+            device_id: None,
+            enabled: true,
+        },
+        AlarmRule {
+            id: 1,
+            name: "Pack over temperature".into(),
+            field: AlarmField::Temperature,
+            condition: AlarmCondition::Above,
+            threshold: 60.0,
+            hysteresis: 5.0,
+            duration: 5.0,
+            device_id: None,
+            enabled: true,
+        },
+    ]
+}
+
+fn decode_rules(buf: &[u8]) -> Result<Vec<AlarmRule>> {
+    let config = config::standard()
+        .with_big_endian()
+        .with_fixed_int_encoding();
+    let (rules, _): (Vec<AlarmRule>, usize) = bincode::decode_from_slice(buf, config)?;
+    Ok(rules)
+}
+
+fn encode_rules(rules: &[AlarmRule]) -> Result<Vec<u8>> {
+    let config = config::standard()
+        .with_big_endian()
+        .with_fixed_int_encoding();
+    Ok(bincode::encode_to_vec(rules, config)?)
+}
+
+fn load_rules() -> Vec<AlarmRule> {
+    history::load_setting(ALARM_RULES_KEY)
+        .and_then(|buf| decode_rules(&buf).ok())
+        .unwrap_or_else(default_rules)
+}
+
+fn save_rules(rules: &[AlarmRule]) {
+    match encode_rules(rules) {
+        Ok(buf) => history::save_setting(ALARM_RULES_KEY, &buf),
+        Err(e) => eprintln!("encode alarm rules failed: {}", e),
+    }
+}
+
+/// 发送桌面通知，在单独的线程中执行避免阻塞
+fn notify(entry: &AlarmEntry) {
+    let summary = format!("Alarm: {}", entry.name);
+    let body = entry.message();
+    std::thread::spawn(move || {
+        if let Err(e) = notify_rust::Notification::new()
+            .appname("CawLink-Desktop")
+            .summary(&summary)
+            .body(&body)
+            .show()
+        {
+            eprintln!("show notification failed: {}", e);
+        }
+    });
+}
+
+/// 判定设备的告警规则，有新告警时发送桌面通知，返回是否有状态变化
+pub fn evaluate(
+    type_id: u32,
+    device_id: u32,
+    time: u64,
+    value: impl Fn(AlarmField) -> Option<f32>,
+) -> bool {
+    let events = match ALARM_ENGINE.lock() {
+        Ok(mut engine) => engine.evaluate(type_id, device_id, time, value),
+        Err(_) => return false,
+    };
+    for event in events.iter() {
+        eprintln!(
+            "alarm {} {}",
+            event.entry.message(),
+            if event.raised { "raised" } else { "cleared" }
+        );
+        if event.raised {
+            notify(&event.entry);
+        }
+    }
+    !events.is_empty()
+}
+
+fn format_time(time: u64) -> String {
+    Local
+        .timestamp_micros(time as i64)
+        .single()
+        .map(|t| t.format("%m-%d %H:%M:%S").to_string())
+        .unwrap_or_default()
+}
+
+/// 将告警规则、日志与横幅显示到界面
+pub fn show_alarms(handle: &AppWindow) {
+    let Ok(engine) = ALARM_ENGINE.lock() else {
+        return;
+    };
+    let service = handle.global::<AlarmModelService>();
+    let rules: Vec<AlarmRuleModel> = engine
+        .get_rules()
+        .iter()
+        .map(|rule| AlarmRuleModel {
+            id: rule.id as i32,
+            name: rule.name.as_str().into(),
+            description: rule.describe().into(),
+            hysteresis: rule.hysteresis,
+            duration: rule.duration,
+            device_id: rule.device_id.map_or(-1, |id| id as i32),
+            enabled: rule.enabled,
+        })
+        .collect();
+    service.set_rules(VecModel::from_slice(rules.as_slice()));
+    let log: Vec<AlarmEntryModel> = engine
+        .get_log()
+        .iter()
+        .rev()
+        .map(|entry| AlarmEntryModel {
+            id: entry.id as i32,
+            message: entry.message().into(),
+            raised: format_time(entry.raised).into(),
+            cleared: entry.cleared.map(format_time).unwrap_or_default().into(),
+            active: entry.is_active(),
+            acked: entry.acked,
+        })
+        .collect();
+    service.set_log(VecModel::from_slice(log.as_slice()));
+    let unacked = engine
+        .get_log()
+        .iter()
+        .filter(|e| e.is_active() && !e.acked)
+        .count();
+    service.set_unacked_count(unacked as i32);
+    match engine.banner() {
+        Some(entry) => {
+            service.set_banner_id(entry.id as i32);
+            service.set_banner(entry.message().into());
+        }
+        None => {
+            service.set_banner_id(-1);
+            service.set_banner("".into());
+        }
+    }
+}
+
+/// 添加告警规则，index为电芯或温度传感器序号，device_id小于0时对所有设备生效
+#[allow(clippy::too_many_arguments)]
+pub fn add_rule(
+    handle: &AppWindow,
+    name: &str,
+    field: i32,
+    index: i32,
+    condition: i32,
+    threshold: f32,
+    hysteresis: f32,
+    duration: f32,
+    device_id: i32,
+) -> Result<()> {
+    let field = AlarmField::from_option(field, index)?;
+    let rule = AlarmRule {
+        id: 0,
+        name: if name.is_empty() {
+            field.name()
+        } else {
+            name.into()
+        },
+        field,
+        condition: AlarmCondition::try_from(condition)?,
+        threshold,
+        hysteresis: hysteresis.max(0.0),
+        duration: duration.max(0.0),
+        device_id: u32::try_from(device_id).ok(),
+        enabled: true,
+    };
+    if let Ok(mut engine) = ALARM_ENGINE.lock() {
+        engine.add_rule(rule);
+        save_rules(engine.get_rules());
+    }
+    show_alarms(handle);
+    Ok(())
+}
+
+pub fn remove_rule(handle: &AppWindow, rule_id: u32) {
+    if let Ok(mut engine) = ALARM_ENGINE.lock() {
+        engine.remove_rule(rule_id, host_time());
+        save_rules(engine.get_rules());
+    }
+    show_alarms(handle);
+}
+
+pub fn set_rule_enabled(handle: &AppWindow, rule_id: u32, enabled: bool) {
+    if let Ok(mut engine) = ALARM_ENGINE.lock() {
+        engine.set_rule_enabled(rule_id, enabled, host_time());
+        save_rules(engine.get_rules());
+    }
+    show_alarms(handle);
+}
+
+/// 确认告警，entry_id小于0时确认全部
+pub fn ack(handle: &AppWindow, entry_id: i32) {
+    if let Ok(mut engine) = ALARM_ENGINE.lock() {
+        match u32::try_from(entry_id) {
+            Ok(id) => engine.ack(id),
+            Err(_) => engine.ack_all(),
+        }
+    }
+    show_alarms(handle);
+}
+
+pub fn clear_log(handle: &AppWindow) {
+    if let Ok(mut engine) = ALARM_ENGINE.lock() {
+        engine.clear_log();
+    }
+    show_alarms(handle);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const S: u64 = 1_000_000;
+
+    fn rule(device_id: Option<u32>) -> AlarmRule {
+        AlarmRule {
+            id: 0,
+            name: "Cell under voltage".into(),
+            field: AlarmField::MinCellVoltage,
+            condition: AlarmCondition::Below,
+            threshold: 3.0,
+            hysteresis: 0.1,
+            duration: 5.0,
+            device_id,
+            enabled: true,
+        }
+    }
+
+    #[test]
+    fn alarm_rule_test() {
+        let buf = encode_rules(&default_rules()).unwrap();
+        assert_eq!(decode_rules(&buf).unwrap(), default_rules());
+        assert_eq!(AlarmField::try_from(4).unwrap(), AlarmField::Temperature);
+        assert!(AlarmField::try_from(14).is_err());
+        assert_eq!(
+            AlarmField::from_option(8, 3).unwrap(),
+            AlarmField::CellVoltage(2)
+        );
+        assert_eq!(
+            AlarmField::from_option(9, 0).unwrap(),
+            AlarmField::TempSensor(0)
+        );
+        assert_eq!(AlarmField::from_option(1, 5).unwrap(), AlarmField::Current);
+        assert!(AlarmField::from_option(8, 300).is_err());
+        assert_eq!(AlarmField::CellVoltage(2).name(), "Cell 3 voltage");
+        assert!(AlarmCondition::try_from(-1).is_err());
+        assert!(!rule(Some(2)).applies_to(1));
+        assert!(rule(None).applies_to(1));
+    }
+
+    #[test]
+    fn alarm_engine_test() {
+        let mut engine = AlarmEngine::new(vec![]);
+        engine.add_rule(rule(None));
+        let mut eval = |time: u64, v: f32| {
+            engine
+                .evaluate(0, 1, time, |_| Some(v))
+                .iter()
+                .map(|e| e.raised)
+                .collect::<Vec<bool>>()
+        };
+        // 持续时间不足不触发
+        assert!(eval(0, 2.9).is_empty());
+        assert!(eval(4 * S, 2.9).is_empty());
+        assert!(eval(5 * S, 3.05).is_empty());
+        assert!(eval(6 * S, 2.9).is_empty());
+        assert_eq!(eval(11 * S, 2.9), vec![true]);
+        assert!(eval(12 * S, 2.8).is_empty());
+        // 回差范围内不解除
+        assert!(eval(13 * S, 3.05).is_empty());
+        assert_eq!(eval(14 * S, 3.2), vec![false]);
+
+        assert_eq!(engine.get_log().len(), 1);
+        assert!(engine.get_log()[0].message().contains("BMS-1"));
+        assert!(engine.banner().is_none());
+        engine.evaluate(0, 1, 20 * S, |_| Some(2.0));
+        // 不同类型的同编号设备分别判定
+        assert!(engine.evaluate(1, 1, 25 * S, |_| Some(2.0)).is_empty());
+        engine.evaluate(0, 1, 30 * S, |_| Some(2.0));
+        let id = engine.banner().unwrap().id;
+        engine.ack(id);
+        assert!(engine.banner().is_none());
+        engine.clear_log();
+        assert_eq!(engine.get_log().len(), 1);
+        engine.remove_rule(0, 40 * S);
+        assert!(!engine.get_log()[0].is_active());
+        engine.clear_log();
+        assert!(engine.get_log().is_empty());
+    }
+
+    #[test]
+    fn alarm_log_limit_test() {
+        let mut rule = rule(None);
+        rule.duration = 0.0;
+        let mut engine = AlarmEngine::new(vec![rule]);
+        // 未解除的告警超出上限时全部保留
+        for device_id in 0..MAX_LOG_ENTRIES as u32 + 5 {
+            engine.evaluate(0, device_id, 0, |_| Some(2.0));
+        }
+        assert_eq!(engine.get_log().len(), MAX_LOG_ENTRIES + 5);
+        for device_id in 0..5 {
+            engine.evaluate(0, device_id, S, |_| Some(3.5));
+        }
+        // 新告警只挤出已解除的记录
+        engine.evaluate(0, 1_000, 2 * S, |_| Some(2.0));
+        assert_eq!(engine.get_log().len(), MAX_LOG_ENTRIES + 1);
+        assert!(engine.get_log().iter().all(|e| e.is_active()));
+    }
+}
@@ -0,0 +1,149 @@
+/// 采样间隔超过该值(us)时不积分，避免断线期间的数据被计入
+const MAX_SAMPLE_GAP: u64 = 10 * 1_000_000;
+const US_PER_HOUR: f64 = 3600.0 * 1_000_000.0;
+
+/// 库仑计
+///
+/// 对电流按时间积分统计充放电的Ah与Wh，电流为正表示充电。
+/// 以首次采样时设备上报的SOC为起点，按设定的容量估算主机侧SOC
+#[derive(Debug, Clone, Default)]
+pub struct CoulombCounter {
+    last: Option<(u64, f32, f32)>,
+    charged_ah: f64,
+    discharged_ah: f64,
+    charged_wh: f64,
+    discharged_wh: f64,
+    initial_soc: Option<f32>,
+    capacity_ah: Option<f32>,
+}
+
+impl CoulombCounter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 更新采样，voltage单位V，current单位A，soc为设备上报的百分比
+    pub fn update(&mut self, time: u64, voltage: f32, current: f32, soc: f32) {
+        if self.initial_soc.is_none() {
+            self.initial_soc = Some(soc);
+        }
+        if let Some((last_time, last_voltage, last_current)) = self.last {
+            let dt = time.saturating_sub(last_time);
+            if dt > 0 && dt <= MAX_SAMPLE_GAP {
+                let hours = dt as f64 / US_PER_HOUR;
+                // 梯形积分
+                let ah = (last_current as f64 + current as f64) / 2.0 * hours;
+                let wh = (last_current as f64 * last_voltage as f64
+                    + current as f64 * voltage as f64)
+                    / 2.0
+                    * hours;
+                if ah >= 0.0 {
+                    self.charged_ah += ah;
+                } else {
+                    self.discharged_ah -= ah;
+                }
+                if wh >= 0.0 {
+                    self.charged_wh += wh;
+                } else {
+                    self.discharged_wh -= wh;
+                }
+            }
+        }
+        self.last = Some((time, voltage, current));
+    }
+
+    /// 重新开始统计，下一次采样的SOC作为新的起点
+    pub fn reset(&mut self) {
+        *self = Self {
+            capacity_ah: self.capacity_ah,
+            ..Default::default()
+        };
+    }
+
+    pub fn set_capacity(&mut self, capacity_ah: Option<f32>) {
+        self.capacity_ah = capacity_ah.filter(|&c| c > 0.0);
+    }
+
+    pub fn get_capacity(&self) -> Option<f32> {
+        self.capacity_ah
+    }
+
+    pub fn get_charged_ah(&self) -> f64 {
+        self.charged_ah
+    }
+
+    pub fn get_discharged_ah(&self) -> f64 {
+        self.discharged_ah
+    }
+
+    pub fn get_charged_wh(&self) -> f64 {
+        self.charged_wh
+    }
+
+    pub fn get_discharged_wh(&self) -> f64 {
+        self.discharged_wh
+    }
+
+    /// 净充入电量(Ah)
+    pub fn net_ah(&self) -> f64 {
+        self.charged_ah - self.discharged_ah
+    }
+
+    /// 主机侧估算的SOC百分比，未设定容量时为None
+    pub fn soc(&self) -> Option<f32> {
+        let capacity = self.capacity_ah?;
+        let initial = self.initial_soc?;
+        Some((initial as f64 + self.net_ah() / capacity as f64 * 100.0).clamp(0.0, 100.0) as f32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const S: u64 = 1_000_000;
+
+    fn assert_near(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-6, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn coulomb_counter_test() {
+        let mut c = CoulombCounter::new();
+        // 1小时以2A充电，再以4A放电半小时
+        for t in 0..=3600 {
+            c.update(t * S, 20.0, 2.0, 50.0);
+        }
+        for t in 3601..=5400 {
+            c.update(t * S, 20.0, -4.0, 50.0);
+        }
+        // 充放电切换的1秒内平均电流为-1A
+        assert_near(c.get_charged_ah(), 2.0);
+        assert_near(c.get_discharged_ah(), (1.0 + 4.0 * 1799.0) / 3600.0);
+        assert_near(c.get_charged_wh(), c.get_charged_ah() * 20.0);
+        assert_near(c.get_discharged_wh(), c.get_discharged_ah() * 20.0);
+
+        assert_eq!(c.soc(), None);
+        c.set_capacity(Some(10.0));
+        assert!((c.soc().unwrap() - 50.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn coulomb_counter_gap_test() {
+        let mut c = CoulombCounter::new();
+        c.set_capacity(Some(4.0));
+        c.update(0, 10.0, -1.0, 80.0);
+        c.update(3600 * S, 10.0, -1.0, 0.0);
+        assert_eq!(c.get_discharged_ah(), 0.0);
+        for t in 1..=1800 {
+            c.update(3600 * S + t * S, 10.0, -1.0, 0.0);
+        }
+        assert_near(c.get_discharged_ah(), 0.5);
+        assert!((c.soc().unwrap() - 67.5).abs() < 0.01);
+
+        c.reset();
+        assert_eq!(c.get_capacity(), Some(4.0));
+        c.update(0, 10.0, 0.0, 30.0);
+        assert_eq!(c.soc(), Some(30.0));
+    }
+}
@@ -0,0 +1,153 @@
+/// 判断充放电的最小电流(A)
+const MIN_CURRENT: f32 = 0.2;
+/// 持续时间不足该值(us)的充放电过程不计数
+const MIN_CYCLE_DURATION: u64 = 60 * 1_000_000;
+
+/// 充放电状态
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CycleState {
+    Idle,
+    Charging,
+    Discharging,
+}
+
+impl CycleState {
+    /// 由CHG/DSG标志与电流方向判断，电流为正表示充电
+    pub fn detect(chg: bool, dsg: bool, current: f32) -> Self {
+        if chg && current > MIN_CURRENT {
+            CycleState::Charging
+        } else if dsg && current < -MIN_CURRENT {
+            CycleState::Discharging
+        } else {
+            CycleState::Idle
+        }
+    }
+}
+
+/// 充放电循环计数
+///
+/// 记录完整的充电、放电过程次数，并按SOC变化量累计等效满循环次数，
+/// SOC累计变化200%(充满再放完)记为一次
+#[derive(Debug, Clone)]
+pub struct CycleCounter {
+    state: CycleState,
+    state_time: u64,
+    last_soc: Option<f32>,
+    charge_cycles: u32,
+    discharge_cycles: u32,
+    equivalent_cycles: f64,
+}
+
+impl Default for CycleCounter {
+    fn default() -> Self {
+        Self {
+            state: CycleState::Idle,
+            state_time: 0,
+            last_soc: None,
+            charge_cycles: 0,
+            discharge_cycles: 0,
+            equivalent_cycles: 0.0,
+        }
+    }
+}
+
+impl CycleCounter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 更新采样，返回本次结束的充放电过程
+    pub fn update(
+        &mut self,
+        time: u64,
+        chg: bool,
+        dsg: bool,
+        current: f32,
+        soc: f32,
+    ) -> Option<CycleState> {
+        if let Some(last_soc) = self.last_soc {
+            self.equivalent_cycles += (soc - last_soc).abs() as f64 / 200.0;
+        }
+        self.last_soc = Some(soc);
+
+        let state = CycleState::detect(chg, dsg, current);
+        if state == self.state {
+            return None;
+        }
+        let finished = self.state;
+        let duration = time.saturating_sub(self.state_time);
+        self.state = state;
+        self.state_time = time;
+        if duration < MIN_CYCLE_DURATION {
+            return None;
+        }
+        match finished {
+            CycleState::Charging => self.charge_cycles += 1,
+            CycleState::Discharging => self.discharge_cycles += 1,
+            CycleState::Idle => return None,
+        }
+        Some(finished)
+    }
+
+    pub fn get_state(&self) -> CycleState {
+        self.state
+    }
+
+    pub fn get_charge_cycles(&self) -> u32 {
+        self.charge_cycles
+    }
+
+    pub fn get_discharge_cycles(&self) -> u32 {
+        self.discharge_cycles
+    }
+
+    pub fn get_equivalent_cycles(&self) -> f64 {
+        self.equivalent_cycles
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const S: u64 = 1_000_000;
+
+    #[test]
+    fn cycle_state_test() {
+        assert_eq!(CycleState::detect(true, true, 1.0), CycleState::Charging);
+        assert_eq!(CycleState::detect(false, true, 1.0), CycleState::Idle);
+        assert_eq!(
+            CycleState::detect(true, true, -1.0),
+            CycleState::Discharging
+        );
+        assert_eq!(CycleState::detect(true, true, 0.1), CycleState::Idle);
+    }
+
+    #[test]
+    fn cycle_counter_test() {
+        let mut c = CycleCounter::new();
+        // 放电100%~0%，用时100分钟
+        for t in 0..=100 {
+            let ret = c.update(t * 60 * S, true, true, -2.0, 100.0 - t as f32);
+            assert_eq!(ret, None);
+        }
+        assert_eq!(c.get_state(), CycleState::Discharging);
+        // 短暂充电不计数
+        assert_eq!(
+            c.update(6060 * S, true, true, 2.0, 0.0),
+            Some(CycleState::Discharging)
+        );
+        assert_eq!(c.update(6070 * S, true, true, 0.0, 0.0), None);
+        // 充电0%~50%
+        for t in 1..=50 {
+            c.update(6070 * S + t * 60 * S, true, true, 2.0, t as f32);
+        }
+        assert_eq!(
+            c.update(10000 * S, true, true, 0.0, 50.0),
+            Some(CycleState::Charging)
+        );
+        assert_eq!(c.get_discharge_cycles(), 1);
+        assert_eq!(c.get_charge_cycles(), 1);
+        assert!((c.get_equivalent_cycles() - 0.75).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,157 @@
+/// 电芯电压偏离平均值的容差(V)
+pub const DRIFT_TOLERANCE: f32 = 0.05;
+/// 内阻高于中位数该倍数时视为异常
+const HIGH_RESISTANCE_RATIO: f32 = 1.5;
+/// 估算内阻所需的最小电流阶跃(A)
+const MIN_CURRENT_STEP: f32 = 1.0;
+/// 电流阶跃前后两次采样的最大间隔(us)
+const MAX_STEP_INTERVAL: u64 = 2 * 1_000_000;
+/// 内阻估算的平滑系数
+const RESISTANCE_ALPHA: f32 = 0.2;
+
+/// 电芯电压统计，电压单位V
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CellStats {
+    pub min_index: usize,
+    pub max_index: usize,
+    pub min: f32,
+    pub max: f32,
+    pub mean: f32,
+}
+
+impl CellStats {
+    pub fn new(voltages: &[f32]) -> Option<Self> {
+        if voltages.is_empty() {
+            return None;
+        }
+        let mut stats = Self {
+            min_index: 0,
+            max_index: 0,
+            min: voltages[0],
+            max: voltages[0],
+            mean: voltages.iter().sum::<f32>() / voltages.len() as f32,
+        };
+        for (i, &v) in voltages.iter().enumerate() {
+            if v < stats.min {
+                stats.min = v;
+                stats.min_index = i;
+            }
+            if v > stats.max {
+                stats.max = v;
+                stats.max_index = i;
+            }
+        }
+        Some(stats)
+    }
+
+    /// 压差
+    pub fn spread(&self) -> f32 {
+        self.max - self.min
+    }
+
+    /// 偏离平均值超过容差的电芯
+    pub fn drift(&self, voltages: &[f32]) -> Vec<bool> {
+        voltages
+            .iter()
+            .map(|&v| (v - self.mean).abs() > DRIFT_TOLERANCE)
+            .collect()
+    }
+}
+
+/// 电芯内阻估算
+///
+/// 电流发生阶跃时，以各电芯电压变化与电流变化之比估算直流内阻(mΩ)，
+/// 多次估算结果做指数平滑
+#[derive(Debug, Clone, Default)]
+pub struct CellAnalyzer {
+    last: Option<(u64, Vec<f32>, f32)>,
+    resistance: Vec<Option<f32>>,
+}
+
+impl CellAnalyzer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 更新采样，voltages单位V，current单位A
+    pub fn update(&mut self, time: u64, voltages: &[f32], current: f32) {
+        if self.resistance.len() != voltages.len() {
+            self.resistance = vec![None; voltages.len()];
+            self.last = None;
+        }
+        if let Some((last_time, last_voltages, last_current)) = self.last.as_ref() {
+            let di = current - last_current;
+            if time.saturating_sub(*last_time) <= MAX_STEP_INTERVAL && di.abs() >= MIN_CURRENT_STEP
+            {
+                for (i, r) in self.resistance.iter_mut().enumerate() {
+                    let estimate = (voltages[i] - last_voltages[i]) / di * 1000.0;
+                    if estimate <= 0.0 {
+                        continue;
+                    }
+                    *r = Some(match *r {
+                        Some(r) => r + (estimate - r) * RESISTANCE_ALPHA,
+                        None => estimate,
+                    });
+                }
+            }
+        }
+        self.last = Some((time, voltages.to_vec(), current));
+    }
+
+    pub fn get_resistance(&self) -> &[Option<f32>] {
+        &self.resistance
+    }
+
+    /// 内阻明显高于其他电芯的电芯
+    pub fn high_resistance(&self) -> Vec<bool> {
+        let mut known: Vec<f32> = self.resistance.iter().flatten().copied().collect();
+        if known.len() < 2 {
+            return vec![false; self.resistance.len()];
+        }
+        known.sort_by(f32::total_cmp);
+        let median = known[known.len() / 2];
+        self.resistance
+            .iter()
+            .map(|r| r.is_some_and(|r| r > median * HIGH_RESISTANCE_RATIO))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const S: u64 = 1_000_000;
+
+    #[test]
+    fn cell_stats_test() {
+        assert_eq!(CellStats::new(&[]), None);
+        let voltages = [3.70, 3.72, 3.60, 3.71];
+        let stats = CellStats::new(&voltages).unwrap();
+        assert_eq!((stats.min_index, stats.max_index), (2, 1));
+        assert!((stats.spread() - 0.12).abs() < 1e-6);
+        assert!((stats.mean - 3.6825).abs() < 1e-6);
+        assert_eq!(stats.drift(&voltages), vec![false, false, true, false]);
+    }
+
+    #[test]
+    fn cell_analyzer_test() {
+        let mut a = CellAnalyzer::new();
+        a.update(0, &[3.70, 3.70, 3.70], 0.0);
+        // 放电10A，电压分别下降20/20/50mV
+        a.update(S, &[3.68, 3.68, 3.65], -10.0);
+        let r = a.get_resistance();
+        assert!((r[0].unwrap() - 2.0).abs() < 1e-3);
+        assert!((r[2].unwrap() - 5.0).abs() < 1e-3);
+        assert_eq!(a.high_resistance(), vec![false, false, true]);
+
+        // 电流变化过小或间隔过长时不估算
+        a.update(2 * S, &[3.60, 3.60, 3.60], -10.5);
+        a.update(10 * S, &[3.70, 3.70, 3.70], 0.0);
+        assert!((a.get_resistance()[0].unwrap() - 2.0).abs() < 1e-3);
+
+        // 串数变化时重新估算
+        a.update(11 * S, &[3.70, 3.70], 0.0);
+        assert_eq!(a.get_resistance(), &[None, None]);
+    }
+}
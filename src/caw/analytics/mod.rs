@@ -0,0 +1,3 @@
+pub mod coulomb;
+pub mod cycles;
+pub mod imbalance;
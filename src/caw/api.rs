@@ -0,0 +1,593 @@
+use futures_util::{SinkExt, StreamExt};
+use lazy_static::lazy_static;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Mutex,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::broadcast,
+};
+use tokio_tungstenite::{
+    tungstenite::{handshake::derive_accept_key, protocol::Role, Message},
+    WebSocketStream,
+};
+
+use serde_json::{json, Value};
+
+use crate::caw::{
+    composer,
+    protocols::discover::type_name,
+    recorder,
+    telemetry::{self, Record},
+};
+
+/// 默认监听地址，只允许本机访问
+pub const DEFAULT_API_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8686);
+/// 请求头与请求体的最大字节数
+const MAX_REQUEST_SIZE: usize = 64 * 1024;
+/// WebSocket推送队列长度，客户端处理不及时时丢弃最早的数据
+const STREAM_CAPACITY: usize = 256;
+
+lazy_static! {
+    /// 各设备最新的遥测数据
+    static ref LATEST: Mutex<HashMap<(u32, u32), Value>> = Mutex::new(HashMap::new());
+    static ref STREAM: broadcast::Sender<String> = broadcast::channel(STREAM_CAPACITY).0;
+}
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+#[derive(Debug, Clone)]
+pub enum ApiError {
+    BadRequest(String),
+    Forbidden,
+    NotFound,
+    MethodNotAllowed,
+    UnsupportedMediaType,
+}
+
+impl ApiError {
+    fn status(&self) -> u16 {
+        match *self {
+            ApiError::BadRequest(_) => 400,
+            ApiError::Forbidden => 403,
+            ApiError::NotFound => 404,
+            ApiError::MethodNotAllowed => 405,
+            ApiError::UnsupportedMediaType => 415,
+        }
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            ApiError::BadRequest(ref message) => {
+                write!(f, "bad request: {}", message)
+            }
+            ApiError::Forbidden => {
+                write!(f, "origin not allowed")
+            }
+            ApiError::NotFound => {
+                write!(f, "not found")
+            }
+            ApiError::MethodNotAllowed => {
+                write!(f, "method not allowed")
+            }
+            ApiError::UnsupportedMediaType => {
+                write!(f, "content-type must be application/json")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ApiError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            ApiError::BadRequest(_) => None,
+            ApiError::Forbidden => None,
+            ApiError::NotFound => None,
+            ApiError::MethodNotAllowed => None,
+            ApiError::UnsupportedMediaType => None,
+        }
+    }
+}
+
+/// 接口所需的设备操作，由主程序提供
+#[derive(Clone, Copy)]
+pub struct ApiContext {
+    /// 已连接设备的类型与编号
+    pub devices: fn() -> Vec<(u32, u32)>,
+    /// 向设备发送数据帧：类型、编号、指令分组、组内编号、数据体
+    pub send: fn(u32, u32, u32, u32, &[u8]) -> Result<()>,
+}
+
+/// 解析监听地址，只给出端口时监听本机
+pub fn parse_addr(addr: &str) -> Result<SocketAddr> {
+    match addr.parse::<u16>() {
+        Ok(port) => Ok(SocketAddr::new(DEFAULT_API_ADDR.ip(), port)),
+        Err(_) => Ok(addr.parse::<SocketAddr>()?),
+    }
+}
+
+/// 解码后的数据转为JSON，附带设备类型与编号，time为Unix时间戳(ms)
+pub fn to_json(record: &Record) -> Value {
+    let mut h = vec!["type_id".to_string(), "device_id".into(), "time".into()];
+    let mut r = vec![
+        Value::from(record.type_id),
+        record.device_id.into(),
+        (record.time / 1000).into(),
+    ];
+    // 数据行中的device_id及CSV使用的本地时间字符串由上面的字段代替
+    for (name, value) in record.header.iter().zip(record.row.iter()) {
+        if name != "device_id" && name != "time" {
+            h.push(name.clone());
+            r.push(value.clone());
+        }
+    }
+    recorder::to_json(record.kind.name(), &h, &r)
+}
+
+/// 推送给WebSocket客户端，遥测数据同时记录为设备的最新值
+fn publish(record: &Record) {
+    let json = to_json(record);
+    let _ = STREAM.send(json.to_string());
+    if record.kind.is_event() {
+        return;
+    }
+    if let Ok(mut latest) = LATEST.lock() {
+        latest.insert((record.type_id, record.device_id), json);
+    }
+}
+
+/// 设备编号变更后迁移最新的遥测数据
+pub fn rekey_device(type_id: u32, device_id: u32, new_device_id: u32) {
+    if let Ok(mut latest) = LATEST.lock() {
+        if let Some(json) = latest.remove(&(type_id, device_id)) {
+            latest.insert((type_id, new_device_id), json);
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    /// 名称为小写
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+/// 解析HTTP请求，数据不完整时返回None
+fn parse_request(buf: &[u8]) -> Result<Option<Request>> {
+    let Some(head_end) = buf.windows(4).position(|w| w == b"\r\n\r\n") else {
+        return Ok(None);
+    };
+    let head = std::str::from_utf8(&buf[..head_end])?;
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let (Some(method), Some(path)) = (request_line.next(), request_line.next()) else {
+        return Err(ApiError::BadRequest("request line".into()).into());
+    };
+    let headers: HashMap<String, String> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_lowercase(), value.trim().to_string()))
+        .collect();
+    let size = match headers.get("content-length") {
+        Some(size) => size
+            .parse::<usize>()
+            .map_err(|_| ApiError::BadRequest("content-length".into()))?,
+        None => 0,
+    };
+    let body_start = head_end + 4;
+    if buf.len() < body_start + size {
+        return Ok(None);
+    }
+    Ok(Some(Request {
+        method: method.into(),
+        // 忽略查询参数
+        path: path.split('?').next().unwrap_or_default().into(),
+        headers,
+        body: buf[body_start..body_start + size].to_vec(),
+    }))
+}
+
+fn response(status: u16, body: &Value) -> Vec<u8> {
+    http_response(status, "application/json", &body.to_string())
+}
+
+fn error_body(message: &str) -> Value {
+    json!({ "error": message })
+}
+
+pub fn http_response(status: u16, content_type: &str, body: &str) -> Vec<u8> {
+    let reason = match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        415 => "Unsupported Media Type",
+        _ => "Error",
+    };
+    format!(
+        "HTTP/1.1 {} {}\r\n\
+         Content-Type: {}\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        reason,
+        content_type,
+        body.len(),
+        body
+    )
+    .into_bytes()
+}
+
+fn parse_id(s: &str) -> std::result::Result<u32, ApiError> {
+    s.parse::<u32>()
+        .map_err(|_| ApiError::BadRequest(format!("invalid id: {}", s)))
+}
+
+/// 解析发送请求，指令以名称(如"Motor(SetSpeed)")或group/code给出，payload为十六进制
+pub fn parse_send(body: &[u8]) -> Result<(u32, u32, Vec<u8>)> {
+    let body: Value = serde_json::from_slice(body)
+        .map_err(|e| ApiError::BadRequest(format!("invalid json: {}", e)))?;
+    let (group, code) = match body.get("command").and_then(|v| v.as_str()) {
+        Some(name) => composer::find_command(name)
+            .map(|code| code.to_raw())
+            .ok_or_else(|| ApiError::BadRequest(format!("unknown command: {}", name)))?,
+        None => {
+            let number = |key: &str| {
+                body.get(key)
+                    .and_then(|v| v.as_u64())
+                    .and_then(|v| u32::try_from(v).ok())
+                    .ok_or_else(|| ApiError::BadRequest(format!("missing {}", key)))
+            };
+            (number("group")?, number("code")?)
+        }
+    };
+    let payload = body.get("payload").and_then(|v| v.as_str()).unwrap_or("");
+    Ok((group, code, composer::parse_hex(payload)?))
+}
+
+/// 浏览器请求只接受本机页面，未带Origin的请求(如curl)不受限
+fn is_local_origin(request: &Request) -> bool {
+    let Some(origin) = request.headers.get("origin") else {
+        return true;
+    };
+    let Some((scheme, host)) = origin.split_once("://") else {
+        return false;
+    };
+    let host = match host.strip_prefix('[') {
+        Some(host) => host.split(']').next().unwrap_or_default(),
+        None => host.split(':').next().unwrap_or_default(),
+    };
+    matches!(scheme, "http" | "https") && matches!(host, "localhost" | "127.0.0.1" | "::1")
+}
+
+/// 请求体须为JSON，跨域页面无法不经预检发送此类请求
+fn is_json(request: &Request) -> bool {
+    request
+        .headers
+        .get("content-type")
+        .and_then(|v| v.split(';').next())
+        .is_some_and(|v| v.trim().eq_ignore_ascii_case("application/json"))
+}
+
+/// 处理HTTP请求，返回状态码与JSON
+fn route(ctx: &ApiContext, request: &Request) -> Result<(u16, Value)> {
+    if !is_local_origin(request) {
+        return Err(ApiError::Forbidden.into());
+    }
+    if request.method == "POST" && !is_json(request) {
+        return Err(ApiError::UnsupportedMediaType.into());
+    }
+    let segments: Vec<&str> = request
+        .path
+        .trim_matches('/')
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .collect();
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["api", "devices"]) => {
+            let devices: Vec<Value> = (ctx.devices)()
+                .iter()
+                .map(|&(type_id, device_id)| {
+                    json!({
+                        "type_id": type_id,
+                        "device_id": device_id,
+                        "type": type_name(type_id),
+                    })
+                })
+                .collect();
+            Ok((200, Value::Array(devices)))
+        }
+        ("GET", ["api", "telemetry"]) => {
+            let latest = LATEST.lock().map_err(|_| "telemetry lock poisoned")?;
+            let mut keys: Vec<&(u32, u32)> = latest.keys().collect();
+            keys.sort();
+            let items: Vec<Value> = keys.iter().map(|key| latest[key].clone()).collect();
+            Ok((200, Value::Array(items)))
+        }
+        ("GET", ["api", "telemetry", type_id, device_id]) => {
+            let key = (parse_id(type_id)?, parse_id(device_id)?);
+            let latest = LATEST.lock().map_err(|_| "telemetry lock poisoned")?;
+            match latest.get(&key) {
+                Some(json) => Ok((200, json.clone())),
+                None => Err(ApiError::NotFound.into()),
+            }
+        }
+        ("POST", ["api", "devices", type_id, device_id, "send"]) => {
+            let (type_id, device_id) = (parse_id(type_id)?, parse_id(device_id)?);
+            if !(ctx.devices)().contains(&(type_id, device_id)) {
+                return Err(ApiError::NotFound.into());
+            }
+            let (group, code, payload) = parse_send(&request.body)?;
+            (ctx.send)(type_id, device_id, group, code, &payload)?;
+            Ok((200, json!({ "ok": true })))
+        }
+        (_, ["api", "devices"])
+        | (_, ["api", "telemetry", ..])
+        | (_, ["api", "devices", _, _, "send"]) => Err(ApiError::MethodNotAllowed.into()),
+        _ => Err(ApiError::NotFound.into()),
+    }
+}
+
+fn handle_request(ctx: &ApiContext, request: &Request) -> Vec<u8> {
+    match route(ctx, request) {
+        Ok((status, body)) => response(status, &body),
+        Err(e) => {
+            let status = e.downcast_ref::<ApiError>().map_or(500, |e| e.status());
+            response(status, &error_body(&e.to_string()))
+        }
+    }
+}
+
+fn is_websocket(request: &Request) -> bool {
+    request.method == "GET"
+        && request.path == "/api/stream"
+        && request
+            .headers
+            .get("upgrade")
+            .is_some_and(|v| v.eq_ignore_ascii_case("websocket"))
+}
+
+/// 推送解码后的数据帧，直到客户端断开
+async fn stream(mut tcp: TcpStream, key: String) -> std::io::Result<()> {
+    let handshake = format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        derive_accept_key(key.as_bytes())
+    );
+    tcp.write_all(handshake.as_bytes()).await?;
+    let mut ws = WebSocketStream::from_raw_socket(tcp, Role::Server, None).await;
+    let mut rx = STREAM.subscribe();
+    loop {
+        tokio::select! {
+            msg = rx.recv() => match msg {
+                Ok(json) => {
+                    if ws.send(Message::Text(json)).await.is_err() {
+                        break;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            msg = ws.next() => match msg {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => (),
+            },
+        }
+    }
+    Ok(())
+}
+
+/// 读取一个HTTP请求，请求无效时回复400，连接关闭或请求无效时返回None
+pub async fn read_request(tcp: &mut TcpStream) -> std::io::Result<Option<Request>> {
+    let mut buf = vec![];
+    let mut tmp_buf = [0u8; 4096];
+    loop {
+        let size = tcp.read(&mut tmp_buf).await?;
+        if size == 0 {
+            return Ok(None);
+        }
+        buf.extend_from_slice(&tmp_buf[..size]);
+        let parsed = parse_request(&buf).map_err(|e| e.to_string());
+        match parsed {
+            Ok(Some(request)) => return Ok(Some(request)),
+            Ok(None) if buf.len() < MAX_REQUEST_SIZE => continue,
+            Ok(None) => {
+                let body = error_body("request too large");
+                tcp.write_all(&response(400, &body)).await?;
+                return Ok(None);
+            }
+            Err(e) => {
+                tcp.write_all(&response(400, &error_body(&e))).await?;
+                return Ok(None);
+            }
+        }
+    }
+}
+
+async fn handle(mut tcp: TcpStream, ctx: ApiContext) -> std::io::Result<()> {
+    let Some(request) = read_request(&mut tcp).await? else {
+        return Ok(());
+    };
+    // 非本机页面的握手由route拒绝
+    if is_websocket(&request) && is_local_origin(&request) {
+        if let Some(key) = request.headers.get("sec-websocket-key") {
+            return stream(tcp, key.clone()).await;
+        }
+    }
+    // 发送指令会短暂阻塞等待设备锁
+    let ret = tokio::task::spawn_blocking(move || handle_request(&ctx, &request)).await;
+    match ret {
+        Ok(buf) => tcp.write_all(&buf).await,
+        Err(e) => {
+            let body = error_body(&e.to_string());
+            tcp.write_all(&response(500, &body)).await
+        }
+    }
+}
+
+/// 启动接口服务
+///
+/// GET /api/devices、GET /api/telemetry[/<type_id>/<device_id>]、
+/// POST /api/devices/<type_id>/<device_id>/send、WebSocket /api/stream
+pub async fn serve(addr: SocketAddr, ctx: ApiContext) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    let _subscription = telemetry::subscribe(publish);
+    eprintln!("api listening on http://{}", addr);
+    loop {
+        let (tcp, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            if let Err(e) = handle(tcp, ctx).await {
+                eprintln!("api connection error: {}", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::caw::telemetry::Kind;
+
+    fn devices() -> Vec<(u32, u32)> {
+        vec![(0, 1)]
+    }
+
+    fn send(_: u32, _: u32, group: u32, _: u32, _: &[u8]) -> Result<()> {
+        if group == 9 {
+            return Err("device busy".into());
+        }
+        Ok(())
+    }
+
+    fn request_with(method: &str, path: &str, headers: &str, body: &str) -> Request {
+        let raw = format!(
+            "{} {} HTTP/1.1\r\nHost: localhost\r\n{}Content-Length: {}\r\n\r\n{}",
+            method,
+            path,
+            headers,
+            body.len(),
+            body
+        );
+        parse_request(raw.as_bytes()).unwrap().unwrap()
+    }
+
+    fn request(method: &str, path: &str, body: &str) -> Request {
+        request_with(method, path, "Content-Type: application/json\r\n", body)
+    }
+
+    #[test]
+    fn parse_request_test() {
+        let raw = b"POST /api/x?a=1 HTTP/1.1\r\nContent-Length: 4\r\nUpgrade: websocket\r\n\r\nab";
+        assert_eq!(parse_request(raw).unwrap(), None);
+        let raw = b"POST /api/x?a=1 HTTP/1.1\r\nContent-Length: 2\r\nUpgrade: websocket\r\n\r\nab";
+        let r = parse_request(raw).unwrap().unwrap();
+        assert_eq!(r.path, "/api/x");
+        assert_eq!(r.headers["upgrade"], "websocket");
+        assert_eq!(r.body, b"ab");
+        assert!(parse_request(b"GET /api HTTP/1.1\r\n").unwrap().is_none());
+        assert!(parse_request(b"\r\n\r\n").is_err());
+        assert_eq!(parse_addr("9000").unwrap().to_string(), "127.0.0.1:9000");
+        assert_eq!(parse_addr("0.0.0.0:80").unwrap().to_string(), "0.0.0.0:80");
+        assert!(parse_addr("x").is_err());
+    }
+
+    #[test]
+    fn route_test() {
+        let ctx = ApiContext { devices, send };
+        let (status, body) = route(&ctx, &request("GET", "/api/devices", "")).unwrap();
+        assert_eq!(status, 200);
+        assert_eq!(
+            body.to_string(),
+            r#"[{"type_id":0,"device_id":1,"type":"BMS"}]"#
+        );
+
+        let header = vec![
+            "time".to_string(),
+            "device_id".to_string(),
+            "soc".to_string(),
+        ];
+        publish(&Record {
+            kind: Kind::Bms,
+            type_id: 0,
+            device_id: 1,
+            time: 1_700_000_000_123_456,
+            header: &header,
+            row: &["2023-11-15 06:13:20.123".into(), 1.into(), 80.into()],
+        });
+        let (_, body) = route(&ctx, &request("GET", "/api/telemetry/0/1", "")).unwrap();
+        assert_eq!(
+            body.to_string(),
+            r#"{"type":"bms","type_id":0,"device_id":1,"time":1700000000123,"soc":80}"#
+        );
+        let e = route(&ctx, &request("GET", "/api/telemetry/0/9", "")).unwrap_err();
+        assert_eq!(e.downcast_ref::<ApiError>().unwrap().status(), 404);
+
+        let path = "/api/devices/0/1/send";
+        let body = r#"{"command":"Motor(SetSpeed)","payload":"00 00 27 10"}"#;
+        assert_eq!(route(&ctx, &request("POST", path, body)).unwrap().0, 200);
+        let body = r#"{"group":1,"code":0}"#;
+        assert_eq!(route(&ctx, &request("POST", path, body)).unwrap().0, 200);
+        for body in [
+            r#"{"command":"Nope"}"#,
+            r#"{"group":1}"#,
+            "{",
+            r#"{"group":9,"code":0}"#,
+        ] {
+            assert!(route(&ctx, &request("POST", path, body)).is_err());
+        }
+        let e = route(&ctx, &request("POST", "/api/devices/0/5/send", "{}")).unwrap_err();
+        assert_eq!(e.downcast_ref::<ApiError>().unwrap().status(), 404);
+        let e = route(&ctx, &request("GET", path, "")).unwrap_err();
+        assert_eq!(e.downcast_ref::<ApiError>().unwrap().status(), 405);
+        let buf = handle_request(&ctx, &request("GET", "/nope", ""));
+        assert!(String::from_utf8(buf)
+            .unwrap()
+            .starts_with("HTTP/1.1 404 Not Found"));
+    }
+
+    #[test]
+    fn origin_test() {
+        let ctx = ApiContext { devices, send };
+        let status = |method: &str, path: &str, headers: &str, body: &str| match route(
+            &ctx,
+            &request_with(method, path, headers, body),
+        ) {
+            Ok((status, _)) => status,
+            Err(e) => e.downcast_ref::<ApiError>().unwrap().status(),
+        };
+        let path = "/api/devices/0/1/send";
+        let body = r#"{"group":1,"code":0}"#;
+        let json = "Content-Type: application/json; charset=utf-8\r\n";
+        assert_eq!(status("POST", path, json, body), 200);
+        assert_eq!(status("POST", path, "", body), 415);
+        assert_eq!(
+            status("POST", path, "Content-Type: text/plain\r\n", body),
+            415
+        );
+        for origin in [
+            "http://localhost:3000",
+            "http://127.0.0.1",
+            "https://[::1]:8080",
+        ] {
+            let headers = format!("Origin: {}\r\n{}", origin, json);
+            assert_eq!(status("POST", path, &headers, body), 200);
+        }
+        for origin in ["http://evil.com", "http://localhost.evil.com", "null"] {
+            let headers = format!("Origin: {}\r\n", origin);
+            assert_eq!(status("GET", "/api/devices", &headers, ""), 403);
+        }
+        let headers = "Origin: http://evil.com\r\nUpgrade: websocket\r\n";
+        let r = request_with("GET", "/api/stream", headers, "");
+        assert!(is_websocket(&r) && !is_local_origin(&r));
+    }
+}
@@ -0,0 +1,31 @@
+use crate::caw::{
+    alarm::{self, AlarmField},
+    analytics::imbalance::CellStats,
+    protocols::bms::BMSInfo,
+};
+
+/// 告警规则使用的字段值
+fn alarm_value(info: &BMSInfo, field: AlarmField) -> Option<f32> {
+    let stats = || CellStats::new(&info.get_cell_voltage());
+    match field {
+        AlarmField::Voltage => Some(info.voltage as f32 / 100.0),
+        AlarmField::Current => Some(info.current as f32 / 100.0),
+        AlarmField::Soc => Some(info.soc as f32 / 100.0),
+        AlarmField::Soh => Some(info.soh as f32 / 100.0),
+        AlarmField::Temperature => Some(info.max_temperature() as f32 / 100.0),
+        AlarmField::MinCellVoltage => stats().map(|s| s.min),
+        AlarmField::MaxCellVoltage => stats().map(|s| s.max),
+        AlarmField::CellSpread => stats().map(|s| s.spread() * 1000.0),
+        AlarmField::CellVoltage(i) => info.cell_voltage.get(i as usize).map(|&v| v as f32 / 100.0),
+        AlarmField::TempSensor(i) => info
+            .temp_sensors
+            .get(i as usize)
+            .map(|s| s.get_temperature() as f32 / 100.0),
+        _ => None,
+    }
+}
+
+/// 用最新数据判定告警规则，返回是否有状态变化
+pub(super) fn evaluate(type_id: u32, device_id: u32, time: u64, info: &BMSInfo) -> bool {
+    alarm::evaluate(type_id, device_id, time, |field| alarm_value(info, field))
+}
@@ -0,0 +1,60 @@
+use crate::ui::*;
+
+use super::{view::show_device, BMSTelemetry, BMS_TELEMETRY};
+use crate::caw::{
+    analytics::imbalance::{CellAnalyzer, CellStats},
+    protocols::bms::BMSInfo,
+};
+
+/// 修改设备的电池容量(Ah)，用于估算主机侧SOC，小于等于0时取消估算
+pub fn set_capacity(handle: &AppWindow, type_id: u32, device_id: u32, capacity_ah: f32) {
+    if let Ok(mut telemetry) = BMS_TELEMETRY.lock() {
+        telemetry
+            .entry((type_id, device_id))
+            .or_insert_with(BMSTelemetry::new)
+            .coulomb
+            .set_capacity(Some(capacity_ah));
+    }
+    show_device(handle, type_id, device_id);
+}
+
+/// 重新开始统计充放电量
+pub fn reset_energy(handle: &AppWindow, type_id: u32, device_id: u32) {
+    if let Ok(mut telemetry) = BMS_TELEMETRY.lock() {
+        if let Some(t) = telemetry.get_mut(&(type_id, device_id)) {
+            t.coulomb.reset();
+        }
+    }
+    show_device(handle, type_id, device_id);
+}
+
+/// 电芯一致性诊断，电压偏差单位mV，内阻单位mΩ，未估算时为-1
+pub(super) fn cell_health_models(
+    bms_info: &BMSInfo,
+    analyzer: &CellAnalyzer,
+) -> Vec<BMSCellHealthModel> {
+    let voltages = bms_info.get_cell_voltage();
+    let stats = match CellStats::new(&voltages) {
+        Some(stats) => stats,
+        None => return vec![],
+    };
+    let drift = stats.drift(&voltages);
+    let high_resistance = analyzer.high_resistance();
+    voltages
+        .iter()
+        .enumerate()
+        .map(|(i, &v)| BMSCellHealthModel {
+            index: i as i32,
+            voltage: v,
+            deviation: (v - stats.mean) * 1000.0,
+            resistance: analyzer
+                .get_resistance()
+                .get(i)
+                .copied()
+                .flatten()
+                .unwrap_or(-1.0),
+            drift: drift[i],
+            high_resistance: high_resistance.get(i).copied().unwrap_or(false),
+        })
+        .collect()
+}
@@ -0,0 +1,162 @@
+use crate::ui::*;
+
+use chrono::{Local, TimeZone};
+use lazy_static::lazy_static;
+use slint::*;
+use std::{
+    collections::HashMap,
+    sync::mpsc::{self, Sender},
+    thread,
+};
+
+use super::BMS_TELEMETRY;
+use crate::caw::{
+    history::{self, PackHistory},
+    protocols::{bms::BMS_STATES, timesync::host_time},
+};
+
+lazy_static! {
+    /// 数据库读写请求，由后台线程依次处理，避免阻塞串口事件循环
+    static ref HISTORY_TX: Sender<HistoryRequest> = spawn_worker();
+}
+
+enum HistoryRequest {
+    /// 读取记录并合并到(type_id, device_id)的遥测状态
+    Load(u32, u32),
+    Save(u32, PackHistory),
+    Rekey(u32, u32),
+    /// 之前的请求处理完成后应答
+    Sync(Sender<()>),
+}
+
+fn spawn_worker() -> Sender<HistoryRequest> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for request in rx {
+            match request {
+                HistoryRequest::Load(type_id, device_id) => {
+                    let stored = history::load(device_id);
+                    merge_loaded(type_id, device_id, stored);
+                }
+                HistoryRequest::Save(device_id, h) => history::save(device_id, &h),
+                HistoryRequest::Rekey(device_id, new_device_id) => {
+                    history::rekey(device_id, new_device_id)
+                }
+                HistoryRequest::Sync(done) => {
+                    let _ = done.send(());
+                }
+            }
+        }
+    });
+    tx
+}
+
+/// 将数据库中的记录与加载期间累计的数据合并，此后才允许写入数据库
+fn merge_loaded(type_id: u32, device_id: u32, stored: Option<PackHistory>) {
+    if let Ok(mut telemetry) = BMS_TELEMETRY.lock() {
+        if let Some(t) = telemetry.get_mut(&(type_id, device_id)) {
+            if let Some(mut stored) = stored {
+                if let Some(h) = t.history.as_ref() {
+                    stored.merge(h);
+                }
+                t.history = Some(stored);
+            }
+            t.history_loaded = true;
+        }
+    }
+}
+
+fn send(request: HistoryRequest) {
+    if HISTORY_TX.send(request).is_err() {
+        eprintln!("history worker stopped");
+    }
+}
+
+/// 在后台读取设备的历史记录
+pub(super) fn load(type_id: u32, device_id: u32) {
+    send(HistoryRequest::Load(type_id, device_id));
+}
+
+/// 在后台写入设备的历史记录
+pub(super) fn save(device_id: u32, h: PackHistory) {
+    send(HistoryRequest::Save(device_id, h));
+}
+
+pub(super) fn rekey(device_id: u32, new_device_id: u32) {
+    send(HistoryRequest::Rekey(device_id, new_device_id));
+}
+
+/// 等待已提交的读写完成，用于退出前
+pub fn sync() {
+    let (tx, rx) = mpsc::channel();
+    send(HistoryRequest::Sync(tx));
+    let _ = rx.recv();
+}
+
+fn format_datetime(time: u64) -> String {
+    Local
+        .timestamp_micros(time as i64)
+        .single()
+        .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_default()
+}
+
+fn history_models(packs: &[(u32, PackHistory)]) -> Vec<BMSHistoryModel> {
+    packs
+        .iter()
+        .map(|(device_id, h)| BMSHistoryModel {
+            device_id: *device_id as i32,
+            first_seen: format_datetime(h.first_seen).into(),
+            last_seen: format_datetime(h.last_seen).into(),
+            charge_cycles: h.charge_cycles as i32,
+            discharge_cycles: h.discharge_cycles as i32,
+            equivalent_cycles: h.equivalent_cycles as f32,
+            charged_wh: h.charged_wh as f32,
+            discharged_wh: h.discharged_wh as f32,
+            has_temperature: h.max_temperature != i32::MIN,
+            max_temperature: h.max_temperature as f32 / 100.0,
+            faults: h.total_faults() as i32,
+            fault_detail: h
+                .fault_counts
+                .iter()
+                .zip(BMS_STATES.iter())
+                .filter(|(&count, _)| count > 0)
+                .map(|(count, name)| std::format!("{} x{}", name, count))
+                .collect::<Vec<String>>()
+                .join(", ")
+                .into(),
+        })
+        .collect()
+}
+
+/// 读取所有电池包的历史记录，在线设备使用内存中的最新数据
+pub fn refresh_history(handle: &AppWindow) {
+    let mut packs: HashMap<u32, PackHistory> = history::list().into_iter().collect();
+    if let Ok(telemetry) = BMS_TELEMETRY.lock() {
+        for (&(_, device_id), t) in telemetry.iter() {
+            if let Some(h) = t.history.as_ref().filter(|_| t.history_loaded) {
+                packs.insert(device_id, h.clone());
+            }
+        }
+    }
+    let mut packs: Vec<(u32, PackHistory)> = packs.into_iter().collect();
+    packs.sort_by_key(|(device_id, _)| *device_id);
+    handle
+        .global::<BMSModelService>()
+        .set_history(VecModel::from_slice(history_models(&packs).as_slice()));
+}
+
+/// 立即保存设备的历史记录，用于断开连接及退出时，尚未加载数据库中的记录时不写入
+pub fn flush_history(type_id: u32, device_id: u32) {
+    let h = BMS_TELEMETRY.lock().ok().and_then(|mut telemetry| {
+        let t = telemetry.get_mut(&(type_id, device_id))?;
+        if !t.history_loaded {
+            return None;
+        }
+        t.history_saved = host_time();
+        t.history.clone()
+    });
+    if let Some(h) = h {
+        save(device_id, h);
+    }
+}
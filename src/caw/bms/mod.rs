@@ -0,0 +1,388 @@
+pub mod alarm;
+pub mod analytics;
+pub mod history;
+pub mod plot;
+pub mod record;
+pub mod view;
+
+use crate::ui::*;
+
+use lazy_static::lazy_static;
+use slint::*;
+use std::{collections::HashMap, sync::Mutex};
+
+use self::view::{show_snapshot, update_list_soc};
+use crate::caw::{
+    alarm::show_alarms,
+    analytics::{
+        coulomb::CoulombCounter,
+        cycles::{CycleCounter, CycleState},
+        imbalance::{CellAnalyzer, CellStats},
+    },
+    chart::series::TimeSeries,
+    clock,
+    devices::device::Device,
+    history::PackHistory,
+    metrics,
+    protocols::{
+        bms::{BMSInfo, TempSensor, BMS_STATES},
+        discover::TypeId,
+        timesync::host_time,
+    },
+    telemetry::{self, Kind},
+    utils::flags::{FlagEvent, FlagTracker},
+};
+
+/// 界面显示的最大状态事件数
+const MAX_STATE_EVENTS: usize = 20;
+
+/// 曲线数据保留时长(us)，不小于界面可选的最大时间窗口
+const PLOT_RETENTION: u64 = 30 * 60 * 1_000_000;
+/// 电池包历史写入数据库的间隔(us)
+const HISTORY_SAVE_INTERVAL: u64 = 30 * 1_000_000;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+lazy_static! {
+    /// 各设备的遥测状态，以(type_id, device_id)为键
+    static ref BMS_TELEMETRY: Mutex<HashMap<(u32, u32), BMSTelemetry>> =
+        Mutex::new(HashMap::new());
+}
+
+/// 单台BMS的遥测状态
+#[derive(Debug)]
+struct BMSTelemetry {
+    info: BMSInfo,
+    state_tracker: FlagTracker,
+    temp_stats: Vec<(i32, i32)>,
+    voltage_series: TimeSeries,
+    current_series: TimeSeries,
+    cell_series: Vec<TimeSeries>,
+    spread_series: TimeSeries,
+    coulomb: CoulombCounter,
+    cell_analyzer: CellAnalyzer,
+    cycles: CycleCounter,
+    /// 电池包历史，首次收到数据时在后台加载数据库中的记录并合并
+    history: Option<PackHistory>,
+    history_requested: bool,
+    /// 已合并数据库中的记录，此前不写入数据库以免覆盖
+    history_loaded: bool,
+    history_saved: u64,
+}
+
+impl BMSTelemetry {
+    fn new() -> Self {
+        Self {
+            info: BMSInfo::default(),
+            state_tracker: FlagTracker::new(BMS_STATES.len()),
+            temp_stats: vec![],
+            voltage_series: TimeSeries::new(PLOT_RETENTION),
+            current_series: TimeSeries::new(PLOT_RETENTION),
+            cell_series: vec![],
+            spread_series: TimeSeries::new(PLOT_RETENTION),
+            coulomb: CoulombCounter::new(),
+            cell_analyzer: CellAnalyzer::new(),
+            cycles: CycleCounter::new(),
+            history: None,
+            history_requested: false,
+            history_loaded: false,
+            history_saved: 0,
+        }
+    }
+
+    fn update_at(&mut self, device_id: u32, info: BMSInfo, time: u64) {
+        let history = self.history.get_or_insert_with(|| PackHistory::new(time));
+        for event in self.state_tracker.update(info.state as u32, time) {
+            if event.raised {
+                history.fault_counts[event.index] += 1;
+            }
+            eprintln!(
+                "bms {} {} {}",
+                device_id,
+                BMS_STATES[event.index],
+                if event.raised { "raised" } else { "cleared" }
+            );
+        }
+        update_temp_stats(&mut self.temp_stats, &info.temp_sensors);
+        self.voltage_series.push(time, info.voltage as f32 / 100.0);
+        self.current_series.push(time, info.current as f32 / 100.0);
+        if self.cell_series.len() != info.get_cell_count() {
+            self.cell_series = vec![TimeSeries::new(PLOT_RETENTION); info.get_cell_count()];
+        }
+        for (series, &v) in self.cell_series.iter_mut().zip(info.cell_voltage.iter()) {
+            series.push(time, v as f32 / 100.0);
+        }
+        let (charged_wh, discharged_wh) = (
+            self.coulomb.get_charged_wh(),
+            self.coulomb.get_discharged_wh(),
+        );
+        self.coulomb.update(
+            time,
+            info.voltage as f32 / 100.0,
+            info.current as f32 / 100.0,
+            info.soc as f32 / 100.0,
+        );
+        history.charged_wh += self.coulomb.get_charged_wh() - charged_wh;
+        history.discharged_wh += self.coulomb.get_discharged_wh() - discharged_wh;
+        let equivalent_cycles = self.cycles.get_equivalent_cycles();
+        match self.cycles.update(
+            time,
+            info.chg != 0,
+            info.dsg != 0,
+            info.current as f32 / 100.0,
+            info.soc as f32 / 100.0,
+        ) {
+            Some(CycleState::Charging) => history.charge_cycles += 1,
+            Some(CycleState::Discharging) => history.discharge_cycles += 1,
+            _ => {}
+        }
+        history.equivalent_cycles += self.cycles.get_equivalent_cycles() - equivalent_cycles;
+        history.max_temperature = history.max_temperature.max(info.max_temperature());
+        history.last_seen = time;
+        let cell_voltage = info.get_cell_voltage();
+        if let Some(stats) = CellStats::new(&cell_voltage) {
+            self.spread_series.push(time, stats.spread() * 1000.0);
+        }
+        self.cell_analyzer
+            .update(time, &cell_voltage, info.current as f32 / 100.0);
+        self.info = info;
+    }
+
+    fn snapshot(&self) -> BMSSnapshot {
+        BMSSnapshot {
+            info: self.info.clone(),
+            state_events: self
+                .state_tracker
+                .get_events()
+                .iter()
+                .rev()
+                .take(MAX_STATE_EVENTS)
+                .copied()
+                .collect(),
+            state_since: (0..BMS_STATES.len())
+                .map(|index| self.state_tracker.get_raised_time(index))
+                .collect(),
+            temp_stats: self.temp_stats.clone(),
+            coulomb: self.coulomb.clone(),
+            cell_analyzer: self.cell_analyzer.clone(),
+        }
+    }
+
+    /// 距上次写入超过间隔时返回需要保存的历史
+    fn history_to_save(&mut self, time: u64) -> Option<PackHistory> {
+        if !self.history_loaded || time.saturating_sub(self.history_saved) < HISTORY_SAVE_INTERVAL {
+            return None;
+        }
+        self.history_saved = time;
+        self.history.clone()
+    }
+}
+
+/// 界面显示所需的遥测数据，状态事件新的在前
+#[derive(Debug, Clone, Default)]
+struct BMSSnapshot {
+    info: BMSInfo,
+    state_events: Vec<FlagEvent>,
+    /// 各状态标志位被置位的时刻，未置位时为None
+    state_since: Vec<Option<u64>>,
+    temp_stats: Vec<(i32, i32)>,
+    coulomb: CoulombCounter,
+    cell_analyzer: CellAnalyzer,
+}
+
+/// 更新各温度传感器的最小/最大值，传感器数量变化时重新统计
+fn update_temp_stats(stats: &mut Vec<(i32, i32)>, sensors: &[TempSensor]) {
+    if stats.len() != sensors.len() {
+        *stats = sensors
+            .iter()
+            .map(|s| (s.get_temperature(), s.get_temperature()))
+            .collect();
+    }
+    for (sensor, (min, max)) in sensors.iter().zip(stats.iter_mut()) {
+        *min = (*min).min(sensor.get_temperature());
+        *max = (*max).max(sensor.get_temperature());
+    }
+}
+
+/// 设备最近一次上报的SOC
+pub fn get_soc(type_id: u32, device_id: u32) -> Option<f32> {
+    BMS_TELEMETRY.lock().ok().and_then(|telemetry| {
+        telemetry
+            .get(&(type_id, device_id))
+            .map(|t| t.info.soc as f32 / 100.0)
+    })
+}
+
+/// 设备编号变更后迁移遥测数据
+pub fn rekey_device(type_id: u32, device_id: u32, new_device_id: u32) {
+    if let Ok(mut telemetry) = BMS_TELEMETRY.lock() {
+        if let Some(mut t) = telemetry.remove(&(type_id, device_id)) {
+            // 旧编号的加载结果会被丢弃，迁移后按新编号重新加载
+            if !t.history_loaded {
+                t.history_requested = false;
+            }
+            telemetry.insert((type_id, new_device_id), t);
+        }
+    }
+    if type_id == TypeId::BMS as u32 {
+        history::rekey(device_id, new_device_id);
+    }
+}
+
+/// 解码并发布BMS数据，返回数据及其主机时间
+fn publish_info(device: &mut Box<dyn Device + Send>, buf: &[u8]) -> Option<(BMSInfo, u64)> {
+    let bms_info = BMSInfo::parse(buf).ok()?;
+    let (device_id, type_id) = device.get_id();
+    let time = clock::sample_time(type_id, device_id, bms_info.get_device_tick());
+    let row = bms_info.csv_row(time, device_id);
+    telemetry::publish(
+        Kind::Bms,
+        type_id,
+        device_id,
+        time,
+        &bms_info.csv_header(),
+        &row,
+    );
+    metrics::set_bms(type_id, device_id, bms_info.gauges());
+    Some((bms_info, time))
+}
+
+/// 无界面运行时只发布数据
+pub fn bms_info_event(
+    device: &mut Box<dyn Device + Send>,
+    buf: Option<&[u8]>,
+    _ui: &Weak<AppWindow>,
+) {
+    if let Some(buf) = buf {
+        publish_info(device, buf);
+    }
+}
+
+pub fn bms_info_protocol(
+    device: &mut Box<dyn Device + Send>,
+    buf: Option<&[u8]>,
+    ui: &Weak<AppWindow>,
+) {
+    if let Some((bms_info, time)) = buf.and_then(|buf| publish_info(device, buf)) {
+        let (device_id, type_id) = device.get_id();
+        let soc = bms_info.soc as f32 / 100.0;
+        record::record(time, device_id, &bms_info);
+        let (snapshot, history) = match BMS_TELEMETRY.lock() {
+            Ok(mut telemetry) => {
+                let t = telemetry
+                    .entry((type_id, device_id))
+                    .or_insert_with(BMSTelemetry::new);
+                if !t.history_requested {
+                    t.history_requested = true;
+                    history::load(type_id, device_id);
+                }
+                t.update_at(device_id, bms_info, time);
+                (t.snapshot(), t.history_to_save(host_time()))
+            }
+            Err(_) => return,
+        };
+        if let Some(h) = history {
+            history::save(device_id, h);
+        }
+        let alarm_changed = alarm::evaluate(type_id, device_id, time, &snapshot.info);
+        let _ = ui.upgrade_in_event_loop(move |handle| {
+            update_list_soc(&handle, type_id, device_id, soc);
+            if alarm_changed {
+                show_alarms(&handle);
+            }
+            record::show_record_status(&handle);
+            let device_service = handle.global::<DeviceModelService>();
+            // 未选中设备时默认显示第一个上报数据的设备
+            if device_service.get_current_type_id() < 0 {
+                device_service.set_current_type_id(type_id as i32);
+                device_service.set_current_device_id(device_id as i32);
+            }
+            if device_service.get_current_type_id() == type_id as i32
+                && device_service.get_current_device_id() == device_id as i32
+            {
+                show_snapshot(&handle, &snapshot);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::caw::protocols::bms::TempSensorKind;
+
+    #[test]
+    fn temp_stats_test() {
+        let sensors = |values: &[i32]| -> Vec<TempSensor> {
+            values
+                .iter()
+                .map(|&v| TempSensor::new(TempSensorKind::Cell, v))
+                .collect()
+        };
+        let mut stats = vec![];
+        update_temp_stats(&mut stats, &sensors(&[2500, 3000]));
+        update_temp_stats(&mut stats, &sensors(&[2000, 3500]));
+        update_temp_stats(&mut stats, &sensors(&[2200, 3100]));
+        assert_eq!(stats, vec![(2000, 2500), (3000, 3500)]);
+        update_temp_stats(&mut stats, &sensors(&[2800]));
+        assert_eq!(stats, vec![(2800, 2800)]);
+    }
+
+    #[test]
+    fn telemetry_test() {
+        let info = |state: u8, soc: i32| BMSInfo {
+            state,
+            soc,
+            ..Default::default()
+        };
+        let mut t = BMSTelemetry::new();
+        t.update_at(1, info(0b01, 8000), host_time());
+        t.update_at(1, info(0b10, 7900), host_time());
+        let snapshot = t.snapshot();
+        assert_eq!(snapshot.info.soc, 7900);
+        let events: Vec<(usize, bool)> = snapshot
+            .state_events
+            .iter()
+            .map(|e| (e.index, e.raised))
+            .collect();
+        assert_eq!(events, vec![(1, true), (0, false), (0, true)]);
+        assert!(snapshot.state_since[0].is_none() && snapshot.state_since[1].is_some());
+        let history = t.history.as_ref().unwrap();
+        assert_eq!(history.fault_counts[..2], [1, 1]);
+        assert!((history.equivalent_cycles - 0.005).abs() < 1e-6);
+    }
+
+    #[test]
+    fn history_save_test() {
+        let mut t = BMSTelemetry::new();
+        t.update_at(1, BMSInfo::default(), 1_000);
+        // 合并数据库中的记录前不写入
+        assert!(t.history_to_save(HISTORY_SAVE_INTERVAL).is_none());
+        t.history_loaded = true;
+        assert!(t.history_to_save(HISTORY_SAVE_INTERVAL).is_some());
+        assert!(t.history_to_save(HISTORY_SAVE_INTERVAL + 1).is_none());
+    }
+
+    #[test]
+    fn telemetry_series_test() {
+        let info = |cell_count: usize, voltage: i32| BMSInfo {
+            cell_voltage: vec![370; cell_count],
+            balance: vec![0; cell_count],
+            voltage,
+            ..Default::default()
+        };
+        let mut t = BMSTelemetry::new();
+        t.update_at(1, info(5, 1850), 1_000);
+        t.update_at(1, info(5, 1860), 2_000);
+        assert_eq!(
+            t.voltage_series.range(0, 2_000),
+            vec![(1_000, 18.5), (2_000, 18.6)]
+        );
+        assert_eq!(t.cell_series.len(), 5);
+        assert_eq!(t.cell_series[4].len(), 2);
+        t.update_at(1, info(4, 1480), 3_000);
+        assert_eq!(t.cell_series.len(), 4);
+        assert_eq!(t.cell_series[0].range(0, 3_000), vec![(3_000, 3.7)]);
+        assert_eq!(t.spread_series.len(), 3);
+    }
+}
@@ -0,0 +1,246 @@
+use crate::ui::*;
+
+use lazy_static::lazy_static;
+use slint::*;
+use std::sync::Mutex;
+
+use super::{BMSTelemetry, BMS_TELEMETRY, PLOT_RETENTION};
+use crate::caw::{
+    chart::{
+        plot::{self, PlotSeries},
+        viewport::Viewport,
+    },
+    protocols::timesync::host_time,
+};
+
+/// 曲线默认时间窗口(us)
+const PLOT_SPAN: u64 = 5 * 60 * 1_000_000;
+/// 滚轮每格的缩放比例
+const PLOT_ZOOM_STEP: f32 = 1.2;
+
+lazy_static! {
+    /// 电压与电流曲线共用的可视时间范围
+    static ref PLOT_VIEWPORT: Mutex<Viewport> = Mutex::new(Viewport::new(PLOT_SPAN, PLOT_RETENTION));
+}
+
+/// 电压曲线图例，第0项为总压，其后为各电芯，串数变化时保留已有的显示状态
+pub(super) fn update_plot_series(handle: &AppWindow, cell_count: usize) {
+    let service = handle.global::<BMSModelService>();
+    let old = service.get_plot_series();
+    if old.row_count() != cell_count + 1 {
+        let items: Vec<BMSPlotSeriesModel> = (0..=cell_count)
+            .map(|i| {
+                let color = plot::series_color(i);
+                BMSPlotSeriesModel {
+                    name: if i == 0 {
+                        "Pack".into()
+                    } else {
+                        std::format!("Cell {}", i).into()
+                    },
+                    color: Color::from_rgb_u8(color.0, color.1, color.2),
+                    visible: old.row_data(i).map_or(i == 0, |item| item.visible),
+                }
+            })
+            .collect();
+        service.set_plot_series(VecModel::from_slice(items.as_slice()));
+    }
+    // 查看历史数据时新数据不影响显示，无需重绘
+    if service.get_plot_follow() {
+        redraw_plot(handle);
+    }
+}
+
+fn redraw_plot(handle: &AppWindow) {
+    let service = handle.global::<BMSModelService>();
+    service.set_plot_tick(service.get_plot_tick().wrapping_add(1));
+}
+
+/// 修改曲线的可视时间范围并重绘
+fn update_viewport<F>(handle: &AppWindow, f: F)
+where
+    F: FnOnce(&mut Viewport, u64),
+{
+    if let Ok(mut viewport) = PLOT_VIEWPORT.lock() {
+        f(&mut viewport, host_time());
+        handle
+            .global::<BMSModelService>()
+            .set_plot_follow(viewport.is_follow());
+    }
+    redraw_plot(handle);
+}
+
+pub fn set_plot_window(handle: &AppWindow, minutes: i32) {
+    update_viewport(handle, |viewport, _| {
+        viewport.set_span(minutes.max(1) as u64 * 60 * 1_000_000)
+    });
+}
+
+/// 以横轴比例anchor处为中心缩放，steps为滚轮格数，正数放大
+pub fn zoom_plot(handle: &AppWindow, anchor: f32, steps: f32) {
+    update_viewport(handle, |viewport, now| {
+        viewport.zoom(now, anchor, PLOT_ZOOM_STEP.powf(-steps))
+    });
+}
+
+/// 拖动平移，ratio为拖动距离占绘图区宽度的比例
+pub fn pan_plot(handle: &AppWindow, ratio: f32) {
+    update_viewport(handle, |viewport, now| viewport.pan(now, ratio));
+}
+
+pub fn set_plot_follow(handle: &AppWindow, follow: bool) {
+    update_viewport(handle, |viewport, now| viewport.set_follow(now, follow));
+}
+
+/// 光标处的时间与各曲线数值，ratio小于0时清除
+pub fn plot_cursor(handle: &AppWindow, ratio: f32) {
+    let service = handle.global::<BMSModelService>();
+    if ratio < 0.0 {
+        service.set_v_readout("".into());
+        service.set_c_readout("".into());
+        service.set_s_readout("".into());
+        return;
+    }
+    let now = host_time();
+    let (time, (start, end)) = match PLOT_VIEWPORT.lock() {
+        Ok(viewport) => (viewport.time_at(now, ratio), viewport.range(now)),
+        Err(_) => return,
+    };
+    let series = service.get_plot_series();
+    let visible = visible_series(handle);
+    let ranges = with_selected(handle, |t| {
+        [
+            voltage_series(t, &visible, start, end),
+            current_series(t, start, end),
+            spread_series(t, start, end),
+        ]
+        .map(|series| {
+            let (min, max) = plot::value_range(&series);
+            BMSPlotRangeModel { min, max }
+        })
+    })
+    .unwrap_or_default();
+    let [v_range, c_range, s_range] = ranges;
+    service.set_v_range(v_range);
+    service.set_c_range(c_range);
+    service.set_s_range(s_range);
+    let (v_readout, c_readout, s_readout) = with_selected(handle, |t| {
+        let mut v_readout = plot::format_time(&time);
+        let voltages = std::iter::once(&t.voltage_series).chain(t.cell_series.iter());
+        for (i, s) in voltages.enumerate() {
+            match (series.row_data(i), s.nearest(time)) {
+                (Some(item), Some((_, v))) if item.visible => {
+                    v_readout += &std::format!("  {} {:.2}V", item.name, v)
+                }
+                _ => (),
+            }
+        }
+        let c_readout = match t.current_series.nearest(time) {
+            Some((_, v)) => std::format!("{}  {:.2}A", plot::format_time(&time), v),
+            None => plot::format_time(&time),
+        };
+        let s_readout = match t.spread_series.nearest(time) {
+            Some((_, v)) => std::format!("{}  {:.0}mV", plot::format_time(&time), v),
+            None => plot::format_time(&time),
+        };
+        (v_readout, c_readout, s_readout)
+    })
+    .unwrap_or_default();
+    service.set_v_readout(v_readout.into());
+    service.set_c_readout(c_readout.into());
+    service.set_s_readout(s_readout.into());
+}
+
+/// 对当前选中设备的遥测数据执行操作
+fn with_selected<T, F>(handle: &AppWindow, f: F) -> Option<T>
+where
+    F: FnOnce(&BMSTelemetry) -> T,
+{
+    let device_service = handle.global::<DeviceModelService>();
+    let key = (
+        device_service.get_current_type_id() as u32,
+        device_service.get_current_device_id() as u32,
+    );
+    BMS_TELEMETRY
+        .lock()
+        .ok()
+        .and_then(|telemetry| telemetry.get(&key).map(f))
+}
+
+/// 切换电压曲线的显示
+pub fn toggle_plot_series(handle: &AppWindow, index: usize) {
+    let service = handle.global::<BMSModelService>();
+    let series = service.get_plot_series();
+    if let Some(mut item) = series.row_data(index) {
+        item.visible = !item.visible;
+        series.set_row_data(index, item);
+        redraw_plot(handle);
+    }
+}
+
+/// 当前选中设备在可视时间范围内的曲线
+fn render_selected<F>(handle: &AppWindow, width: f32, height: f32, f: F) -> Image
+where
+    F: FnOnce(&BMSTelemetry, u64, u64) -> Vec<PlotSeries>,
+{
+    let (start, end) = match PLOT_VIEWPORT.lock() {
+        Ok(viewport) => viewport.range(host_time()),
+        Err(_) => return Image::default(),
+    };
+    let series = with_selected(handle, |t| f(t, start, end)).unwrap_or_default();
+    plot::render_series(width, height, &series, start, end)
+}
+
+/// 电压曲线图例的显示状态
+fn visible_series(handle: &AppWindow) -> Vec<bool> {
+    handle
+        .global::<BMSModelService>()
+        .get_plot_series()
+        .iter()
+        .map(|item| item.visible)
+        .collect()
+}
+
+/// 按图例的显示状态选取总压与各电芯电压
+fn voltage_series(t: &BMSTelemetry, visible: &[bool], start: u64, end: u64) -> Vec<PlotSeries> {
+    std::iter::once(&t.voltage_series)
+        .chain(t.cell_series.iter())
+        .enumerate()
+        .filter(|(i, _)| visible.get(*i).copied().unwrap_or(*i == 0))
+        .map(|(i, series)| PlotSeries {
+            color: plot::series_color(i),
+            points: series.range(start, end),
+        })
+        .collect()
+}
+
+fn current_series(t: &BMSTelemetry, start: u64, end: u64) -> Vec<PlotSeries> {
+    vec![PlotSeries {
+        color: plot::series_color(0),
+        points: t.current_series.range(start, end),
+    }]
+}
+
+/// 电芯压差(mV)
+fn spread_series(t: &BMSTelemetry, start: u64, end: u64) -> Vec<PlotSeries> {
+    vec![PlotSeries {
+        color: plot::series_color(3),
+        points: t.spread_series.range(start, end),
+    }]
+}
+
+/// 电压曲线，按图例的显示状态绘制总压与各电芯电压
+pub fn render_voltage_plot(handle: &AppWindow, width: f32, height: f32) -> Image {
+    let visible = visible_series(handle);
+    render_selected(handle, width, height, |t, start, end| {
+        voltage_series(t, &visible, start, end)
+    })
+}
+
+pub fn render_current_plot(handle: &AppWindow, width: f32, height: f32) -> Image {
+    render_selected(handle, width, height, current_series)
+}
+
+/// 电芯压差曲线(mV)
+pub fn render_spread_plot(handle: &AppWindow, width: f32, height: f32) -> Image {
+    render_selected(handle, width, height, spread_series)
+}
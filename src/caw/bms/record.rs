@@ -0,0 +1,62 @@
+use crate::ui::*;
+
+use lazy_static::lazy_static;
+use slint::*;
+use std::sync::Mutex;
+
+use super::Result;
+use crate::caw::{
+    protocols::bms::BMSInfo,
+    recorder::{RecordOptions, RecorderSet},
+};
+
+lazy_static! {
+    /// 遥测数据CSV记录，每台设备写入单独的文件，为None时未记录
+    static ref BMS_RECORDER: Mutex<Option<RecorderSet>> = Mutex::new(None);
+}
+
+/// 开始记录遥测数据到CSV，已在记录时切换到新的选项
+pub fn start_record(options: RecordOptions) -> Result<()> {
+    let recorder = RecorderSet::new("bms", options)?;
+    if let Ok(mut r) = BMS_RECORDER.lock() {
+        *r = Some(recorder);
+    }
+    Ok(())
+}
+
+pub fn stop_record() {
+    if let Ok(mut r) = BMS_RECORDER.lock() {
+        *r = None;
+    }
+}
+
+pub(super) fn record(time: u64, device_id: u32, info: &BMSInfo) {
+    if let Ok(mut r) = BMS_RECORDER.lock() {
+        if let Some(recorder) = r.as_mut() {
+            let key = device_id.to_string();
+            let (header, row) = (info.csv_header(), info.csv_row(time, device_id));
+            if let Err(e) = recorder.write(&key, time, header, row) {
+                eprintln!("record bms info failed: {}", e);
+                *r = None;
+            }
+        }
+    }
+}
+
+/// 显示记录状态，记录多台设备时显示记录目录
+pub fn show_record_status(handle: &AppWindow) {
+    let service = handle.global::<BMSModelService>();
+    let status = BMS_RECORDER.lock().ok().and_then(|r| {
+        r.as_ref().map(|recorder| {
+            let file = match recorder.get_paths().as_slice() {
+                [path] => path.display().to_string(),
+                paths => std::format!("{} ({} files)", recorder.get_dir().display(), paths.len()),
+            };
+            (file, recorder.get_rows())
+        })
+    });
+    service.set_recording(status.is_some());
+    let (file, rows) = status.unwrap_or_default();
+    service.set_record_file(file.into());
+    service.set_record_rows(rows as i32);
+}
@@ -0,0 +1,187 @@
+use crate::ui::*;
+
+use chrono::{Local, TimeZone};
+use slint::*;
+
+use super::{analytics::cell_health_models, plot::update_plot_series, BMSSnapshot, BMS_TELEMETRY};
+use crate::caw::{
+    analytics::imbalance::CellStats,
+    protocols::bms::{BMSInfo, BMS_STATES},
+    utils::flags::FlagEvent,
+};
+
+/// 单行显示的最大电芯数
+const MAX_CELLS_PER_ROW: usize = 8;
+
+fn state_event_models(events: &[FlagEvent]) -> Vec<BMSStateEventModel> {
+    events
+        .iter()
+        .map(|event| BMSStateEventModel {
+            name: BMS_STATES[event.index].into(),
+            raised: event.raised,
+            time: format_clock(event.time).into(),
+        })
+        .collect()
+}
+
+/// 时刻，格式为时:分:秒
+fn format_clock(time: u64) -> String {
+    Local
+        .timestamp_micros(time as i64)
+        .single()
+        .map(|t| t.format("%H:%M:%S").to_string())
+        .unwrap_or_default()
+}
+
+fn temp_sensor_models(bms_info: &BMSInfo, stats: &[(i32, i32)]) -> Vec<BMSTempSensorModel> {
+    bms_info
+        .temp_sensors
+        .iter()
+        .zip(bms_info.temp_sensor_names())
+        .zip(stats.iter())
+        .map(|((sensor, name), &(min, max))| BMSTempSensorModel {
+            name: name.into(),
+            temperature: sensor.get_temperature() as f32 / 100.0,
+            min: min as f32 / 100.0,
+            max: max as f32 / 100.0,
+            alarm: sensor.is_alarm(),
+        })
+        .collect()
+}
+
+/// 电芯按行排列，超过单行上限时平均分为多行
+fn cell_rows(bms_info: &BMSInfo) -> Vec<BMSCellRowModel> {
+    let count = bms_info.get_cell_count();
+    let rows = count.div_ceil(MAX_CELLS_PER_ROW).max(1);
+    let row_size = count.div_ceil(rows).max(1);
+    let cells: Vec<BMSCellModel> = bms_info
+        .cell_voltage
+        .iter()
+        .zip(bms_info.balance.iter())
+        .enumerate()
+        .map(|(i, (&voltage, &balance))| BMSCellModel {
+            index: i as i32,
+            voltage: voltage as f32 / 100.0,
+            balance: balance as i32,
+        })
+        .collect();
+    cells
+        .chunks(row_size)
+        .map(|row| BMSCellRowModel {
+            cells: VecModel::from_slice(row),
+        })
+        .collect()
+}
+
+/// 将遥测数据显示到BMS视图
+pub(super) fn show_snapshot(handle: &AppWindow, snapshot: &BMSSnapshot) {
+    let service = handle.global::<BMSModelService>();
+    let bms_info = &snapshot.info;
+    let balance: Vec<i32> = bms_info.balance.iter().map(|&x| x as i32).collect();
+    let cell_voltage: Vec<f32> = bms_info
+        .cell_voltage
+        .iter()
+        .map(|&x| x as f32 / 100.0)
+        .collect();
+    service.set_bms_info(BMSInfoModel {
+        balance: VecModel::from_slice(balance.as_slice()),
+        cell_voltage: VecModel::from_slice(cell_voltage.as_slice()),
+        chg: bms_info.chg != 0,
+        current: bms_info.current as f32 / 100.0,
+        dsg: bms_info.dsg != 0,
+        soc: bms_info.soc as f32 / 100.0,
+        soh: bms_info.soh as f32 / 100.0,
+        state: VecModel::from_slice(bms_info.states().as_slice()),
+        temperature: bms_info.max_temperature() as f32 / 100.0,
+        temperature_alarm: bms_info.is_temperature_alarm(),
+        voltage: bms_info.voltage as f32 / 100.0,
+    });
+    service.set_temp_sensors(VecModel::from_slice(
+        temp_sensor_models(bms_info, &snapshot.temp_stats).as_slice(),
+    ));
+    service.set_state_events(VecModel::from_slice(
+        state_event_models(&snapshot.state_events).as_slice(),
+    ));
+    let state_since: Vec<SharedString> = snapshot
+        .state_since
+        .iter()
+        .map(|time| time.map(format_clock).unwrap_or_default().into())
+        .collect();
+    service.set_state_since(VecModel::from_slice(state_since.as_slice()));
+    service.set_cell_rows(VecModel::from_slice(cell_rows(bms_info).as_slice()));
+    let coulomb = &snapshot.coulomb;
+    service.set_energy(BMSEnergyModel {
+        charged_ah: coulomb.get_charged_ah() as f32,
+        discharged_ah: coulomb.get_discharged_ah() as f32,
+        charged_wh: coulomb.get_charged_wh() as f32,
+        discharged_wh: coulomb.get_discharged_wh() as f32,
+        capacity: coulomb.get_capacity().unwrap_or(0.0),
+        has_host_soc: coulomb.soc().is_some(),
+        host_soc: coulomb.soc().unwrap_or(0.0),
+    });
+    let stats = CellStats::new(&bms_info.get_cell_voltage());
+    service.set_cell_stats(stats.map_or_else(BMSCellStatsModel::default, |stats| {
+        BMSCellStatsModel {
+            spread: stats.spread() * 1000.0,
+            mean: stats.mean,
+            min: stats.min,
+            min_index: stats.min_index as i32,
+            max: stats.max,
+            max_index: stats.max_index as i32,
+        }
+    }));
+    service.set_cell_health(VecModel::from_slice(
+        cell_health_models(bms_info, &snapshot.cell_analyzer).as_slice(),
+    ));
+    update_plot_series(handle, bms_info.get_cell_count());
+}
+
+/// 更新设备列表中对应设备的SOC
+pub(super) fn update_list_soc(handle: &AppWindow, type_id: u32, device_id: u32, soc: f32) {
+    let list = handle.global::<DeviceModelService>().get_device_list();
+    for row in 0..list.row_count() {
+        if let Some(mut item) = list.row_data(row) {
+            if item.type_id == type_id as i32 && item.device_id == device_id as i32 {
+                if item.soc != soc {
+                    item.soc = soc;
+                    list.set_row_data(row, item);
+                }
+                break;
+            }
+        }
+    }
+}
+
+/// 选中设备后显示其遥测数据，尚未收到数据时显示默认值
+pub fn show_device(handle: &AppWindow, type_id: u32, device_id: u32) {
+    let snapshot = BMS_TELEMETRY
+        .lock()
+        .ok()
+        .and_then(|telemetry| telemetry.get(&(type_id, device_id)).map(|t| t.snapshot()))
+        .unwrap_or_default();
+    show_snapshot(handle, &snapshot);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cell_rows_test() {
+        let rows = |count: usize| {
+            let p = BMSInfo {
+                cell_voltage: vec![0; count],
+                balance: vec![0; count],
+                ..Default::default()
+            };
+            cell_rows(&p)
+                .iter()
+                .map(|row| row.cells.row_count())
+                .collect::<Vec<usize>>()
+        };
+        assert_eq!(rows(4), vec![4]);
+        assert_eq!(rows(8), vec![8]);
+        assert_eq!(rows(13), vec![7, 6]);
+        assert_eq!(rows(16), vec![8, 8]);
+    }
+}
@@ -1 +1,3 @@
 pub mod plot;
+pub mod series;
+pub mod viewport;
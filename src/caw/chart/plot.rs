@@ -1,31 +1,116 @@
+use chrono::{Local, TimeZone};
 use plotters::prelude::*;
 use slint::SharedPixelBuffer;
 
-pub fn render_plot(width: f32, height: f32) -> slint::Image {
-    let mut pixel_buffer = SharedPixelBuffer::new(width as u32, height as u32);
+/// 绘图区边距，界面按此换算光标位置，需与Plot组件保持一致
+const MARGIN: u32 = 5;
+const X_LABEL_AREA: u32 = 20;
+const Y_LABEL_AREA: u32 = 40;
+
+/// 曲线数据，时间为主机时间(us)
+pub struct PlotSeries {
+    pub color: RGBColor,
+    pub points: Vec<(u64, f32)>,
+}
+
+/// 曲线颜色，与界面图例保持一致
+pub fn series_color(index: usize) -> RGBColor {
+    const COLORS: [RGBColor; 8] = [
+        RGBColor(0xea, 0x56, 0x56),
+        RGBColor(0x45, 0x8c, 0xd8),
+        RGBColor(0x45, 0xd8, 0x45),
+        RGBColor(0xff, 0x91, 0x00),
+        RGBColor(0x9c, 0x56, 0xea),
+        RGBColor(0x00, 0xb8, 0xb8),
+        RGBColor(0xd8, 0x45, 0xa8),
+        RGBColor(0x80, 0x80, 0x80),
+    ];
+    COLORS[index % COLORS.len()]
+}
+
+/// 纵轴范围，按数据自动缩放并留出边距
+pub fn value_range(series: &[PlotSeries]) -> (f32, f32) {
+    let (min, max) = series
+        .iter()
+        .flat_map(|s| s.points.iter().map(|&(_, v)| v))
+        .fold((f32::MAX, f32::MIN), |(min, max), v| {
+            (min.min(v), max.max(v))
+        });
+    if min > max {
+        return (0.0, 1.0);
+    }
+    let margin = ((max - min) * 0.05).max(0.05);
+    (min - margin, max + margin)
+}
+
+pub fn format_time(time: &u64) -> String {
+    Local
+        .timestamp_micros(*time as i64)
+        .single()
+        .map(|t| t.format("%H:%M:%S").to_string())
+        .unwrap_or_default()
+}
+
+/// 绘制[start, end]时间段内的曲线
+pub fn render_series(
+    width: f32,
+    height: f32,
+    series: &[PlotSeries],
+    start: u64,
+    end: u64,
+) -> slint::Image {
+    let mut pixel_buffer = SharedPixelBuffer::new(width.max(1.0) as u32, height.max(1.0) as u32);
     let size = (pixel_buffer.width(), pixel_buffer.height());
     let backend = BitMapBackend::with_buffer(pixel_buffer.make_mut_bytes(), size);
 
     let root = backend.into_drawing_area();
     root.fill(&WHITE).expect("error filling drawing area");
+    let (min, max) = value_range(series);
     let mut chart = ChartBuilder::on(&root)
-        .margin(0)
-        .x_label_area_size(30)
-        .y_label_area_size(30)
-        .build_cartesian_2d(-100f32..100f32, -2.0f32..2f32)
+        .margin(MARGIN)
+        .x_label_area_size(X_LABEL_AREA)
+        .y_label_area_size(Y_LABEL_AREA)
+        .build_cartesian_2d(start..end.max(start + 1), min..max)
         .expect("error building coordinate system");
-    chart.configure_mesh().draw().expect("error drawing");
-
     chart
-        .draw_series(LineSeries::new(
-            (-100..=100).map(|x| x as f32).map(|x| (x, x.sin())),
-            &RED,
-        ))
-        .unwrap()
-        .legend(|(x, y)| PathElement::new(vec![(x, y), (x, y)], &RED));
+        .configure_mesh()
+        .x_labels(5)
+        .y_labels(5)
+        .x_label_formatter(&format_time)
+        .draw()
+        .expect("error drawing");
+
+    for s in series.iter() {
+        chart
+            .draw_series(LineSeries::new(s.points.iter().copied(), &s.color))
+            .expect("error drawing series");
+    }
 
     root.present().expect("error presenting");
     drop(chart);
     drop(root);
     slint::Image::from_rgb8(pixel_buffer)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn value_range_test() {
+        assert_eq!(value_range(&[]), (0.0, 1.0));
+        let series = [
+            PlotSeries {
+                color: series_color(0),
+                points: vec![(0, 3.0), (1, 4.0)],
+            },
+            PlotSeries {
+                color: series_color(1),
+                points: vec![(0, 2.0)],
+            },
+        ];
+        let (min, max) = value_range(&series);
+        assert!((min - 1.9).abs() < 1e-6);
+        assert!((max - 4.1).abs() < 1e-6);
+    }
+}
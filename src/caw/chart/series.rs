@@ -0,0 +1,93 @@
+use std::collections::VecDeque;
+
+/// 时间序列
+///
+/// 时间为主机时间(us)，只保留最近retention时长内的数据
+#[derive(Debug, Clone)]
+pub struct TimeSeries {
+    points: VecDeque<(u64, f32)>,
+    retention: u64,
+}
+
+impl TimeSeries {
+    pub fn new(retention: u64) -> Self {
+        Self {
+            points: VecDeque::new(),
+            retention,
+        }
+    }
+
+    pub fn push(&mut self, time: u64, value: f32) {
+        self.points.push_back((time, value));
+        let oldest = time.saturating_sub(self.retention);
+        while self.points.front().is_some_and(|&(t, _)| t < oldest) {
+            self.points.pop_front();
+        }
+    }
+
+    /// 时间在[start, end]内的数据
+    pub fn range(&self, start: u64, end: u64) -> Vec<(u64, f32)> {
+        let begin = self.points.partition_point(|&(t, _)| t < start);
+        self.points
+            .range(begin..)
+            .take_while(|&&(t, _)| t <= end)
+            .copied()
+            .collect()
+    }
+
+    /// 与time最接近的数据
+    pub fn nearest(&self, time: u64) -> Option<(u64, f32)> {
+        let index = self.points.partition_point(|&(t, _)| t < time);
+        let after = self.points.get(index).copied();
+        let before = index
+            .checked_sub(1)
+            .and_then(|i| self.points.get(i))
+            .copied();
+        match (before, after) {
+            (Some(b), Some(a)) => Some(if time - b.0 <= a.0 - time { b } else { a }),
+            (b, a) => b.or(a),
+        }
+    }
+
+    pub fn last(&self) -> Option<(u64, f32)> {
+        self.points.back().copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.points.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn time_series_test() {
+        let mut s = TimeSeries::new(100);
+        for t in (0..=300).step_by(10) {
+            s.push(t, t as f32);
+        }
+        assert_eq!(s.len(), 11);
+        assert_eq!(s.last(), Some((300, 300.0)));
+        assert_eq!(
+            s.range(215, 250),
+            vec![(220, 220.0), (230, 230.0), (240, 240.0), (250, 250.0)]
+        );
+        assert!(s.range(0, 100).is_empty());
+        assert_eq!(s.nearest(0), Some((200, 200.0)));
+        assert_eq!(s.nearest(254), Some((250, 250.0)));
+        assert_eq!(s.nearest(256), Some((260, 260.0)));
+        assert_eq!(s.nearest(1000), Some((300, 300.0)));
+        s.clear();
+        assert!(s.is_empty());
+    }
+}
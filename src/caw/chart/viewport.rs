@@ -0,0 +1,112 @@
+/// 最小时间窗口(us)
+const MIN_SPAN: u64 = 10 * 1_000_000;
+
+/// 曲线横轴的可视时间范围
+///
+/// 跟随实时数据时右边界为当前时间，缩放与平移均以时间窗口的比例表示
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Viewport {
+    span: u64,
+    end: u64,
+    max_span: u64,
+    follow: bool,
+}
+
+impl Viewport {
+    pub fn new(span: u64, max_span: u64) -> Self {
+        Self {
+            span: span.clamp(MIN_SPAN, max_span),
+            end: 0,
+            max_span,
+            follow: true,
+        }
+    }
+
+    /// 当前的时间范围[start, end]
+    pub fn range(&self, now: u64) -> (u64, u64) {
+        let end = if self.follow { now } else { self.end };
+        (end.saturating_sub(self.span), end)
+    }
+
+    /// 横轴比例ratio(0~1)处的时间
+    pub fn time_at(&self, now: u64, ratio: f32) -> u64 {
+        let (start, _) = self.range(now);
+        start + (self.span as f64 * ratio.clamp(0.0, 1.0) as f64) as u64
+    }
+
+    pub fn is_follow(&self) -> bool {
+        self.follow
+    }
+
+    /// 开启跟随时回到最新数据，关闭时停留在当前位置
+    pub fn set_follow(&mut self, now: u64, follow: bool) {
+        if self.follow && !follow {
+            self.end = now;
+        }
+        self.follow = follow;
+    }
+
+    pub fn set_span(&mut self, span: u64) {
+        self.span = span.clamp(MIN_SPAN, self.max_span);
+    }
+
+    /// 以横轴比例anchor处为中心缩放，factor小于1为放大
+    pub fn zoom(&mut self, now: u64, anchor: f32, factor: f32) {
+        let anchor = anchor.clamp(0.0, 1.0) as f64;
+        let time = self.time_at(now, anchor as f32);
+        let span = ((self.span as f64 * factor as f64) as u64).clamp(MIN_SPAN, self.max_span);
+        if !self.follow {
+            self.end = time + (span as f64 * (1.0 - anchor)) as u64;
+        }
+        self.span = span;
+    }
+
+    /// 按时间窗口的比例平移，ratio为正时向更早的时间移动，平移后停止跟随
+    pub fn pan(&mut self, now: u64, ratio: f32) {
+        let (_, end) = self.range(now);
+        let offset = (self.span as f64 * ratio.abs() as f64) as u64;
+        self.end = if ratio > 0.0 {
+            end.saturating_sub(offset)
+        } else {
+            (end + offset).min(now)
+        };
+        self.follow = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const S: u64 = 1_000_000;
+
+    #[test]
+    fn viewport_follow_test() {
+        let mut v = Viewport::new(60 * S, 600 * S);
+        assert_eq!(v.range(1000 * S), (940 * S, 1000 * S));
+        assert_eq!(v.time_at(1000 * S, 0.5), 970 * S);
+        v.zoom(1000 * S, 1.0, 0.5);
+        assert!(v.is_follow());
+        assert_eq!(v.range(1000 * S), (970 * S, 1000 * S));
+        v.zoom(1000 * S, 1.0, 100.0);
+        assert_eq!(v.range(1000 * S), (400 * S, 1000 * S));
+        v.zoom(1000 * S, 1.0, 0.0);
+        assert_eq!(v.range(1000 * S), (990 * S, 1000 * S));
+    }
+
+    #[test]
+    fn viewport_pan_zoom_test() {
+        let mut v = Viewport::new(60 * S, 600 * S);
+        v.pan(1000 * S, 0.5);
+        assert!(!v.is_follow());
+        assert_eq!(v.range(1010 * S), (910 * S, 970 * S));
+        v.pan(1010 * S, -1.0);
+        assert_eq!(v.range(1010 * S), (950 * S, 1010 * S));
+        v.zoom(1010 * S, 0.5, 0.5);
+        assert_eq!(v.range(1010 * S), (965 * S, 995 * S));
+        v.set_follow(1020 * S, true);
+        assert_eq!(v.range(1020 * S), (990 * S, 1020 * S));
+        v.set_follow(1030 * S, false);
+        assert_eq!(v.range(1100 * S), (1000 * S, 1030 * S));
+    }
+}
@@ -0,0 +1,560 @@
+use lazy_static::lazy_static;
+use serde_json::Value;
+use serialport::SerialPortType;
+use slint::Weak;
+use std::{
+    net::SocketAddr,
+    path::Path,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::caw::{
+    composer,
+    connector::Connector,
+    devices::{capture::Direction, device::Device, monitor::Monitor, serial::Serial},
+    inspector::{self, FrameRecord},
+    metrics,
+    protocols::{
+        code::{CmdCode, SystemCode},
+        discover::{type_name, Discover, DISCOVER_BAUD_RATE, DISCOVER_MAGIC},
+        pingpong::ping,
+        protocol::FrameBuffer,
+        timesync::host_time,
+    },
+    recorder::{self, RecordOptions, RecorderSet},
+    script::{self, ScriptContext},
+    telemetry,
+    utils::args,
+};
+
+/// 子命令，第一个参数为其中之一时以命令行模式运行，不启动界面
+pub const COMMANDS: [&str; 8] = [
+    "ports", "discover", "monitor", "log", "ping", "send", "script", "help",
+];
+/// 不带值的选项
+const SWITCHES: [&str; 2] = ["--json", "--csv"];
+/// 等待应答的默认超时时间
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+const USAGE: &str = "usage: caw-link-desktop <command> [options]
+
+commands:
+  ports                                  list serial ports
+  discover                               find devices on USB serial ports
+  monitor <device> [--duration <s>]      print telemetry and acks
+  log <device> [--csv] [--duration <s>]  log telemetry as CSV
+      [--record <dir>] [--record-max-size <MB>] [--record-max-minutes <min>]
+  ping <device> [--count <n>]            measure round-trip time
+  send <device> <group> <code> [hex]     send a frame and print the response
+      [--timeout <s>]
+  script <device> <file>                 run a test script and save a pass/fail report
+
+<device> is a serial port name or a device-id.
+options:
+  --json             JSON Lines output
+  --baud <rate>      serial baud rate (default 128000)
+  --metrics [addr]   serve Prometheus metrics for monitor and log
+                     (default 127.0.0.1:9686)";
+
+lazy_static! {
+    static ref OUTPUT: Mutex<Output> = Mutex::new(Output::new(Format::Text));
+}
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+#[derive(Debug, Clone)]
+pub enum CliError {
+    Usage(String),
+    DeviceNotFound(String),
+    Disconnected,
+    NoResponse,
+    TestFailed,
+}
+
+impl std::fmt::Display for CliError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            CliError::Usage(ref message) => {
+                write!(f, "{}\n\n{}", message, USAGE)
+            }
+            CliError::DeviceNotFound(ref device) => {
+                write!(f, "device not found: {}", device)
+            }
+            CliError::Disconnected => {
+                write!(f, "device disconnected")
+            }
+            CliError::NoResponse => {
+                write!(f, "no response")
+            }
+            CliError::TestFailed => {
+                write!(f, "test failed")
+            }
+        }
+    }
+}
+
+impl std::error::Error for CliError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            CliError::Usage(_) => None,
+            CliError::DeviceNotFound(_) => None,
+            CliError::Disconnected => None,
+            CliError::NoResponse => None,
+            CliError::TestFailed => None,
+        }
+    }
+}
+
+/// 输出格式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// 每行一条记录，字段以name=value列出
+    Text,
+    /// JSON Lines
+    Json,
+    /// 表头变化时重新输出表头
+    Csv,
+}
+
+/// 命令行输出，指定记录目录时按数据类型分别写入轮转的CSV文件
+struct Output {
+    format: Format,
+    header: Vec<String>,
+    recorder: Option<RecorderSet>,
+}
+
+impl Output {
+    fn new(format: Format) -> Self {
+        Self {
+            format,
+            header: vec![],
+            recorder: None,
+        }
+    }
+
+    /// 格式化一条记录，CSV格式表头变化时先输出表头
+    fn format(&mut self, kind: &str, header: Vec<String>, row: Vec<Value>) -> String {
+        match self.format {
+            Format::Text => {
+                let fields: Vec<String> = header
+                    .iter()
+                    .zip(row.iter())
+                    .map(|(name, value)| format!("{}={}", name, recorder::csv_field(value)))
+                    .collect();
+                format!("{} {}", kind, fields.join(" "))
+            }
+            Format::Json => recorder::to_json(kind, &header, &row).to_string(),
+            Format::Csv => {
+                let fields: Vec<String> = row.iter().map(recorder::csv_field).collect();
+                let line = fields.join(",");
+                if header != self.header {
+                    let line = format!("{}\n{}", header.join(","), line);
+                    self.header = header;
+                    return line;
+                }
+                line
+            }
+        }
+    }
+
+    fn emit(&mut self, kind: &str, header: Vec<String>, row: Vec<Value>) {
+        if let Some(recorder) = self.recorder.as_mut() {
+            if let Err(e) = recorder.write(kind, host_time(), header, row) {
+                eprintln!("record failed: {}", e);
+            }
+            return;
+        }
+        println!("{}", self.format(kind, header, row));
+    }
+}
+
+fn strings(values: &[&str]) -> Vec<String> {
+    values.iter().map(|&v| v.into()).collect()
+}
+
+fn emit(kind: &str, header: Vec<String>, row: Vec<Value>) {
+    if let Ok(mut output) = OUTPUT.lock() {
+        output.emit(kind, header, row);
+    }
+}
+
+/// 输出数据帧，文本格式附带十六进制对照
+fn emit_frame(frame: &FrameRecord) {
+    let Ok(mut output) = OUTPUT.lock() else {
+        return;
+    };
+    if output.format == Format::Text {
+        println!("{}", inspector::describe(frame));
+        return;
+    }
+    let header = strings(&["time", "direction", "command", "size", "crc", "data"]);
+    let row = vec![
+        recorder::format_time(frame.time).into(),
+        format!("{:?}", frame.direction).into(),
+        frame.command().into(),
+        frame.size.into(),
+        if frame.crc_ok { "ok" } else { "error" }.into(),
+        composer::format_hex(&frame.data).into(),
+    ];
+    output.emit("frame", header, row);
+}
+
+/// USB串口，与界面中的设备搜索一致
+fn usb_ports() -> Result<Vec<String>> {
+    Ok(Serial::ports()?
+        .into_iter()
+        .filter(|port| matches!(port.port_type, SerialPortType::UsbPort(_)))
+        .map(|port| port.port_name)
+        .collect())
+}
+
+/// 发送发现指令并按应答设置设备编号
+fn probe(port: &str, baud_rate: u32) -> Result<Serial> {
+    let mut r_buf = [0u8; 12];
+    let mut serial = Serial::probe(port, baud_rate, DISCOVER_MAGIC.as_slice(), &mut r_buf)?;
+    Discover::check_device_magic(&r_buf[0..4])?;
+    let (device_id, type_id) = Discover::parse(&r_buf[4..12])?.get_id();
+    serial.set_id(device_id, type_id);
+    Ok(serial)
+}
+
+/// 按串口名称或设备编号打开设备
+fn open_device(target: &str, baud_rate: u32) -> Result<Box<dyn Device + Send>> {
+    if Serial::ports()?.iter().any(|port| port.port_name == target) {
+        return Ok(Box::new(probe(target, baud_rate)?));
+    }
+    let device_id = target
+        .parse::<u32>()
+        .map_err(|_| CliError::DeviceNotFound(target.into()))?;
+    for port in usb_ports()? {
+        if let Ok(serial) = probe(&port, baud_rate) {
+            if serial.get_id().0 == device_id {
+                return Ok(Box::new(serial));
+            }
+        }
+    }
+    Err(CliError::DeviceNotFound(target.into()).into())
+}
+
+/// 通过Connector接收数据，直到设备断开或超过运行时间，acks为是否输出应答
+fn run_connector(
+    device: Box<dyn Device + Send>,
+    acks: bool,
+    duration: Option<Duration>,
+    metrics_addr: Option<SocketAddr>,
+) -> Result<()> {
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    let connected = rt.block_on(async move {
+        if let Some(addr) = metrics_addr {
+            tokio::spawn(async move {
+                if let Err(e) = metrics::serve(addr).await {
+                    eprintln!("metrics server failed: {}", e);
+                }
+            });
+        }
+        let (device_id, type_id) = device.get_id();
+        metrics::count_connect(type_id, device_id);
+        let _subscription = telemetry::subscribe(move |record| {
+            if acks || !record.kind.is_event() {
+                let (header, row) = (record.header.to_vec(), record.row.to_vec());
+                emit(record.kind.name(), header, row);
+            }
+        });
+        let mut connector = Connector::new(Box::new(Monitor::new(device)));
+        connector.event_loop(telemetry::event(), Weak::default());
+        let start = Instant::now();
+        while duration.is_none_or(|d| start.elapsed() < d) {
+            if !connector.is_running() || connector.check_timeout() {
+                return false;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        true
+    });
+    rt.shutdown_background();
+    if !connected {
+        return Err(CliError::Disconnected.into());
+    }
+    Ok(())
+}
+
+/// 运行测试脚本，报告记录逐条输出到标准错误，结束后输出完整报告并保存在脚本旁
+fn run_script(device: Box<dyn Device + Send>, path: &Path) -> Result<()> {
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    let (device_id, type_id) = device.get_id();
+    let script_path = path.to_path_buf();
+    let ret = rt.block_on(async move {
+        let mut connector = Connector::new(Box::new(Monitor::new(device)));
+        connector.event_loop(telemetry::event(), Weak::default());
+        let handle = connector.get_device();
+        let ctx = ScriptContext {
+            type_id,
+            device_id,
+            send: Arc::new(move |group: u32, code: u32, data: &[u8]| {
+                let mut device = handle.lock().map_err(|_| "device lock poisoned")?;
+                composer::send(&mut device, group, code, data)
+            }),
+        };
+        // 脚本运行期间保持连接
+        let ret = tokio::task::spawn_blocking(move || {
+            script::run(&script_path, ctx, |entry| eprintln!("{}", entry.to_line()))
+                .map_err(|e| e.to_string())
+        })
+        .await;
+        drop(connector);
+        ret
+    });
+    rt.shutdown_background();
+    let report = ret??;
+    println!("{}", report.to_text());
+    let out = script::save_report(path, &report)?;
+    eprintln!("report saved to {}", out.display());
+    if !report.passed() {
+        return Err(CliError::TestFailed.into());
+    }
+    Ok(())
+}
+
+fn list_ports() -> Result<()> {
+    for port in Serial::ports()? {
+        let (kind, description) = match port.port_type {
+            SerialPortType::UsbPort(info) => (
+                "usb",
+                format!(
+                    "{:04x}:{:04x} {}",
+                    info.vid,
+                    info.pid,
+                    info.product.unwrap_or_default()
+                ),
+            ),
+            SerialPortType::PciPort => ("pci", String::new()),
+            SerialPortType::BluetoothPort => ("bluetooth", String::new()),
+            SerialPortType::Unknown => ("unknown", String::new()),
+        };
+        emit(
+            "port",
+            strings(&["name", "port_type", "description"]),
+            vec![
+                port.port_name.into(),
+                kind.into(),
+                description.trim().into(),
+            ],
+        );
+    }
+    Ok(())
+}
+
+fn discover(baud_rate: u32) -> Result<()> {
+    for port in usb_ports()? {
+        if let Ok(serial) = probe(&port, baud_rate) {
+            let (device_id, type_id) = serial.get_id();
+            emit(
+                "device",
+                strings(&["port", "device_type", "device_id"]),
+                vec![port.into(), type_name(type_id).into(), device_id.into()],
+            );
+        }
+    }
+    Ok(())
+}
+
+fn ping_device(device: &mut Box<dyn Device + Send>, count: usize) -> Result<()> {
+    let (device_id, _) = device.get_id();
+    let mut frames = FrameBuffer::new();
+    let mut received = 0;
+    for seq in 0..count {
+        if seq > 0 {
+            thread::sleep(Duration::from_millis(500));
+        }
+        let start = Instant::now();
+        ping(device)?;
+        let mut rtt = None;
+        while let Some(remain) = DEFAULT_TIMEOUT.checked_sub(start.elapsed()) {
+            match frames.read_frame(device, remain) {
+                Ok((header, _)) if header.get_cmd_code() == CmdCode::System(SystemCode::Pong) => {
+                    rtt = Some(start.elapsed());
+                    break;
+                }
+                Ok(_) => (),
+                Err(_) => break,
+            }
+        }
+        let header = strings(&["seq", "device_id", "rtt_ms"]);
+        match rtt {
+            Some(rtt) => {
+                received += 1;
+                let rtt = (rtt.as_secs_f64() * 10000.0).round() / 10.0;
+                emit(
+                    "pong",
+                    header,
+                    vec![seq.into(), device_id.into(), rtt.into()],
+                );
+            }
+            None => emit(
+                "timeout",
+                header,
+                vec![seq.into(), device_id.into(), Value::Null],
+            ),
+        }
+    }
+    if received == 0 {
+        return Err(CliError::NoResponse.into());
+    }
+    Ok(())
+}
+
+fn send_frame(
+    device: &mut Box<dyn Device + Send>,
+    group: u32,
+    code: u32,
+    payload: &[u8],
+    timeout: Duration,
+) -> Result<()> {
+    let mut frames = FrameBuffer::new();
+    composer::send(device, group, code, payload)?;
+    let start = Instant::now();
+    while let Some(remain) = timeout.checked_sub(start.elapsed()) {
+        let Ok(frame) = frames.read_raw_frame(device, remain) else {
+            break;
+        };
+        if composer::is_response((group, code), frame.get_cmd_code()) {
+            emit_frame(&FrameRecord::new(0, Direction::Rx, frame));
+            return Ok(());
+        }
+    }
+    Err(CliError::NoResponse.into())
+}
+
+fn duration_arg(args: &[String], name: &str) -> Result<Option<Duration>> {
+    Ok(args::positive(args, name)?.map(Duration::from_secs_f64))
+}
+
+fn execute(command: &str, args: &[String]) -> Result<()> {
+    let positional = args::positional(args, 2, &SWITCHES);
+    let baud_rate = args::positive(args, "--baud")?.map_or(DISCOVER_BAUD_RATE, |v| v as u32);
+    let format = if args::flag(args, "--json") {
+        Format::Json
+    } else if command == "log" {
+        Format::Csv
+    } else {
+        Format::Text
+    };
+    if let Ok(mut output) = OUTPUT.lock() {
+        *output = Output::new(format);
+    }
+    let target = || {
+        positional
+            .first()
+            .map(|s| s.as_str())
+            .ok_or_else(|| CliError::Usage(format!("{} requires a device", command)))
+    };
+    match command {
+        "ports" => list_ports(),
+        "discover" => discover(baud_rate),
+        "monitor" => {
+            let device = open_device(target()?, baud_rate)?;
+            let duration = duration_arg(args, "--duration")?;
+            run_connector(device, true, duration, metrics::addr_arg(args)?)
+        }
+        "log" => {
+            let device = open_device(target()?, baud_rate)?;
+            if let Some(options) = RecordOptions::from_args(args)? {
+                let prefix = type_name(device.get_id().1).to_lowercase();
+                let recorder = RecorderSet::new(&prefix, options)?;
+                if let Ok(mut output) = OUTPUT.lock() {
+                    output.recorder = Some(recorder);
+                }
+            }
+            let duration = duration_arg(args, "--duration")?;
+            let ret = run_connector(device, false, duration, metrics::addr_arg(args)?);
+            if let Ok(mut output) = OUTPUT.lock() {
+                output.recorder = None;
+            }
+            ret
+        }
+        "ping" => {
+            let mut device = open_device(target()?, baud_rate)?;
+            let count = args::positive(args, "--count")?.map_or(4, |v| v as usize);
+            ping_device(&mut device, count)
+        }
+        "send" => {
+            let (Some(group), Some(code)) = (positional.get(1), positional.get(2)) else {
+                return Err(CliError::Usage("send requires <device> <group> <code>".into()).into());
+            };
+            let payload = positional.get(3).map_or("", |s| s.as_str());
+            let (group, code, payload) = composer::compose(group, code, payload)?;
+            let mut device = open_device(target()?, baud_rate)?;
+            let timeout = duration_arg(args, "--timeout")?.unwrap_or(DEFAULT_TIMEOUT);
+            send_frame(&mut device, group, code, &payload, timeout)
+        }
+        "script" => {
+            let Some(path) = positional.get(1) else {
+                return Err(CliError::Usage("script requires <device> <file>".into()).into());
+            };
+            let device = open_device(target()?, baud_rate)?;
+            run_script(device, Path::new(path.as_str()))
+        }
+        _ => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+    }
+}
+
+/// 第一个参数为子命令时执行并返回结果，否则返回None以启动界面
+pub fn run(args: &[String]) -> Option<Result<()>> {
+    let command = args.get(1).filter(|c| COMMANDS.contains(&c.as_str()))?;
+    Some(execute(command, args))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn output_format_test() {
+        let header = strings(&["device_id", "state", "name"]);
+        let row = vec![1.into(), recorder::number(-2.5), "a\"b".into()];
+        let mut output = Output::new(Format::Text);
+        assert_eq!(
+            output.format("bms", header.clone(), row.clone()),
+            "bms device_id=1 state=-2.5 name=a\"b"
+        );
+        let mut output = Output::new(Format::Json);
+        assert_eq!(
+            output.format("bms", header.clone(), row.clone()),
+            r#"{"type":"bms","device_id":1,"state":-2.5,"name":"a\"b"}"#
+        );
+        // 文本列即使形如数值也按字符串输出
+        assert_eq!(
+            output.format("frame", strings(&["data"]), vec!["00".into()]),
+            r#"{"type":"frame","data":"00"}"#
+        );
+        let mut output = Output::new(Format::Csv);
+        assert_eq!(
+            output.format("bms", header.clone(), row.clone()),
+            "device_id,state,name\n1,-2.5,a\"b"
+        );
+        assert_eq!(output.format("bms", header, row), "1,-2.5,a\"b");
+    }
+
+    #[test]
+    fn run_test() {
+        assert!(run(&strings(&["app"])).is_none());
+        assert!(run(&strings(&["app", "--record", "out"])).is_none());
+        let e = run(&strings(&["app", "monitor"])).unwrap().unwrap_err();
+        assert!(e.to_string().starts_with("monitor requires a device"));
+        assert!(run(&strings(&["app", "send", "1"])).unwrap().is_err());
+        let e = run(&strings(&["app", "script", "1"])).unwrap().unwrap_err();
+        assert!(e.to_string().starts_with("script requires <device> <file>"));
+        assert!(run(&strings(&["app", "ping", "--count", "x"]))
+            .unwrap()
+            .is_err());
+    }
+}
@@ -0,0 +1,233 @@
+use lazy_static::lazy_static;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+};
+
+use super::protocols::timesync::host_time;
+
+/// 参与估算的最大样本数
+const MAX_SAMPLES: usize = 32;
+
+lazy_static! {
+    /// 各设备的时钟同步状态，以(type_id, device_id)为键
+    static ref CLOCKS: Mutex<HashMap<(u32, u32), ClockSync>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ClockSample {
+    device_tick: u64, // us
+    host_time: u64,   // us，请求与应答的中点
+    rtt: u64,         // us
+}
+
+/// 设备时钟同步
+///
+/// 根据时间同步的往返样本估算设备计数与主机时间的偏移及漂移。
+/// 串口延时抖动较大，只取往返时延不超过最小时延两倍的样本做线性拟合
+#[derive(Debug)]
+pub struct ClockSync {
+    samples: VecDeque<ClockSample>,
+    base_tick: u64,
+    base_offset: i64,
+    offset: f64,
+    drift: f64,
+}
+
+impl Default for ClockSync {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ClockSync {
+    pub fn new() -> Self {
+        Self {
+            samples: VecDeque::new(),
+            base_tick: 0,
+            base_offset: 0,
+            offset: 0.0,
+            drift: 0.0,
+        }
+    }
+
+    /// 加入一次同步样本
+    ///
+    /// `send_time`为主机发送请求时刻，`recv_time`为主机收到应答时刻
+    pub fn update(&mut self, send_time: u64, device_tick: u64, recv_time: u64) {
+        if recv_time < send_time {
+            return;
+        }
+        // 设备重启后计数归零，丢弃旧样本
+        if let Some(last) = self.samples.back() {
+            if device_tick < last.device_tick {
+                self.samples.clear();
+            }
+        }
+        if self.samples.len() >= MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(ClockSample {
+            device_tick,
+            host_time: send_time + (recv_time - send_time) / 2,
+            rtt: recv_time - send_time,
+        });
+        self.estimate();
+    }
+
+    fn estimate(&mut self) {
+        let min_rtt = self.samples.iter().map(|s| s.rtt).min().unwrap_or(0);
+        let samples: Vec<ClockSample> = self
+            .samples
+            .iter()
+            .filter(|s| s.rtt <= min_rtt * 2)
+            .copied()
+            .collect();
+
+        let base = samples[0];
+        self.base_tick = base.device_tick;
+        self.base_offset = base.host_time as i64 - base.device_tick as i64;
+
+        // 以偏移量对设备计数做最小二乘拟合，斜率即为漂移
+        let points: Vec<(f64, f64)> = samples
+            .iter()
+            .map(|s| {
+                let x = s.device_tick as f64 - self.base_tick as f64;
+                let y = (s.host_time as i64 - s.device_tick as i64 - self.base_offset) as f64;
+                (x, y)
+            })
+            .collect();
+        let n = points.len() as f64;
+        let mean_x = points.iter().map(|p| p.0).sum::<f64>() / n;
+        let mean_y = points.iter().map(|p| p.1).sum::<f64>() / n;
+        let sxx: f64 = points.iter().map(|p| (p.0 - mean_x).powi(2)).sum();
+        let sxy: f64 = points.iter().map(|p| (p.0 - mean_x) * (p.1 - mean_y)).sum();
+        self.drift = if sxx > 0.0 { sxy / sxx } else { 0.0 };
+        self.offset = mean_y - self.drift * mean_x;
+    }
+
+    pub fn is_synced(&self) -> bool {
+        !self.samples.is_empty()
+    }
+
+    /// 主机时间与设备计数的偏移，单位us
+    pub fn get_offset(&self) -> i64 {
+        let tick = self.samples.back().map(|s| s.device_tick).unwrap_or(0);
+        self.offset_at(tick).round() as i64
+    }
+
+    /// 设备时钟相对主机时钟的漂移，设备走快为正，单位ppm
+    pub fn get_drift_ppm(&self) -> f64 {
+        (1.0 / (1.0 + self.drift) - 1.0) * 1e6
+    }
+
+    /// 最近一次同步的往返时延，单位us
+    pub fn get_rtt(&self) -> Option<u64> {
+        self.samples.back().map(|s| s.rtt)
+    }
+
+    fn offset_at(&self, device_tick: u64) -> f64 {
+        self.base_offset as f64
+            + self.offset
+            + self.drift * (device_tick as f64 - self.base_tick as f64)
+    }
+
+    /// 将设备计数换算为主机时间，未同步时返回None
+    pub fn to_host_time(&self, device_tick: u64) -> Option<u64> {
+        if !self.is_synced() {
+            return None;
+        }
+        Some((device_tick as f64 + self.offset_at(device_tick)).round() as u64)
+    }
+}
+
+/// 更新设备的时钟同步状态，设备首次同步时新建
+pub fn with_clock<F, R>(type_id: u32, device_id: u32, f: F) -> Option<R>
+where
+    F: FnOnce(&mut ClockSync) -> R,
+{
+    let mut clocks = CLOCKS.lock().ok()?;
+    Some(f(clocks.entry((type_id, device_id)).or_default()))
+}
+
+/// 数据的主机时间
+///
+/// 数据附带设备计数且设备时钟已同步时按同步结果换算，否则取当前主机时间
+pub fn sample_time(type_id: u32, device_id: u32, device_tick: Option<u64>) -> u64 {
+    device_tick
+        .and_then(|tick| {
+            let clocks = CLOCKS.lock().ok()?;
+            clocks.get(&(type_id, device_id))?.to_host_time(tick)
+        })
+        .unwrap_or_else(host_time)
+}
+
+/// 设备断开时清除同步状态，重新连接后需重新同步
+pub fn remove_device(type_id: u32, device_id: u32) {
+    if let Ok(mut clocks) = CLOCKS.lock() {
+        clocks.remove(&(type_id, device_id));
+    }
+}
+
+/// 设备编号修改后迁移同步状态
+pub fn rekey_device(type_id: u32, device_id: u32, new_device_id: u32) {
+    if let Ok(mut clocks) = CLOCKS.lock() {
+        if let Some(clock) = clocks.remove(&(type_id, device_id)) {
+            clocks.insert((type_id, new_device_id), clock);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OFFSET: u64 = 1_700_000_000_000_000;
+
+    /// 模拟50ppm漂移的设备时钟
+    fn device_tick(host_time: u64) -> u64 {
+        let t = (host_time - OFFSET) as f64;
+        (t * (1.0 + 50e-6)) as u64
+    }
+
+    #[test]
+    fn clock_sync_test() {
+        let mut clock = ClockSync::new();
+        assert!(clock.to_host_time(0).is_none());
+        for i in 0..20u64 {
+            let send_time = OFFSET + i * 5_000_000;
+            // 请求延时1ms，应答延时在1ms~20ms之间抖动
+            let tick = device_tick(send_time + 1000);
+            let recv_time = send_time + 2000 + (i * 7 % 5) * 4500;
+            clock.update(send_time, tick, recv_time);
+        }
+        let host_time = OFFSET + 100_000_000;
+        let estimate = clock.to_host_time(device_tick(host_time)).unwrap();
+        assert!((estimate as i64 - host_time as i64).abs() < 100);
+        assert!((clock.get_drift_ppm() - 50.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn clock_reset_test() {
+        let mut clock = ClockSync::new();
+        clock.update(OFFSET, 10_000_000, OFFSET + 2000);
+        clock.update(OFFSET + 5_000_000, 1000, OFFSET + 5_002_000);
+        assert_eq!(clock.to_host_time(1000).unwrap(), OFFSET + 5_001_000);
+    }
+
+    #[test]
+    fn sample_time_test() {
+        with_clock(9, 1, |clock| {
+            clock.update(OFFSET, 1000, OFFSET + 2000);
+        });
+        assert_eq!(sample_time(9, 1, Some(3000)), OFFSET + 3000);
+        rekey_device(9, 1, 2);
+        assert_eq!(sample_time(9, 2, Some(3000)), OFFSET + 3000);
+        // 未附带计数或未同步时取当前主机时间
+        let now = host_time();
+        assert!(sample_time(9, 2, None) >= now);
+        assert!(sample_time(9, 1, Some(3000)) >= now);
+        remove_device(9, 2);
+        assert!(sample_time(9, 2, Some(3000)) >= now);
+    }
+}
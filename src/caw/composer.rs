@@ -0,0 +1,573 @@
+use crate::ui::*;
+
+use bincode::{config, Decode, Encode};
+use lazy_static::lazy_static;
+use slint::{ComponentHandle, Model, VecModel};
+use std::{
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use crate::caw::{
+    devices::{capture::Direction, device::Device},
+    history,
+    inspector::FrameRecord,
+    protocols::{
+        code::{BMSCode, CmdCode, MotorCode, SystemCode, CMD_CODES},
+        protocol::{ProtocolHeader, RawFrame},
+        system::DEVICE_NAME_SIZE,
+    },
+};
+
+/// 常用指令在设置表中的键
+const SNIPPETS_KEY: &str = "composer_snippets";
+/// 等待应答的超时时间
+pub const RESPONSE_TIMEOUT: Duration = Duration::from_secs(1);
+/// 周期上报的指令，除非与发送的指令对应，否则不视为应答
+const PERIODIC_CODES: [CmdCode; 4] = [
+    CmdCode::System(SystemCode::Pong),
+    CmdCode::System(SystemCode::TimeSync),
+    CmdCode::BMS(BMSCode::Info),
+    CmdCode::Motor(MotorCode::Info),
+];
+
+lazy_static! {
+    static ref SNIPPETS: Mutex<Vec<Snippet>> = Mutex::new(load_snippets());
+    /// 等待应答的发送请求及下一个请求编号
+    static ref WAITERS: Mutex<(u64, Vec<Waiter>)> = Mutex::new((0, vec![]));
+}
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+#[derive(Debug, Clone)]
+pub enum ComposerError {
+    Hex(String),
+    Value(String, String),
+    Number(String),
+}
+
+impl std::fmt::Display for ComposerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            ComposerError::Hex(ref text) => {
+                write!(f, "invalid hex: {}", text)
+            }
+            ComposerError::Value(ref name, ref value) => {
+                write!(f, "invalid value for {}: {}", name, value)
+            }
+            ComposerError::Number(ref text) => {
+                write!(f, "invalid number: {}", text)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ComposerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            ComposerError::Hex(_) => None,
+            ComposerError::Value(_, _) => None,
+            ComposerError::Number(_) => None,
+        }
+    }
+}
+
+/// 数据体字段类型，均按大端编码
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FieldKind {
+    U8,
+    U32,
+    U64,
+    /// 放大100倍后以i32传输的数值
+    Scaled,
+    /// 定长的UTF-8文本，不足部分补0
+    Text(usize),
+}
+
+impl FieldKind {
+    pub fn name(&self) -> String {
+        match *self {
+            FieldKind::U8 => "u8".into(),
+            FieldKind::U32 => "u32".into(),
+            FieldKind::U64 => "u64".into(),
+            FieldKind::Scaled => "x100".into(),
+            FieldKind::Text(size) => format!("text[{}]", size),
+        }
+    }
+
+    fn encode(&self, value: &str) -> Option<Vec<u8>> {
+        let value = value.trim();
+        let number = if value.is_empty() { "0" } else { value };
+        match *self {
+            FieldKind::U8 => parse_number(number)
+                .ok()?
+                .try_into()
+                .ok()
+                .map(|v: u8| vec![v]),
+            FieldKind::U32 => parse_number(number)
+                .ok()?
+                .try_into()
+                .ok()
+                .map(|v: u32| v.to_be_bytes().to_vec()),
+            FieldKind::U64 => parse_number(number).ok().map(|v| v.to_be_bytes().to_vec()),
+            FieldKind::Scaled => number
+                .parse::<f32>()
+                .ok()
+                .map(|v| ((v * 100.0).round() as i32).to_be_bytes().to_vec()),
+            FieldKind::Text(size) => {
+                if value.len() > size {
+                    return None;
+                }
+                let mut buf = value.as_bytes().to_vec();
+                buf.resize(size, 0);
+                Some(buf)
+            }
+        }
+    }
+}
+
+/// 已知指令的数据体字段
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PayloadField {
+    pub name: &'static str,
+    pub kind: FieldKind,
+}
+
+const fn field(name: &'static str, kind: FieldKind) -> PayloadField {
+    PayloadField { name, kind }
+}
+
+/// 指令的数据体结构，与各协议模块中的请求一致，无数据体或未知时为空
+pub fn payload_fields(code: CmdCode) -> Vec<PayloadField> {
+    match code {
+        CmdCode::System(SystemCode::Bootloader) | CmdCode::System(SystemCode::FirmwareVerify) => {
+            vec![
+                field("size", FieldKind::U32),
+                field("chunk_size", FieldKind::U32),
+                field("crc", FieldKind::U32),
+            ]
+        }
+        CmdCode::System(SystemCode::TimeSync) => vec![field("host_time", FieldKind::U64)],
+        CmdCode::System(SystemCode::Provision) => vec![
+            field("device_id", FieldKind::U32),
+            field("name", FieldKind::Text(DEVICE_NAME_SIZE)),
+        ],
+        CmdCode::Motor(MotorCode::Enable) => vec![field("enable", FieldKind::U8)],
+        CmdCode::Motor(MotorCode::SetSpeed) => vec![field("rpm", FieldKind::Scaled)],
+        CmdCode::Motor(MotorCode::SetTorque) => vec![field("current", FieldKind::Scaled)],
+        CmdCode::Motor(MotorCode::SetPosition) => vec![field("position", FieldKind::Scaled)],
+        _ => vec![],
+    }
+}
+
+/// 按字段编码数据体，空值按0或空文本处理
+pub fn encode_fields(fields: &[PayloadField], values: &[String]) -> Result<Vec<u8>> {
+    let mut buf = vec![];
+    for (i, field) in fields.iter().enumerate() {
+        let value = values.get(i).map_or("", |v| v.as_str());
+        let mut data = field
+            .kind
+            .encode(value)
+            .ok_or_else(|| ComposerError::Value(field.name.into(), value.into()))?;
+        buf.append(&mut data);
+    }
+    Ok(buf)
+}
+
+/// 解析十进制或0x开头的十六进制数
+pub fn parse_number(text: &str) -> Result<u64> {
+    let text = text.trim();
+    let ret = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => text.parse::<u64>(),
+    };
+    Ok(ret.map_err(|_| ComposerError::Number(text.into()))?)
+}
+
+/// 解析十六进制数据，字节之间可用空格或逗号分隔，也可连写
+pub fn parse_hex(text: &str) -> Result<Vec<u8>> {
+    let mut buf = vec![];
+    for token in text.split(|c: char| c.is_whitespace() || c == ',') {
+        let token = token
+            .strip_prefix("0x")
+            .or_else(|| token.strip_prefix("0X"))
+            .unwrap_or(token);
+        if !token.len().is_multiple_of(2) || !token.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(ComposerError::Hex(token.into()).into());
+        }
+        for i in (0..token.len()).step_by(2) {
+            buf.push(u8::from_str_radix(&token[i..i + 2], 16)?);
+        }
+    }
+    Ok(buf)
+}
+
+pub fn format_hex(data: &[u8]) -> String {
+    data.iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// 按名称(如"Motor(SetSpeed)")查找已知指令
+pub fn find_command(name: &str) -> Option<CmdCode> {
+    CMD_CODES
+        .iter()
+        .find(|code| format!("{:?}", code) == name)
+        .copied()
+}
+
+/// 指令名称，无法识别时显示原始编号
+pub fn command_name(group: u32, code: u32) -> String {
+    match CmdCode::from_raw(group, code) {
+        Some(cmd_code) => format!("{:?}", cmd_code),
+        None => format!("Unknown({:#x}:{:#x})", group, code),
+    }
+}
+
+/// 发送数据帧，已知指令经ProtocolHeader::write_data发送，未知指令按原始编号打包
+pub fn send(device: &mut Box<dyn Device + Send>, group: u32, code: u32, data: &[u8]) -> Result<()> {
+    match CmdCode::from_raw(group, code) {
+        Some(cmd_code) => ProtocolHeader::write_data(device, cmd_code, data),
+        None => device.write(&ProtocolHeader::pack_raw(group, code, data)),
+    }
+}
+
+/// 接收到的指令是否为所发送指令的应答，无法识别的指令均视为应答
+pub fn is_response(sent: (u32, u32), cmd_code: Option<CmdCode>) -> bool {
+    let expected = match CmdCode::from_raw(sent.0, sent.1) {
+        Some(CmdCode::System(SystemCode::Ping)) => Some(CmdCode::System(SystemCode::Pong)),
+        code => code,
+    };
+    match cmd_code {
+        Some(code) if PERIODIC_CODES.contains(&code) => Some(code) == expected,
+        _ => true,
+    }
+}
+
+struct Waiter {
+    id: u64,
+    type_id: u32,
+    device_id: u32,
+    sent: (u32, u32),
+    tx: Sender<FrameRecord>,
+}
+
+/// 一次性的应答等待，释放时注销
+pub struct ResponseWaiter {
+    id: u64,
+    rx: Receiver<FrameRecord>,
+}
+
+impl ResponseWaiter {
+    /// 等待应答帧，超时返回None
+    pub fn wait(self, timeout: Duration) -> Option<FrameRecord> {
+        self.rx.recv_timeout(timeout).ok()
+    }
+}
+
+impl Drop for ResponseWaiter {
+    fn drop(&mut self) {
+        if let Ok(mut waiters) = WAITERS.lock() {
+            waiters.1.retain(|w| w.id != self.id);
+        }
+    }
+}
+
+/// 登记对所发送指令的应答等待，需在发送前调用，避免错过应答
+///
+/// 应答由Monitor直接转交，不受帧查看器暂停、过滤及容量的影响
+pub fn expect_response(type_id: u32, device_id: u32, sent: (u32, u32)) -> ResponseWaiter {
+    let (tx, rx) = mpsc::channel();
+    let mut waiters = WAITERS.lock().unwrap_or_else(|e| e.into_inner());
+    let id = waiters.0;
+    waiters.0 += 1;
+    waiters.1.push(Waiter {
+        id,
+        type_id,
+        device_id,
+        sent,
+        tx,
+    });
+    ResponseWaiter { id, rx }
+}
+
+/// 将收到的数据帧交给等待应答的发送请求，每个请求只接收第一个应答
+pub fn offer_response(type_id: u32, device_id: u32, frame: &RawFrame) {
+    let Ok(mut waiters) = WAITERS.lock() else {
+        return;
+    };
+    waiters.1.retain(|w| {
+        if w.type_id != type_id
+            || w.device_id != device_id
+            || !is_response(w.sent, frame.get_cmd_code())
+        {
+            return true;
+        }
+        let _ = w.tx.send(FrameRecord::new(0, Direction::Rx, frame.clone()));
+        false
+    });
+}
+
+/// 保存的常用指令
+#[derive(Encode, Decode, PartialEq, Debug, Clone)]
+pub struct Snippet {
+    pub name: String,
+    pub group: u32,
+    pub code: u32,
+    pub payload: Vec<u8>,
+}
+
+fn encode_snippets(snippets: &[Snippet]) -> Result<Vec<u8>> {
+    let config = config::standard()
+        .with_big_endian()
+        .with_fixed_int_encoding();
+    Ok(bincode::encode_to_vec(snippets, config)?)
+}
+
+fn decode_snippets(buf: &[u8]) -> Result<Vec<Snippet>> {
+    let config = config::standard()
+        .with_big_endian()
+        .with_fixed_int_encoding();
+    let (snippets, _): (Vec<Snippet>, usize) = bincode::decode_from_slice(buf, config)?;
+    Ok(snippets)
+}
+
+fn load_snippets() -> Vec<Snippet> {
+    history::load_setting(SNIPPETS_KEY)
+        .and_then(|buf| decode_snippets(&buf).ok())
+        .unwrap_or_default()
+}
+
+fn save_snippets(snippets: &[Snippet]) {
+    match encode_snippets(snippets) {
+        Ok(buf) => history::save_setting(SNIPPETS_KEY, &buf),
+        Err(e) => eprintln!("encode composer snippets failed: {}", e),
+    }
+}
+
+/// 解析界面中输入的分组、编号与十六进制数据体
+pub fn compose(group: &str, code: &str, payload: &str) -> Result<(u32, u32, Vec<u8>)> {
+    let group = u32::try_from(parse_number(group)?)?;
+    let code = u32::try_from(parse_number(code)?)?;
+    Ok((group, code, parse_hex(payload)?))
+}
+
+fn current_fields(handle: &AppWindow) -> Vec<PayloadField> {
+    let service = handle.global::<ComposerModelService>();
+    CMD_CODES
+        .get(service.get_command_index() as usize)
+        .map(|code| payload_fields(*code))
+        .unwrap_or_default()
+}
+
+/// 显示指令列表及常用指令
+pub fn show_composer(handle: &AppWindow) {
+    let service = handle.global::<ComposerModelService>();
+    let mut commands: Vec<slint::SharedString> = CMD_CODES
+        .iter()
+        .map(|code| format!("{:?}", code).into())
+        .collect();
+    commands.push("Raw".into());
+    service.set_commands(VecModel::from_slice(commands.as_slice()));
+    if service.get_command_index() < 0 {
+        service.set_command_index(commands.len() as i32 - 1);
+    }
+    let Ok(snippets) = SNIPPETS.lock() else {
+        return;
+    };
+    let snippets: Vec<ComposerSnippetModel> = snippets
+        .iter()
+        .map(|snippet| ComposerSnippetModel {
+            name: snippet.name.as_str().into(),
+            command: command_name(snippet.group, snippet.code).into(),
+            payload: format_hex(&snippet.payload).into(),
+        })
+        .collect();
+    service.set_snippets(VecModel::from_slice(snippets.as_slice()));
+}
+
+/// 选择指令，已知指令填入编号并按结构显示数据体字段，最后一项为原始编号
+pub fn select_command(handle: &AppWindow, index: i32) {
+    let service = handle.global::<ComposerModelService>();
+    service.set_command_index(index);
+    if let Some(code) = CMD_CODES.get(index as usize) {
+        let (group, code_index) = code.to_raw();
+        service.set_group(group.to_string().into());
+        service.set_code(code_index.to_string().into());
+    }
+    let fields: Vec<ComposerFieldModel> = current_fields(handle)
+        .iter()
+        .map(|field| ComposerFieldModel {
+            name: field.name.into(),
+            kind: field.kind.name().into(),
+            value: "".into(),
+        })
+        .collect();
+    service.set_fields(VecModel::from_slice(fields.as_slice()));
+    service.set_payload(match encode_fields(&current_fields(handle), &[]) {
+        Ok(buf) => format_hex(&buf).into(),
+        Err(_) => "".into(),
+    });
+}
+
+/// 修改字段值后重新编码数据体
+pub fn set_field(handle: &AppWindow, index: i32, value: &str) {
+    let service = handle.global::<ComposerModelService>();
+    let model = service.get_fields();
+    if let Some(mut row) = model.row_data(index as usize) {
+        row.value = value.into();
+        model.set_row_data(index as usize, row);
+    }
+    let values: Vec<String> = model.iter().map(|row| row.value.to_string()).collect();
+    match encode_fields(&current_fields(handle), &values) {
+        Ok(buf) => service.set_payload(format_hex(&buf).into()),
+        Err(e) => service.set_response(e.to_string().into()),
+    }
+}
+
+/// 保存当前输入的指令
+pub fn save_snippet(handle: &AppWindow, name: &str) {
+    let service = handle.global::<ComposerModelService>();
+    match compose(
+        &service.get_group(),
+        &service.get_code(),
+        &service.get_payload(),
+    ) {
+        Ok((group, code, payload)) => {
+            if let Ok(mut snippets) = SNIPPETS.lock() {
+                let name = match name.trim() {
+                    "" => command_name(group, code),
+                    name => name.into(),
+                };
+                snippets.push(Snippet {
+                    name,
+                    group,
+                    code,
+                    payload,
+                });
+                save_snippets(&snippets);
+            }
+        }
+        Err(e) => service.set_response(e.to_string().into()),
+    }
+    show_composer(handle);
+}
+
+/// 将常用指令填入输入框
+pub fn load_snippet(handle: &AppWindow, index: i32) {
+    let Some(snippet) = SNIPPETS
+        .lock()
+        .ok()
+        .and_then(|snippets| snippets.get(index as usize).cloned())
+    else {
+        return;
+    };
+    let service = handle.global::<ComposerModelService>();
+    let command_index = CMD_CODES
+        .iter()
+        .position(|code| code.to_raw() == (snippet.group, snippet.code))
+        .unwrap_or(CMD_CODES.len());
+    service.set_command_index(command_index as i32);
+    service.set_fields(VecModel::from_slice(&[]));
+    service.set_group(snippet.group.to_string().into());
+    service.set_code(snippet.code.to_string().into());
+    service.set_payload(format_hex(&snippet.payload).into());
+}
+
+pub fn remove_snippet(handle: &AppWindow, index: i32) {
+    if let Ok(mut snippets) = SNIPPETS.lock() {
+        if index >= 0 && (index as usize) < snippets.len() {
+            snippets.remove(index as usize);
+            save_snippets(&snippets);
+        }
+    }
+    show_composer(handle);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::caw::protocols::protocol::FrameBuffer;
+
+    #[test]
+    fn parse_hex_test() {
+        assert_eq!(parse_hex("").unwrap(), Vec::<u8>::new());
+        assert_eq!(parse_hex("01 0x02,ff").unwrap(), vec![1, 2, 0xff]);
+        assert_eq!(parse_hex("0a0B0c").unwrap(), vec![0x0a, 0x0b, 0x0c]);
+        assert!(parse_hex("123").is_err());
+        assert!(parse_hex("zz").is_err());
+        assert_eq!(format_hex(&[0x0a, 0xff]), "0a ff");
+        assert_eq!(parse_number("0x10").unwrap(), 16);
+        assert_eq!(compose("1", "0x1", "").unwrap(), (1, 1, vec![]));
+        assert!(compose("1", "x", "").is_err());
+    }
+
+    #[test]
+    fn encode_fields_test() {
+        let fields = payload_fields(CmdCode::Motor(MotorCode::SetSpeed));
+        let buf = encode_fields(&fields, &["-1.5".into()]).unwrap();
+        assert_eq!(buf, (-150i32).to_be_bytes());
+        let fields = payload_fields(CmdCode::System(SystemCode::Provision));
+        let buf = encode_fields(&fields, &["0x102".into(), "bms".into()]).unwrap();
+        assert_eq!(buf.len(), 4 + DEVICE_NAME_SIZE);
+        assert_eq!(&buf[..7], &[0, 0, 1, 2, b'b', b'm', b's']);
+        assert_eq!(encode_fields(&fields, &[]).unwrap(), vec![0; 20]);
+        let long = "x".repeat(DEVICE_NAME_SIZE + 1);
+        assert!(encode_fields(&fields, &["1".into(), long]).is_err());
+        let fields = payload_fields(CmdCode::Motor(MotorCode::Enable));
+        assert!(encode_fields(&fields, &["256".into()]).is_err());
+        assert!(payload_fields(CmdCode::System(SystemCode::Ping)).is_empty());
+    }
+
+    #[test]
+    fn response_test() {
+        let ping = CmdCode::System(SystemCode::Ping).to_raw();
+        let pong = Some(CmdCode::System(SystemCode::Pong));
+        let info = Some(CmdCode::BMS(BMSCode::Info));
+        let ack = Some(CmdCode::System(SystemCode::Ack));
+        assert!(is_response(ping, pong));
+        assert!(!is_response(ping, info));
+        assert!(is_response(CmdCode::BMS(BMSCode::Info).to_raw(), info));
+        assert!(is_response((9, 9), ack));
+        assert!(is_response((9, 9), None));
+        assert!(!is_response((9, 9), pong));
+    }
+
+    #[test]
+    fn response_waiter_test() {
+        let raw_frame = |code: CmdCode| {
+            let mut frames = FrameBuffer::new();
+            frames.push(&ProtocolHeader::pack(code, &[1]).unwrap());
+            frames.next_raw_frame().unwrap()
+        };
+        let ping = CmdCode::System(SystemCode::Ping).to_raw();
+        let waiter = expect_response(9, 1, ping);
+        offer_response(9, 1, &raw_frame(CmdCode::BMS(BMSCode::Info)));
+        offer_response(9, 2, &raw_frame(CmdCode::System(SystemCode::Pong)));
+        assert_eq!(waiter.rx.try_recv(), Err(mpsc::TryRecvError::Empty));
+        offer_response(9, 1, &raw_frame(CmdCode::System(SystemCode::Pong)));
+        let frame = waiter.wait(Duration::ZERO).unwrap();
+        assert_eq!(frame.cmd_code, Some(CmdCode::System(SystemCode::Pong)));
+        let waiter = expect_response(9, 3, ping);
+        drop(waiter);
+        assert!(WAITERS.lock().unwrap().1.iter().all(|w| w.device_id != 3));
+    }
+
+    #[test]
+    fn snippet_test() {
+        let snippets = vec![Snippet {
+            name: "enable".into(),
+            group: 3,
+            code: 1,
+            payload: vec![1],
+        }];
+        let buf = encode_snippets(&snippets).unwrap();
+        assert_eq!(decode_snippets(&buf).unwrap(), snippets);
+        assert_eq!(command_name(3, 1), "Motor(Enable)");
+        assert_eq!(command_name(3, 0x20), "Unknown(0x3:0x20)");
+    }
+}
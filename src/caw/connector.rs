@@ -16,12 +16,11 @@ use tokio::{runtime::Handle, task::JoinHandle};
 use crate::caw::protocols::{
     code::{CmdCode, SystemCode},
     pingpong::ping,
+    timesync::{self, TimeSyncResponse},
 };
 
 use super::{
-    devices::device::Device,
-    event::Event,
-    protocols::protocol::{self, ProtocolHeader},
+    bms, clock, devices::device::Device, event::Event, metrics, protocols::protocol::FrameBuffer,
 };
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
@@ -61,14 +60,22 @@ pub struct Connector {
     timeout: Arc<Mutex<Instant>>,
     event_task: Option<JoinHandle<()>>,
     running: Arc<AtomicBool>,
+    busy: Arc<AtomicBool>,
 }
 
 impl Drop for Connector {
     fn drop(&mut self) {
-        if let Ok(device) = self.device.lock() {
-            println!("Connector drop: id:{:?}", device.get_id());
-            self.running.store(false, Ordering::Relaxed);
-        }
+        let id = match self.device.lock() {
+            Ok(device) => device.get_id(),
+            Err(_) => return,
+        };
+        eprintln!("Connector drop: id:{:?}", id);
+        self.running.store(false, Ordering::Relaxed);
+        // 保存两次定时写入之间的历史数据
+        let (device_id, type_id) = id;
+        bms::history::flush_history(type_id, device_id);
+        metrics::disconnect_device(type_id, device_id);
+        clock::remove_device(type_id, device_id);
     }
 }
 
@@ -80,6 +87,7 @@ impl Connector {
             timeout: Arc::new(Mutex::new(Instant::now())),
             event_task: None,
             running,
+            busy: Arc::new(AtomicBool::new(false)),
         }
     }
 
@@ -87,8 +95,13 @@ impl Connector {
         self.running.load(Ordering::Relaxed)
     }
 
+    /// 设备被独占（如固件升级）期间不做超时判断
     pub fn check_timeout(&self) -> bool {
-        if let Ok(timeout) = self.timeout.lock() {
+        if let Ok(mut timeout) = self.timeout.lock() {
+            if self.busy.load(Ordering::Relaxed) {
+                *timeout = Instant::now();
+                return false;
+            }
             if timeout.elapsed().as_secs() > 10u64 {
                 return true;
             }
@@ -100,28 +113,45 @@ impl Connector {
         Arc::clone(&self.device)
     }
 
+    pub fn get_busy(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.busy)
+    }
+
     pub fn event_loop(&mut self, mut event: Event, ui: Weak<AppWindow>) {
-        println!("event_loop {:?}", Handle::try_current());
+        eprintln!("event_loop {:?}", Handle::try_current());
         let device = Arc::clone(&self.device);
         let event_running = Arc::clone(&self.running);
         let timeout = Arc::clone(&self.timeout);
+        let busy = Arc::clone(&self.busy);
 
         self.event_task = Some(tokio::task::spawn_blocking(move || -> () {
             let mut tmp_buf = [0; 1024];
-            let mut buf = vec![];
-            let mut index = 0usize;
+            let mut frames = FrameBuffer::new();
             let mut ping_timer = Instant::now();
+            // 未收到应答的ping发送时刻，用于统计往返时延
+            let mut ping_sent: Option<Instant> = None;
+            let mut sync_timer: Option<Instant> = None;
 
             tokio::spawn(async move {
                 while event_running.load(Ordering::Relaxed) {
+                    // 设备被独占时让出设备锁
+                    if busy.load(Ordering::Relaxed) {
+                        tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+                        continue;
+                    }
                     if let Ok(mut device) = device.lock() {
                         if ping_timer.elapsed().as_secs() > 3u64 {
                             ping_timer = Instant::now();
                             let ret = ping(&mut device);
-                            println!("ping {:?} -> {:?}", device.get_id(), ret);
+                            eprintln!("ping {:?} -> {:?}", device.get_id(), ret);
+                            ping_sent = ret.is_ok().then(Instant::now);
+                        }
+                        if sync_timer.is_none_or(|t| t.elapsed().as_secs() > 5u64) {
+                            sync_timer = Some(Instant::now());
+                            let _ = timesync::request(&mut device);
                         }
                         let _ = device
-                            .read(&mut tmp_buf[index..])
+                            .read(&mut tmp_buf)
                             .or_else(|e| {
                                 if let Some(err) = e.downcast_ref::<io::Error>() {
                                     match err.kind() {
@@ -134,42 +164,45 @@ impl Connector {
                                 Err(e)
                             })
                             .map(|size| {
-                                buf.append(&mut tmp_buf[..size].to_vec());
-                                index += size;
-
-                                loop {
-                                    if buf.len() < protocol::HEADER_SIZE {
-                                        break;
-                                    }
-                                    match ProtocolHeader::parse(&buf[..]) {
-                                        Err(_) => break,
-                                        Ok(header) => {
-                                            if buf.len()
-                                                < header.get_data_size() as usize
-                                                    + protocol::HEADER_SIZE
-                                            {
-                                                break;
+                                frames.push(&tmp_buf[..size]);
+                                // 无法识别指令的数据帧被跳过，由帧查看器显示
+                                while let Some((header, data)) = frames.next_frame() {
+                                    let data = &data[..];
+                                    match header.get_cmd_code() {
+                                        CmdCode::System(SystemCode::Pong) => {
+                                            eprintln!("pong {:?}", device.get_id());
+                                            if let Some(sent) = ping_sent.take() {
+                                                let (device_id, type_id) = device.get_id();
+                                                metrics::set_ping_rtt(
+                                                    type_id,
+                                                    device_id,
+                                                    sent.elapsed(),
+                                                );
+                                            }
+                                            if let Ok(mut timeout) = timeout.lock() {
+                                                *timeout = Instant::now();
                                             }
-                                            match header.get_cmd_code() {
-                                                CmdCode::System(SystemCode::Pong) => {
-                                                    println!("pong {:?}", device.get_id());
-                                                    if let Ok(mut timeout) = timeout.lock() {
-                                                        *timeout = Instant::now();
-                                                    }
-                                                }
-                                                _ => {
-                                                    event.call(
-                                                        header.get_cmd_code(),
-                                                        &mut device,
-                                                        Some(&buf[protocol::HEADER_SIZE..]),
-                                                        &ui,
+                                        }
+                                        CmdCode::System(SystemCode::TimeSync) => {
+                                            if let Ok(v) = TimeSyncResponse::parse(data) {
+                                                let (device_id, type_id) = device.get_id();
+                                                clock::with_clock(type_id, device_id, |clock| {
+                                                    clock.update(
+                                                        v.get_host_time(),
+                                                        v.get_device_tick(),
+                                                        timesync::host_time(),
                                                     );
-                                                }
+                                                    metrics::set_clock(type_id, device_id, clock);
+                                                });
                                             }
-                                            let protocol_size = header.get_data_size() as usize
-                                                + protocol::HEADER_SIZE;
-                                            buf.drain(0..protocol_size);
-                                            index -= protocol_size;
+                                        }
+                                        _ => {
+                                            event.call(
+                                                header.get_cmd_code(),
+                                                &mut device,
+                                                Some(data),
+                                                &ui,
+                                            );
                                         }
                                     }
                                 }
@@ -0,0 +1,307 @@
+use super::device::Device;
+use bincode::{
+    config::{self},
+    Decode, Encode,
+};
+use chrono::{Local, TimeZone};
+use lazy_static::lazy_static;
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::caw::protocols::timesync::host_time;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+const SESSION_MAGIC: [u8; 4] = *b"CAWS";
+const SESSION_VERSION: u8 = 1;
+pub const SESSION_HEADER_SIZE: usize = 13;
+pub const RECORD_HEADER_SIZE: usize = 13;
+/// 默认会话文件目录
+pub const DEFAULT_CAPTURE_DIR: &str = "sessions";
+
+lazy_static! {
+    /// 会话文件目录，为None时不抓取
+    static ref CAPTURE_DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+}
+
+#[derive(Debug, Clone)]
+pub enum SessionError {
+    Magic,
+    Version(u8),
+    Direction(u8),
+    Truncated,
+}
+
+impl std::fmt::Display for SessionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            SessionError::Magic => {
+                write!(f, "not a session file")
+            }
+            SessionError::Version(version) => {
+                write!(f, "unsupported session version: {}", version)
+            }
+            SessionError::Direction(direction) => {
+                write!(f, "invalid record direction: {}", direction)
+            }
+            SessionError::Truncated => {
+                write!(f, "session file truncated")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SessionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            SessionError::Magic => None,
+            SessionError::Version(_) => None,
+            SessionError::Direction(_) => None,
+            SessionError::Truncated => None,
+        }
+    }
+}
+
+/// 数据方向，相对于主机
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Rx = 0,
+    Tx,
+}
+
+impl TryFrom<u8> for Direction {
+    type Error = SessionError;
+
+    fn try_from(direction: u8) -> std::result::Result<Self, Self::Error> {
+        match direction {
+            0 => Ok(Direction::Rx),
+            1 => Ok(Direction::Tx),
+            _ => Err(SessionError::Direction(direction)),
+        }
+    }
+}
+
+/// 会话文件头
+#[derive(Encode, Decode, PartialEq, Debug)]
+pub struct SessionHeader {
+    magic: [u8; 4],
+    version: u8,
+    device_id: u32,
+    type_id: u32,
+}
+
+impl SessionHeader {
+    pub fn new(device_id: u32, type_id: u32) -> Self {
+        Self {
+            magic: SESSION_MAGIC,
+            version: SESSION_VERSION,
+            device_id,
+            type_id,
+        }
+    }
+
+    pub fn get_id(&self) -> (u32, u32) {
+        (self.device_id, self.type_id)
+    }
+}
+
+/// 会话记录头，其后为size字节的原始数据
+#[derive(Encode, Decode, PartialEq, Debug)]
+struct RecordHeader {
+    time: u64, // us
+    direction: u8,
+    size: u32,
+}
+
+/// 会话中的一次收发
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionRecord {
+    pub time: u64, // us
+    pub direction: Direction,
+    pub data: Vec<u8>,
+}
+
+impl SessionRecord {
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let config = config::standard()
+            .with_big_endian()
+            .with_fixed_int_encoding();
+        let mut buf = bincode::encode_to_vec(
+            RecordHeader {
+                time: self.time,
+                direction: self.direction as u8,
+                size: self.data.len() as u32,
+            },
+            config,
+        )?;
+        buf.extend_from_slice(&self.data);
+        Ok(buf)
+    }
+}
+
+impl SessionHeader {
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let config = config::standard()
+            .with_big_endian()
+            .with_fixed_int_encoding();
+        Ok(bincode::encode_to_vec(self, config)?)
+    }
+}
+
+/// 解析会话文件
+pub fn parse_session(buf: &[u8]) -> Result<(SessionHeader, Vec<SessionRecord>)> {
+    let config = config::standard()
+        .with_big_endian()
+        .with_fixed_int_encoding();
+    if buf.len() < SESSION_HEADER_SIZE {
+        return Err(SessionError::Truncated.into());
+    }
+    let (header, _): (SessionHeader, usize) =
+        bincode::decode_from_slice(&buf[..SESSION_HEADER_SIZE], config)?;
+    if header.magic != SESSION_MAGIC {
+        return Err(SessionError::Magic.into());
+    }
+    if header.version != SESSION_VERSION {
+        return Err(SessionError::Version(header.version).into());
+    }
+    let mut records = vec![];
+    let mut offset = SESSION_HEADER_SIZE;
+    while offset < buf.len() {
+        if buf.len() < offset + RECORD_HEADER_SIZE {
+            return Err(SessionError::Truncated.into());
+        }
+        let (record, _): (RecordHeader, usize) =
+            bincode::decode_from_slice(&buf[offset..offset + RECORD_HEADER_SIZE], config)?;
+        offset += RECORD_HEADER_SIZE;
+        let end = offset + record.size as usize;
+        if buf.len() < end {
+            return Err(SessionError::Truncated.into());
+        }
+        records.push(SessionRecord {
+            time: record.time,
+            direction: Direction::try_from(record.direction)?,
+            data: buf[offset..end].to_vec(),
+        });
+        offset = end;
+    }
+    Ok((header, records))
+}
+
+/// 开始或停止抓取，已连接的设备在下一次收发时生效
+pub fn set_capture_dir(dir: Option<PathBuf>) -> Result<()> {
+    if let Some(dir) = dir.as_ref() {
+        fs::create_dir_all(dir)?;
+    }
+    if let Ok(mut capture_dir) = CAPTURE_DIR.lock() {
+        *capture_dir = dir;
+    }
+    Ok(())
+}
+
+pub fn is_capturing() -> bool {
+    CAPTURE_DIR.lock().is_ok_and(|dir| dir.is_some())
+}
+
+/// 抓取设备收发的原始数据
+///
+/// 包装实际的设备，抓取开启时每台设备写入一个以时间命名的会话文件
+pub struct Capture {
+    device: Box<dyn Device + Send>,
+    dir: Option<PathBuf>,
+    writer: Option<BufWriter<File>>,
+}
+
+impl Capture {
+    pub fn new(device: Box<dyn Device + Send>) -> Self {
+        Self {
+            device,
+            dir: None,
+            writer: None,
+        }
+    }
+
+    fn open(&self, dir: &Path) -> Result<BufWriter<File>> {
+        let (device_id, type_id) = self.device.get_id();
+        let name = Local
+            .timestamp_micros(host_time() as i64)
+            .single()
+            .map(|t| t.format("%Y%m%d-%H%M%S").to_string())
+            .unwrap_or_default();
+        let path = dir.join(format!("session-{}-{}-{}.caws", type_id, device_id, name));
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(&SessionHeader::new(device_id, type_id).encode()?)?;
+        Ok(writer)
+    }
+
+    /// 与全局的抓取设置同步
+    fn sync_writer(&mut self) {
+        let dir = CAPTURE_DIR.lock().ok().and_then(|dir| dir.clone());
+        if dir == self.dir {
+            return;
+        }
+        self.writer = match dir.as_ref() {
+            Some(dir) => match self.open(dir) {
+                Ok(writer) => Some(writer),
+                Err(e) => {
+                    eprintln!("open session file failed: {}", e);
+                    None
+                }
+            },
+            None => None,
+        };
+        self.dir = dir;
+    }
+
+    fn capture(&mut self, direction: Direction, data: &[u8]) {
+        self.sync_writer();
+        let Some(writer) = self.writer.as_mut() else {
+            return;
+        };
+        let record = SessionRecord {
+            time: host_time(),
+            direction,
+            data: data.to_vec(),
+        };
+        let ret = record
+            .encode()
+            .and_then(|buf| Ok(writer.write_all(&buf).and_then(|_| writer.flush())?));
+        if let Err(e) = ret {
+            eprintln!("write session file failed: {}", e);
+            self.writer = None;
+        }
+    }
+}
+
+impl Device for Capture {
+    fn get_id(&self) -> (u32, u32) {
+        self.device.get_id()
+    }
+
+    fn set_id(&mut self, device_id: u32, type_id: u32) {
+        self.device.set_id(device_id, type_id)
+    }
+
+    fn write(&mut self, w_buf: &[u8]) -> Result<()> {
+        self.device.write(w_buf)?;
+        self.capture(Direction::Tx, w_buf);
+        Ok(())
+    }
+
+    fn read(&mut self, r_buf: &mut [u8]) -> Result<usize> {
+        let size = self.device.read(r_buf)?;
+        if size > 0 {
+            self.capture(Direction::Rx, &r_buf[..size]);
+        }
+        Ok(size)
+    }
+
+    fn read_exact(&mut self, r_buf: &mut [u8]) -> Result<()> {
+        self.device.read_exact(r_buf)?;
+        self.capture(Direction::Rx, r_buf);
+        Ok(())
+    }
+}
@@ -4,6 +4,7 @@ type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
 pub trait Device {
     fn get_id(&self) -> (u32, u32);
+    fn set_id(&mut self, device_id: u32, type_id: u32);
     fn write(&mut self, w_buf: &[u8]) -> Result<()>;
     fn read(&mut self, r_buf: &mut [u8]) -> Result<usize>;
     fn read_exact(&mut self, r_buf: &mut [u8]) -> Result<()>;
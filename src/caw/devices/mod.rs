@@ -1,2 +1,5 @@
+pub mod capture;
 pub mod device;
+pub mod monitor;
+pub mod replay;
 pub mod serial;
@@ -0,0 +1,72 @@
+use super::capture::Direction;
+use super::device::Device;
+use crate::caw::{composer, inspector, metrics, protocols::protocol::FrameBuffer};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// 监视设备收发的数据帧
+///
+/// 包装实际的设备，按方向切分数据帧后交给帧查看器并计入连接统计，
+/// 包括无法识别指令的数据帧，收到的帧同时转交给等待应答的指令编辑器
+pub struct Monitor {
+    device: Box<dyn Device + Send>,
+    rx: FrameBuffer,
+    tx: FrameBuffer,
+}
+
+impl Monitor {
+    pub fn new(device: Box<dyn Device + Send>) -> Self {
+        Self {
+            device,
+            rx: FrameBuffer::new(),
+            tx: FrameBuffer::new(),
+        }
+    }
+
+    fn monitor(&mut self, direction: Direction, data: &[u8]) {
+        let (device_id, type_id) = self.device.get_id();
+        let frames = match direction {
+            Direction::Rx => &mut self.rx,
+            Direction::Tx => &mut self.tx,
+        };
+        frames.push(data);
+        while let Some(frame) = frames.next_raw_frame() {
+            metrics::count_frame(type_id, device_id, direction, frame.check_checksum());
+            if direction == Direction::Rx {
+                composer::offer_response(type_id, device_id, &frame);
+            }
+            inspector::log(type_id, device_id, direction, frame);
+        }
+        metrics::count_resyncs(type_id, device_id, frames.take_resyncs());
+    }
+}
+
+impl Device for Monitor {
+    fn get_id(&self) -> (u32, u32) {
+        self.device.get_id()
+    }
+
+    fn set_id(&mut self, device_id: u32, type_id: u32) {
+        self.device.set_id(device_id, type_id)
+    }
+
+    fn write(&mut self, w_buf: &[u8]) -> Result<()> {
+        self.device.write(w_buf)?;
+        self.monitor(Direction::Tx, w_buf);
+        Ok(())
+    }
+
+    fn read(&mut self, r_buf: &mut [u8]) -> Result<usize> {
+        let size = self.device.read(r_buf)?;
+        if size > 0 {
+            self.monitor(Direction::Rx, &r_buf[..size]);
+        }
+        Ok(size)
+    }
+
+    fn read_exact(&mut self, r_buf: &mut [u8]) -> Result<()> {
+        self.device.read_exact(r_buf)?;
+        self.monitor(Direction::Rx, r_buf);
+        Ok(())
+    }
+}
@@ -0,0 +1,152 @@
+use super::capture::{self, Direction, SessionRecord};
+use super::device::Device;
+use std::{
+    collections::VecDeque,
+    fs, io,
+    time::{Duration, Instant},
+};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// 无数据时单次读取的最长等待时间，与串口的读超时一致
+const READ_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// 回放会话文件
+///
+/// 按记录的时间间隔依次返回接收到的数据，speed大于1时加速回放，
+/// 写入的数据直接丢弃，回放结束后读取返回UnexpectedEof
+pub struct Replay {
+    device_id: u32,
+    type_id: u32,
+    records: VecDeque<SessionRecord>,
+    /// 当前记录中已读取的字节数
+    offset: usize,
+    start_time: u64,
+    start: Option<Instant>,
+    speed: f32,
+}
+
+impl Replay {
+    pub fn open(path: &str, speed: f32) -> Result<Self> {
+        let buf = fs::read(path)?;
+        let (header, records) = capture::parse_session(&buf)?;
+        let (device_id, type_id) = header.get_id();
+        let records: VecDeque<SessionRecord> = records
+            .into_iter()
+            .filter(|r| r.direction == Direction::Rx)
+            .collect();
+        Ok(Self {
+            device_id,
+            type_id,
+            start_time: records.front().map_or(0, |r| r.time),
+            records,
+            offset: 0,
+            start: None,
+            speed: if speed > 0.0 { speed } else { 1.0 },
+        })
+    }
+
+    /// 距下一条记录到期的时间，已到期时返回None
+    fn wait_time(&mut self, record_time: u64) -> Option<Duration> {
+        let start = *self.start.get_or_insert_with(Instant::now);
+        let due = Duration::from_micros(
+            (record_time.saturating_sub(self.start_time) as f64 / self.speed as f64) as u64,
+        );
+        due.checked_sub(start.elapsed()).filter(|d| !d.is_zero())
+    }
+}
+
+impl Device for Replay {
+    fn get_id(&self) -> (u32, u32) {
+        (self.device_id, self.type_id)
+    }
+
+    fn set_id(&mut self, device_id: u32, type_id: u32) {
+        self.device_id = device_id;
+        self.type_id = type_id;
+    }
+
+    fn write(&mut self, _w_buf: &[u8]) -> Result<()> {
+        Ok(())
+    }
+
+    fn read(&mut self, r_buf: &mut [u8]) -> Result<usize> {
+        let Some(time) = self.records.front().map(|r| r.time) else {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        };
+        if let Some(wait) = self.wait_time(time) {
+            std::thread::sleep(wait.min(READ_TIMEOUT));
+            if wait > READ_TIMEOUT {
+                return Err(io::Error::from(io::ErrorKind::TimedOut).into());
+            }
+        }
+        let record = &self.records[0];
+        let size = r_buf.len().min(record.data.len() - self.offset);
+        r_buf[..size].copy_from_slice(&record.data[self.offset..self.offset + size]);
+        self.offset += size;
+        if self.offset >= record.data.len() {
+            self.records.pop_front();
+            self.offset = 0;
+        }
+        Ok(size)
+    }
+
+    fn read_exact(&mut self, r_buf: &mut [u8]) -> Result<()> {
+        let mut index = 0;
+        while index < r_buf.len() {
+            match self.read(&mut r_buf[index..]) {
+                Ok(size) => index += size,
+                Err(e) => match e.downcast_ref::<io::Error>() {
+                    Some(err) if err.kind() == io::ErrorKind::TimedOut => (),
+                    _ => return Err(e),
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::caw::devices::capture::SessionHeader;
+
+    #[test]
+    fn replay_test() {
+        let mut buf = SessionHeader::new(3, 2).encode().unwrap();
+        let records = [
+            (1_000_000, Direction::Rx, vec![1, 2, 3]),
+            (1_010_000, Direction::Tx, vec![9]),
+            (1_020_000, Direction::Rx, vec![4, 5]),
+        ];
+        for (time, direction, data) in records {
+            let record = SessionRecord {
+                time,
+                direction,
+                data,
+            };
+            buf.append(&mut record.encode().unwrap());
+        }
+        let (header, parsed) = capture::parse_session(&buf).unwrap();
+        assert_eq!(header.get_id(), (3, 2));
+        assert_eq!(parsed.len(), 3);
+        assert!(capture::parse_session(&buf[..buf.len() - 1]).is_err());
+
+        let path = std::env::temp_dir().join(format!("caw-replay-{}.caws", std::process::id()));
+        fs::write(&path, &buf).unwrap();
+        let mut replay = Replay::open(path.to_str().unwrap(), 10.0).unwrap();
+        let _ = fs::remove_file(path);
+        assert_eq!(replay.get_id(), (3, 2));
+        let mut r_buf = [0u8; 2];
+        assert_eq!(replay.read(&mut r_buf).unwrap(), 2);
+        assert_eq!(r_buf, [1, 2]);
+        let mut r_buf = [0u8; 3];
+        replay.read_exact(&mut r_buf).unwrap();
+        assert_eq!(r_buf, [3, 4, 5]);
+        let e = replay.read(&mut r_buf).unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<io::Error>().unwrap().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+}
@@ -71,11 +71,6 @@ impl Serial {
         })
     }
 
-    pub fn set_id(&mut self, device_id: u32, type_id: u32) {
-        self.device_id = device_id;
-        self.type_id = type_id;
-    }
-
     /// 搜索特定的串口设备
     ///
     /// 遍历串口设备，发送特定的数据并接口返回数据，通过返回的数据来匹配特定设备
@@ -89,19 +84,25 @@ impl Serial {
                 SerialPortType::UsbPort(_) => (),
                 _ => continue,
             }
-            if let Ok(_) = Serial::new(&port.port_name[..], baud_rate).and_then(|mut serial| {
-                let mut r_buf = [0u8; 12];
-                serial
-                    .write(w_buf)
-                    .and_then(|_| serial.read_exact(&mut r_buf[..]))
-                    .and_then(|_| f(serial, &r_buf[..], ui))
-            }) {
+            let mut r_buf = [0u8; 12];
+            if Serial::probe(&port.port_name[..], baud_rate, w_buf, &mut r_buf)
+                .and_then(|serial| f(serial, &r_buf[..], ui))
+                .is_ok()
+            {
                 return Ok(());
             }
         }
         Err(SerialError::DeviceNotExist.into())
     }
 
+    /// 打开串口并发送数据，读满r_buf后返回串口
+    pub fn probe(path: &str, baud_rate: u32, w_buf: &[u8], r_buf: &mut [u8]) -> Result<Self> {
+        let mut serial = Serial::new(path, baud_rate)?;
+        serial.write(w_buf)?;
+        serial.read_exact(r_buf)?;
+        Ok(serial)
+    }
+
     pub fn ports() -> Result<Vec<SerialPortInfo>> {
         Ok(serialport::available_ports()?)
     }
@@ -111,6 +112,12 @@ impl Device for Serial {
     fn get_id(&self) -> (u32, u32) {
         (self.device_id, self.type_id)
     }
+
+    fn set_id(&mut self, device_id: u32, type_id: u32) {
+        self.device_id = device_id;
+        self.type_id = type_id;
+    }
+
     fn read(&mut self, r_buf: &mut [u8]) -> Result<usize> {
         Ok(self.driver.read(r_buf)?)
     }
@@ -0,0 +1,429 @@
+use std::{fs, path::Path, time::Duration};
+
+use crate::caw::{
+    devices::device::Device,
+    protocols::{
+        ack::{self, AckStatus},
+        code::{CmdCode, SystemCode},
+        firmware::{self, FirmwareInfo},
+        protocol::FrameBuffer,
+        system,
+    },
+};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+const DEFAULT_CHUNK_SIZE: usize = 256;
+const DEFAULT_RETRIES: usize = 3;
+const DEFAULT_TIMEOUT: Duration = Duration::from_millis(1000);
+
+#[derive(Debug, Clone)]
+pub enum FirmwareError {
+    EmptyImage,
+    Rejected(CmdCode, AckStatus),
+    RetryExhausted(CmdCode, u32),
+    ChecksumMismatch,
+}
+
+impl std::fmt::Display for FirmwareError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FirmwareError::EmptyImage => {
+                write!(f, "empty image")
+            }
+            FirmwareError::Rejected(code, status) => {
+                write!(f, "{:?} rejected: {:?}", code, status)
+            }
+            FirmwareError::RetryExhausted(code, seq) => {
+                write!(f, "{:?} seq:{} retry exhausted", code, seq)
+            }
+            FirmwareError::ChecksumMismatch => {
+                write!(f, "image checksum mismatch")
+            }
+        }
+    }
+}
+
+impl std::error::Error for FirmwareError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        None
+    }
+}
+
+/// 固件升级阶段
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FirmwareState {
+    Bootloader,
+    Writing,
+    Verifying,
+    Rebooting,
+    Done,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FirmwareProgress {
+    pub state: FirmwareState,
+    pub sent: usize,
+    pub total: usize,
+    /// 累计重发次数，应答超时或分块校验失败时增加
+    pub retries: usize,
+}
+
+impl FirmwareProgress {
+    pub fn percent(&self) -> f32 {
+        if self.total == 0 {
+            return 0.0;
+        }
+        self.sent as f32 / self.total as f32
+    }
+}
+
+/// 固件升级器
+///
+/// 进入Bootloader后按分块发送固件镜像，每个分块等待设备应答，失败时重发，
+/// 全部发送完成后由设备按CRC32校验和校验镜像并重启设备。
+/// CRC32只用于发现传输错误，不能防止镜像被篡改
+pub struct FirmwareUpdater {
+    image: Vec<u8>,
+    chunk_size: usize,
+    retries: usize,
+    timeout: Duration,
+}
+
+impl FirmwareUpdater {
+    pub fn new(image: Vec<u8>) -> Self {
+        Self {
+            image,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            retries: DEFAULT_RETRIES,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// 从文件读取固件镜像
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self::new(fs::read(path)?))
+    }
+
+    /// 设置分块大小
+    pub fn set_chunk_size(mut self, size: usize) -> Self {
+        self.chunk_size = size.max(1);
+        self
+    }
+
+    /// 设置单个请求的重试次数
+    pub fn set_retries(mut self, retries: usize) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// 设置等待应答的超时时间
+    pub fn set_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn run<F>(&self, device: &mut Box<dyn Device + Send>, mut progress: F) -> Result<()>
+    where
+        F: FnMut(FirmwareProgress),
+    {
+        if self.image.is_empty() {
+            return Err(FirmwareError::EmptyImage.into());
+        }
+        let total = self.image.len();
+        let info = FirmwareInfo::new(&self.image[..], self.chunk_size as u32);
+        let mut frames = FrameBuffer::new();
+        let mut report = |state, sent, retries| {
+            progress(FirmwareProgress {
+                state,
+                sent,
+                total,
+                retries,
+            });
+        };
+
+        report(FirmwareState::Bootloader, 0, 0);
+        let mut retries = self.request(
+            device,
+            &mut frames,
+            CmdCode::System(SystemCode::Bootloader),
+            0,
+            |device| firmware::enter_bootloader(device, &info),
+        )?;
+
+        let mut sent = 0usize;
+        for (seq, chunk) in self.image.chunks(self.chunk_size).enumerate() {
+            retries += self.request(
+                device,
+                &mut frames,
+                CmdCode::System(SystemCode::FirmwareChunk),
+                seq as u32,
+                |device| firmware::write_chunk(device, seq as u32, chunk),
+            )?;
+            sent += chunk.len();
+            report(FirmwareState::Writing, sent, retries);
+        }
+
+        report(FirmwareState::Verifying, sent, retries);
+        retries += self
+            .request(
+                device,
+                &mut frames,
+                CmdCode::System(SystemCode::FirmwareVerify),
+                0,
+                |device| firmware::verify(device, &info),
+            )
+            .map_err(|e| match e.downcast_ref::<FirmwareError>() {
+                Some(FirmwareError::Rejected(_, AckStatus::CrcError)) => {
+                    FirmwareError::ChecksumMismatch.into()
+                }
+                _ => e,
+            })?;
+
+        report(FirmwareState::Rebooting, sent, retries);
+        retries += self.request(
+            device,
+            &mut frames,
+            CmdCode::System(SystemCode::Reboot),
+            0,
+            system::reboot,
+        )?;
+
+        report(FirmwareState::Done, sent, retries);
+        Ok(())
+    }
+
+    /// 发送请求并等待应答，超时或分块校验失败时重发，返回重发次数
+    fn request<F>(
+        &self,
+        device: &mut Box<dyn Device + Send>,
+        frames: &mut FrameBuffer,
+        code: CmdCode,
+        seq: u32,
+        send: F,
+    ) -> Result<usize>
+    where
+        F: Fn(&mut Box<dyn Device + Send>) -> Result<()>,
+    {
+        for retry in 0..=self.retries {
+            send(device)?;
+            // 应答超时或无法解析时重发
+            if let Some(ack) = ack::wait_ack(device, frames, code, seq, self.timeout)? {
+                match ack.get_status() {
+                    AckStatus::Ok => return Ok(retry),
+                    // 分块校验失败时重发
+                    AckStatus::CrcError if code != CmdCode::System(SystemCode::FirmwareVerify) => {}
+                    status => return Err(FirmwareError::Rejected(code, status).into()),
+                }
+            }
+        }
+        Err(FirmwareError::RetryExhausted(code, seq).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::caw::protocols::{
+        ack::{Ack, ACK_SIZE},
+        firmware::FirmwareChunkHeader,
+        protocol::ProtocolHeader,
+    };
+    use crate::caw::utils::crypto::crc32_slice;
+    use std::{
+        collections::VecDeque,
+        sync::{Arc, Mutex},
+    };
+
+    /// 模拟Bootloader，收到指令后将应答写入接收缓冲区
+    struct MockBootloader {
+        rx: VecDeque<u8>,
+        frames: FrameBuffer,
+        info: Option<FirmwareInfo>,
+        image: Arc<Mutex<Vec<u8>>>,
+        rebooted: Arc<Mutex<bool>>,
+        /// 首次收到该序号的分块时回复CRC错误
+        corrupt_seq: Option<u32>,
+        /// 丢弃该序号分块的首次应答
+        drop_seq: Option<u32>,
+        /// 该序号分块的首次应答被截断
+        truncate_seq: Option<u32>,
+    }
+
+    impl MockBootloader {
+        fn new(image: Arc<Mutex<Vec<u8>>>, rebooted: Arc<Mutex<bool>>) -> Self {
+            Self {
+                rx: VecDeque::new(),
+                frames: FrameBuffer::new(),
+                info: None,
+                image,
+                rebooted,
+                corrupt_seq: None,
+                drop_seq: None,
+                truncate_seq: None,
+            }
+        }
+
+        fn ack(&mut self, code: SystemCode, status: AckStatus, seq: u32) {
+            let data = Ack::new(CmdCode::System(code), status, seq)
+                .encode()
+                .unwrap();
+            let frame = ProtocolHeader::pack(CmdCode::System(SystemCode::Ack), &data[..]).unwrap();
+            self.rx.extend(frame);
+        }
+
+        fn handle(&mut self, header: ProtocolHeader, data: Vec<u8>) {
+            let code = match header.get_cmd_code() {
+                CmdCode::System(code) => code,
+                _ => return,
+            };
+            match code {
+                SystemCode::Bootloader => {
+                    self.info = Some(FirmwareInfo::parse(&data[..]).unwrap());
+                    self.image.lock().unwrap().clear();
+                    self.ack(code, AckStatus::Ok, 0);
+                }
+                SystemCode::FirmwareChunk => {
+                    let chunk = FirmwareChunkHeader::parse(&data[..]).unwrap();
+                    let seq = chunk.get_seq();
+                    if self.drop_seq.take_if(|s| *s == seq).is_some() {
+                        return;
+                    }
+                    if self.corrupt_seq.take_if(|s| *s == seq).is_some() || !chunk.check(&data[8..])
+                    {
+                        self.ack(code, AckStatus::CrcError, seq);
+                        return;
+                    }
+                    let chunk_size = self.info.unwrap().get_chunk_size() as usize;
+                    let mut image = self.image.lock().unwrap();
+                    if image.len() == seq as usize * chunk_size {
+                        image.extend_from_slice(&data[8..]);
+                    }
+                    drop(image);
+                    if self.truncate_seq.take_if(|s| *s == seq).is_some() {
+                        let data = Ack::new(CmdCode::System(code), AckStatus::Ok, seq)
+                            .encode()
+                            .unwrap();
+                        let frame = ProtocolHeader::pack(
+                            CmdCode::System(SystemCode::Ack),
+                            &data[..ACK_SIZE - 4],
+                        )
+                        .unwrap();
+                        self.rx.extend(frame);
+                        return;
+                    }
+                    self.ack(code, AckStatus::Ok, seq);
+                }
+                SystemCode::FirmwareVerify => {
+                    let info = FirmwareInfo::parse(&data[..]).unwrap();
+                    let image = self.image.lock().unwrap().clone();
+                    let status = if image.len() == info.get_size() as usize
+                        && crc32_slice(&image[..]) == info.get_crc()
+                    {
+                        AckStatus::Ok
+                    } else {
+                        AckStatus::CrcError
+                    };
+                    self.ack(code, status, 0);
+                }
+                SystemCode::Reboot => {
+                    *self.rebooted.lock().unwrap() = true;
+                    self.ack(code, AckStatus::Ok, 0);
+                }
+                _ => (),
+            }
+        }
+    }
+
+    impl Device for MockBootloader {
+        fn get_id(&self) -> (u32, u32) {
+            (0, 0)
+        }
+
+        fn set_id(&mut self, _device_id: u32, _type_id: u32) {}
+
+        fn write(&mut self, w_buf: &[u8]) -> Result<()> {
+            self.frames.push(w_buf);
+            while let Some((header, data)) = self.frames.next_frame() {
+                self.handle(header, data);
+            }
+            Ok(())
+        }
+
+        fn read(&mut self, r_buf: &mut [u8]) -> Result<usize> {
+            let size = r_buf.len().min(self.rx.len());
+            for (i, b) in self.rx.drain(..size).enumerate() {
+                r_buf[i] = b;
+            }
+            Ok(size)
+        }
+
+        fn read_exact(&mut self, r_buf: &mut [u8]) -> Result<()> {
+            self.read(r_buf).map(|_| ())
+        }
+    }
+
+    fn image() -> Vec<u8> {
+        (0..1000).map(|x| (x % 251) as u8).collect()
+    }
+
+    #[test]
+    fn firmware_update_test() {
+        let written = Arc::new(Mutex::new(vec![]));
+        let rebooted = Arc::new(Mutex::new(false));
+        let mut mock = MockBootloader::new(Arc::clone(&written), Arc::clone(&rebooted));
+        mock.corrupt_seq = Some(1);
+        mock.drop_seq = Some(2);
+        mock.truncate_seq = Some(4);
+        let mut device: Box<dyn Device + Send> = Box::new(mock);
+
+        let mut states = vec![];
+        FirmwareUpdater::new(image())
+            .set_chunk_size(128)
+            .set_timeout(Duration::from_millis(20))
+            .run(&mut device, |p| states.push(p))
+            .unwrap();
+
+        assert_eq!(*written.lock().unwrap(), image());
+        assert!(*rebooted.lock().unwrap());
+        assert_eq!(states.first().unwrap().state, FirmwareState::Bootloader);
+        assert_eq!(states.last().unwrap().state, FirmwareState::Done);
+        assert_eq!(states.last().unwrap().percent(), 1.0);
+        // 分块1校验失败、分块2应答丢失、分块4应答被截断，各重发一次
+        assert_eq!(states.last().unwrap().retries, 3);
+    }
+
+    #[test]
+    fn firmware_no_retry_test() {
+        let written = Arc::new(Mutex::new(vec![]));
+        let rebooted = Arc::new(Mutex::new(false));
+        let mock = MockBootloader::new(Arc::clone(&written), Arc::clone(&rebooted));
+        let mut device: Box<dyn Device + Send> = Box::new(mock);
+
+        FirmwareUpdater::new(image())
+            .set_chunk_size(128)
+            .set_retries(0)
+            .set_timeout(Duration::from_millis(20))
+            .run(&mut device, |_| ())
+            .unwrap();
+        assert_eq!(*written.lock().unwrap(), image());
+    }
+
+    #[test]
+    fn firmware_retry_exhausted_test() {
+        let written = Arc::new(Mutex::new(vec![]));
+        let rebooted = Arc::new(Mutex::new(false));
+        let mut mock = MockBootloader::new(Arc::clone(&written), Arc::clone(&rebooted));
+        mock.drop_seq = Some(3);
+        let mut device: Box<dyn Device + Send> = Box::new(mock);
+        let ret = FirmwareUpdater::new(image())
+            .set_chunk_size(128)
+            .set_retries(0)
+            .set_timeout(Duration::from_millis(20))
+            .run(&mut device, |_| ());
+        match ret.unwrap_err().downcast_ref::<FirmwareError>() {
+            Some(FirmwareError::RetryExhausted(_, 3)) => (),
+            e => panic!("unexpected error: {:?}", e),
+        }
+    }
+}
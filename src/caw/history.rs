@@ -0,0 +1,269 @@
+use bincode::{config, Decode, Encode};
+use lazy_static::lazy_static;
+use redb::{Database, ReadableTable, TableDefinition};
+use std::path::{Path, PathBuf};
+
+/// 本地数据库文件名，保存电池包历史与设置
+const HISTORY_DB_FILE: &str = "history.redb";
+/// 用户数据目录下的应用目录
+const APP_DIR: &str = "caw-link";
+/// 以device_id为键，值为bincode编码的PackHistory
+const PACK_TABLE: TableDefinition<u32, &[u8]> = TableDefinition::new("pack");
+/// 以名称为键的设置项
+const SETTING_TABLE: TableDefinition<&str, &[u8]> = TableDefinition::new("setting");
+/// 记录的故障标志位数，与BMS状态位一致
+pub const FAULT_COUNT: usize = 8;
+
+lazy_static! {
+    /// 打开失败(如被其他实例占用)时不记录历史
+    static ref HISTORY_DB: Option<HistoryDb> = match HistoryDb::open(&db_path()) {
+        Ok(db) => Some(db),
+        Err(e) => {
+            eprintln!("open history db failed: {}", e);
+            None
+        }
+    };
+}
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// 数据库位于用户数据目录，与启动时的工作目录无关，无法获取时使用当前目录
+fn db_path() -> PathBuf {
+    match dirs::data_dir().map(|dir| dir.join(APP_DIR)) {
+        Some(dir) if std::fs::create_dir_all(&dir).is_ok() => dir.join(HISTORY_DB_FILE),
+        _ => PathBuf::from(HISTORY_DB_FILE),
+    }
+}
+
+/// 电池包历史记录
+#[derive(Encode, Decode, PartialEq, Debug, Clone, Default)]
+pub struct PackHistory {
+    pub first_seen: u64, // us
+    pub last_seen: u64,  // us
+    pub charge_cycles: u32,
+    pub discharge_cycles: u32,
+    pub equivalent_cycles: f64,
+    pub charged_wh: f64,
+    pub discharged_wh: f64,
+    pub max_temperature: i32, // x100
+    pub fault_counts: [u32; FAULT_COUNT],
+}
+
+impl PackHistory {
+    pub fn new(time: u64) -> Self {
+        Self {
+            first_seen: time,
+            last_seen: time,
+            max_temperature: i32::MIN,
+            ..Default::default()
+        }
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        let config = config::standard()
+            .with_big_endian()
+            .with_fixed_int_encoding();
+        let (history, _): (PackHistory, usize) = bincode::decode_from_slice(buf, config)?;
+        Ok(history)
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let config = config::standard()
+            .with_big_endian()
+            .with_fixed_int_encoding();
+        Ok(bincode::encode_to_vec(self, config)?)
+    }
+
+    /// 合并另一段时间内的记录，计数累加，时间与最高温度取范围
+    pub fn merge(&mut self, other: &PackHistory) {
+        self.first_seen = self.first_seen.min(other.first_seen);
+        self.last_seen = self.last_seen.max(other.last_seen);
+        self.charge_cycles += other.charge_cycles;
+        self.discharge_cycles += other.discharge_cycles;
+        self.equivalent_cycles += other.equivalent_cycles;
+        self.charged_wh += other.charged_wh;
+        self.discharged_wh += other.discharged_wh;
+        self.max_temperature = self.max_temperature.max(other.max_temperature);
+        for (count, other) in self.fault_counts.iter_mut().zip(other.fault_counts.iter()) {
+            *count += other;
+        }
+    }
+
+    pub fn total_faults(&self) -> u32 {
+        self.fault_counts.iter().sum()
+    }
+}
+
+/// 电池包历史数据库
+pub struct HistoryDb {
+    db: Database,
+}
+
+impl HistoryDb {
+    pub fn open(path: &Path) -> Result<Self> {
+        let db = Database::create(path)?;
+        // 提前建表，避免只读事务打开不存在的表
+        let tx = db.begin_write()?;
+        tx.open_table(PACK_TABLE)?;
+        tx.open_table(SETTING_TABLE)?;
+        tx.commit()?;
+        Ok(Self { db })
+    }
+
+    pub fn load(&self, device_id: u32) -> Result<Option<PackHistory>> {
+        let tx = self.db.begin_read()?;
+        let table = tx.open_table(PACK_TABLE)?;
+        match table.get(device_id)? {
+            Some(value) => Ok(Some(PackHistory::decode(value.value())?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn save(&self, device_id: u32, history: &PackHistory) -> Result<()> {
+        let buf = history.encode()?;
+        let tx = self.db.begin_write()?;
+        {
+            let mut table = tx.open_table(PACK_TABLE)?;
+            table.insert(device_id, buf.as_slice())?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// 设备编号变更后迁移历史记录，覆盖新编号原有的记录
+    pub fn rekey(&self, device_id: u32, new_device_id: u32) -> Result<()> {
+        let tx = self.db.begin_write()?;
+        {
+            let mut table = tx.open_table(PACK_TABLE)?;
+            let value = table.remove(device_id)?.map(|v| v.value().to_vec());
+            if let Some(value) = value {
+                table.insert(new_device_id, value.as_slice())?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// 所有电池包的历史记录，按device_id排序
+    pub fn list(&self) -> Result<Vec<(u32, PackHistory)>> {
+        let tx = self.db.begin_read()?;
+        let table = tx.open_table(PACK_TABLE)?;
+        let mut packs = vec![];
+        for entry in table.iter()? {
+            let (key, value) = entry?;
+            packs.push((key.value(), PackHistory::decode(value.value())?));
+        }
+        Ok(packs)
+    }
+
+    pub fn load_setting(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let tx = self.db.begin_read()?;
+        let table = tx.open_table(SETTING_TABLE)?;
+        Ok(table.get(key)?.map(|value| value.value().to_vec()))
+    }
+
+    pub fn save_setting(&self, key: &str, value: &[u8]) -> Result<()> {
+        let tx = self.db.begin_write()?;
+        {
+            let mut table = tx.open_table(SETTING_TABLE)?;
+            table.insert(key, value)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+/// 读取电池包历史，没有记录时返回None
+pub fn load(device_id: u32) -> Option<PackHistory> {
+    HISTORY_DB
+        .as_ref()
+        .and_then(|db| db.load(device_id).unwrap_or_default())
+}
+
+pub fn save(device_id: u32, history: &PackHistory) {
+    if let Some(db) = HISTORY_DB.as_ref() {
+        if let Err(e) = db.save(device_id, history) {
+            eprintln!("save history failed: {}", e);
+        }
+    }
+}
+
+pub fn rekey(device_id: u32, new_device_id: u32) {
+    if let Some(db) = HISTORY_DB.as_ref() {
+        if let Err(e) = db.rekey(device_id, new_device_id) {
+            eprintln!("rekey history failed: {}", e);
+        }
+    }
+}
+
+pub fn list() -> Vec<(u32, PackHistory)> {
+    HISTORY_DB
+        .as_ref()
+        .and_then(|db| db.list().ok())
+        .unwrap_or_default()
+}
+
+/// 读取设置项，没有记录时返回None
+pub fn load_setting(key: &str) -> Option<Vec<u8>> {
+    HISTORY_DB
+        .as_ref()
+        .and_then(|db| db.load_setting(key).unwrap_or_default())
+}
+
+pub fn save_setting(key: &str, value: &[u8]) {
+    if let Some(db) = HISTORY_DB.as_ref() {
+        if let Err(e) = db.save_setting(key, value) {
+            eprintln!("save setting {} failed: {}", key, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn history_db_test() {
+        let path = std::env::temp_dir().join(format!("caw-history-{}.redb", std::process::id()));
+        let db = HistoryDb::open(&path).unwrap();
+        assert_eq!(db.load(1).unwrap(), None);
+        let mut history = PackHistory::new(1_000);
+        history.charge_cycles = 3;
+        history.equivalent_cycles = 2.5;
+        history.fault_counts[2] = 4;
+        db.save(2, &history).unwrap();
+        db.save(1, &PackHistory::new(2_000)).unwrap();
+        assert_eq!(db.load(2).unwrap(), Some(history.clone()));
+        let packs = db.list().unwrap();
+        assert_eq!(packs.len(), 2);
+        assert_eq!(packs[0].0, 1);
+        assert_eq!(packs[1], (2, history.clone()));
+        db.rekey(2, 5).unwrap();
+        assert_eq!(db.load(2).unwrap(), None);
+        assert_eq!(db.load(5).unwrap(), Some(history));
+        assert_eq!(db.load_setting("test").unwrap(), None);
+        db.save_setting("test", &[1, 2, 3]).unwrap();
+        assert_eq!(db.load_setting("test").unwrap(), Some(vec![1, 2, 3]));
+        drop(db);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn pack_history_merge_test() {
+        let mut stored = PackHistory::new(1_000);
+        stored.last_seen = 5_000;
+        stored.charge_cycles = 3;
+        stored.max_temperature = 3000;
+        stored.fault_counts[1] = 2;
+        let mut recent = PackHistory::new(9_000);
+        recent.charge_cycles = 1;
+        recent.charged_wh = 12.5;
+        recent.fault_counts[1] = 1;
+        stored.merge(&recent);
+        assert_eq!((stored.first_seen, stored.last_seen), (1_000, 9_000));
+        assert_eq!(stored.charge_cycles, 4);
+        assert_eq!(stored.charged_wh, 12.5);
+        assert_eq!(stored.max_temperature, 3000);
+        assert_eq!(stored.fault_counts[1], 3);
+    }
+}
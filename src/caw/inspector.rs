@@ -0,0 +1,338 @@
+use crate::ui::*;
+
+use chrono::{Local, TimeZone};
+use lazy_static::lazy_static;
+use slint::{ComponentHandle, VecModel};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+};
+
+use crate::caw::{
+    devices::capture::Direction,
+    protocols::{code::CmdCode, protocol::RawFrame, timesync::host_time},
+};
+
+/// 每台设备保留的最大帧数
+const MAX_FRAMES: usize = 500;
+/// 列表中预览的数据体字节数
+const PREVIEW_SIZE: usize = 16;
+
+lazy_static! {
+    static ref INSPECTOR: Mutex<Inspector> = Mutex::new(Inspector::new());
+}
+
+/// 连接上的一个数据帧
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameRecord {
+    /// 递增的帧序号
+    pub index: u64,
+    pub time: u64, // us
+    pub direction: Direction,
+    pub cmd_code: Option<CmdCode>,
+    pub raw_code: (u32, u32),
+    pub size: usize,
+    pub crc_ok: bool,
+    pub data: Vec<u8>,
+}
+
+impl FrameRecord {
+    pub fn new(index: u64, direction: Direction, frame: RawFrame) -> Self {
+        Self {
+            index,
+            time: host_time(),
+            direction,
+            cmd_code: frame.get_cmd_code(),
+            raw_code: frame.raw_code,
+            size: frame.size(),
+            crc_ok: frame.check_checksum(),
+            data: frame.data,
+        }
+    }
+
+    /// 指令名称，无法识别时显示原始编号
+    pub fn command(&self) -> String {
+        match self.cmd_code {
+            Some(cmd_code) => format!("{:?}", cmd_code),
+            None => format!("Unknown({:#x}:{:#x})", self.raw_code.0, self.raw_code.1),
+        }
+    }
+
+    /// 按逗号分隔的关键字过滤指令名称，不区分大小写，为空时全部匹配
+    pub fn matches(&self, filter: &str) -> bool {
+        let command = self.command().to_lowercase();
+        let mut terms = filter
+            .split(',')
+            .map(|t| t.trim().to_lowercase())
+            .filter(|t| !t.is_empty())
+            .peekable();
+        terms.peek().is_none() || terms.any(|t| command.contains(&t))
+    }
+}
+
+/// 帧查看器，按设备保存最近的收发数据帧
+pub struct Inspector {
+    frames: HashMap<(u32, u32), VecDeque<FrameRecord>>,
+    next_index: u64,
+    paused: bool,
+    filter: String,
+    selected: Option<u64>,
+    /// 上次显示时的帧序号与设备，未变化时不刷新列表
+    shown: Option<(u64, u32, u32)>,
+}
+
+impl Inspector {
+    pub fn new() -> Self {
+        Self {
+            frames: HashMap::new(),
+            next_index: 0,
+            paused: false,
+            filter: String::new(),
+            selected: None,
+            shown: None,
+        }
+    }
+
+    pub fn log(&mut self, type_id: u32, device_id: u32, direction: Direction, frame: RawFrame) {
+        if self.paused {
+            return;
+        }
+        let frames = self.frames.entry((type_id, device_id)).or_default();
+        if frames.len() >= MAX_FRAMES {
+            frames.pop_front();
+        }
+        frames.push_back(FrameRecord::new(self.next_index, direction, frame));
+        self.next_index += 1;
+    }
+
+    /// 符合过滤条件的数据帧，按时间先后排列
+    pub fn get_frames(&self, type_id: u32, device_id: u32) -> Vec<&FrameRecord> {
+        self.frames
+            .get(&(type_id, device_id))
+            .map(|frames| frames.iter().filter(|f| f.matches(&self.filter)).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn find(&self, type_id: u32, device_id: u32, index: u64) -> Option<&FrameRecord> {
+        self.frames
+            .get(&(type_id, device_id))
+            .and_then(|frames| frames.iter().find(|f| f.index == index))
+    }
+
+    pub fn clear(&mut self, type_id: u32, device_id: u32) {
+        self.frames.remove(&(type_id, device_id));
+        self.selected = None;
+    }
+
+    pub fn rekey(&mut self, type_id: u32, device_id: u32, new_device_id: u32) {
+        if let Some(frames) = self.frames.remove(&(type_id, device_id)) {
+            self.frames.insert((type_id, new_device_id), frames);
+        }
+    }
+}
+
+/// 记录一个数据帧
+pub fn log(type_id: u32, device_id: u32, direction: Direction, frame: RawFrame) {
+    if let Ok(mut inspector) = INSPECTOR.lock() {
+        inspector.log(type_id, device_id, direction, frame);
+    }
+}
+
+/// 设备编号变更后迁移已记录的数据帧
+pub fn rekey_device(type_id: u32, device_id: u32, new_device_id: u32) {
+    if let Ok(mut inspector) = INSPECTOR.lock() {
+        inspector.rekey(type_id, device_id, new_device_id);
+    }
+}
+
+/// 十六进制与ASCII对照，每行16字节
+pub fn hex_dump(data: &[u8]) -> String {
+    data.chunks(16)
+        .enumerate()
+        .map(|(line, chunk)| {
+            let hex: Vec<String> = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+            let ascii: String = chunk
+                .iter()
+                .map(|&b| {
+                    if b.is_ascii_graphic() || b == b' ' {
+                        b as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            format!("{:04x}  {:<47}  |{}|", line * 16, hex.join(" "), ascii)
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+fn format_time(time: u64) -> String {
+    Local
+        .timestamp_micros(time as i64)
+        .single()
+        .map(|t| t.format("%H:%M:%S%.3f").to_string())
+        .unwrap_or_default()
+}
+
+fn frame_model(frame: &FrameRecord) -> InspectorFrameModel {
+    let preview = &frame.data[..frame.data.len().min(PREVIEW_SIZE)];
+    let preview: Vec<String> = preview.iter().map(|b| format!("{:02x}", b)).collect();
+    InspectorFrameModel {
+        index: frame.index as i32,
+        time: format_time(frame.time).into(),
+        direction: match frame.direction {
+            Direction::Rx => "RX".into(),
+            Direction::Tx => "TX".into(),
+        },
+        command: frame.command().into(),
+        size: frame.size as i32,
+        crc_ok: frame.crc_ok,
+        known: frame.cmd_code.is_some(),
+        preview: preview.join(" ").into(),
+    }
+}
+
+/// 数据帧概要及数据体的十六进制对照
+pub fn describe(frame: &FrameRecord) -> String {
+    format!(
+        "{} {} {} size:{} crc:{}\n{}",
+        format_time(frame.time),
+        frame_model(frame).direction,
+        frame.command(),
+        frame.size,
+        if frame.crc_ok { "ok" } else { "error" },
+        hex_dump(&frame.data)
+    )
+}
+
+/// 刷新当前设备的数据帧列表，force为false时仅在有新数据帧时刷新
+pub fn show_frames(handle: &AppWindow, force: bool) {
+    let Ok(mut inspector) = INSPECTOR.lock() else {
+        return;
+    };
+    let device = handle.global::<DeviceModelService>();
+    let (type_id, device_id) = (
+        device.get_current_type_id() as u32,
+        device.get_current_device_id() as u32,
+    );
+    let shown = Some((inspector.next_index, type_id, device_id));
+    if !force && inspector.shown == shown {
+        return;
+    }
+    inspector.shown = shown;
+    let service = handle.global::<InspectorModelService>();
+    let frames: Vec<InspectorFrameModel> = inspector
+        .get_frames(type_id, device_id)
+        .into_iter()
+        .map(frame_model)
+        .collect();
+    service.set_frames(VecModel::from_slice(frames.as_slice()));
+    service.set_paused(inspector.paused);
+    let selected = inspector
+        .selected
+        .and_then(|index| inspector.find(type_id, device_id, index));
+    match selected {
+        Some(frame) => {
+            service.set_selected(frame.index as i32);
+            service.set_dump(describe(frame).into());
+        }
+        None => {
+            service.set_selected(-1);
+            service.set_dump("".into());
+        }
+    }
+}
+
+pub fn set_filter(handle: &AppWindow, filter: &str) {
+    if let Ok(mut inspector) = INSPECTOR.lock() {
+        inspector.filter = filter.into();
+    }
+    show_frames(handle, true);
+}
+
+pub fn set_paused(handle: &AppWindow, paused: bool) {
+    if let Ok(mut inspector) = INSPECTOR.lock() {
+        inspector.paused = paused;
+    }
+    show_frames(handle, true);
+}
+
+pub fn select(handle: &AppWindow, index: i32) {
+    if let Ok(mut inspector) = INSPECTOR.lock() {
+        inspector.selected = (index >= 0).then_some(index as u64);
+    }
+    show_frames(handle, true);
+}
+
+/// 清除当前设备的数据帧
+pub fn clear(handle: &AppWindow) {
+    let device = handle.global::<DeviceModelService>();
+    if let Ok(mut inspector) = INSPECTOR.lock() {
+        inspector.clear(
+            device.get_current_type_id() as u32,
+            device.get_current_device_id() as u32,
+        );
+    }
+    show_frames(handle, true);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::caw::protocols::{
+        code::SystemCode,
+        protocol::{FrameBuffer, ProtocolHeader},
+    };
+
+    fn raw_frame(buf: &[u8]) -> RawFrame {
+        let mut frames = FrameBuffer::new();
+        frames.push(buf);
+        frames.next_raw_frame().unwrap()
+    }
+
+    #[test]
+    fn inspector_test() {
+        let ping = ProtocolHeader::pack(CmdCode::System(SystemCode::Ping), &[]).unwrap();
+        let mut unknown = ProtocolHeader::pack(CmdCode::System(SystemCode::Pong), b"ab").unwrap();
+        unknown[7] = 0x7f;
+        unknown[18] ^= 0xff;
+        let mut inspector = Inspector::new();
+        inspector.log(0, 1, Direction::Tx, raw_frame(&ping));
+        inspector.log(0, 1, Direction::Rx, raw_frame(&unknown));
+        let frames = inspector.get_frames(0, 1);
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].command(), "System(Ping)");
+        assert!(frames[0].crc_ok);
+        assert_eq!(frames[1].command(), "Unknown(0x7f:0x1)");
+        assert!(!frames[1].crc_ok);
+        assert_eq!(frames[1].size, unknown.len());
+
+        inspector.filter = "ping, bms".into();
+        assert_eq!(inspector.get_frames(0, 1).len(), 1);
+        inspector.filter = "UNKNOWN".into();
+        assert_eq!(inspector.get_frames(0, 1)[0].index, 1);
+        inspector.rekey(0, 1, 5);
+        assert!(inspector.get_frames(0, 1).is_empty());
+        assert!(inspector.find(0, 5, 1).is_some());
+
+        inspector.paused = true;
+        inspector.log(0, 5, Direction::Tx, raw_frame(&ping));
+        inspector.filter.clear();
+        assert_eq!(inspector.get_frames(0, 5).len(), 2);
+    }
+
+    #[test]
+    fn hex_dump_test() {
+        let data: Vec<u8> = (0x30..0x42).collect();
+        assert_eq!(
+            hex_dump(&data),
+            "0000  30 31 32 33 34 35 36 37 38 39 3a 3b 3c 3d 3e 3f  |0123456789:;<=>?|\n\
+             0010  40 41                                            |@A|"
+        );
+        assert_eq!(
+            hex_dump(&[0, b'a']),
+            format!("0000  00 61{}  |.a|", " ".repeat(42))
+        );
+    }
+}
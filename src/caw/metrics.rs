@@ -0,0 +1,417 @@
+use lazy_static::lazy_static;
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Mutex,
+    time::Duration,
+};
+use tokio::{io::AsyncWriteExt, net::TcpListener};
+
+use crate::caw::{
+    api,
+    clock::ClockSync,
+    devices::capture::Direction,
+    protocols::discover::{type_name, TypeId},
+    utils::args,
+};
+
+/// 默认监听地址，只允许本机访问
+pub const DEFAULT_METRICS_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 9686);
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+lazy_static! {
+    static ref METRICS: Mutex<Metrics> = Mutex::new(Metrics::new());
+}
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+/// 指标名称、说明及取值
+type Metric<T, V> = (&'static str, &'static str, fn(&T) -> V);
+
+/// 电池包遥测数据
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BmsGauges {
+    pub cell_voltage: Vec<f32>, // V
+    pub voltage: f32,           // V
+    pub current: f32,           // A
+    pub temperature: f32,       // °C
+    pub soc: f32,               // %
+    pub soh: f32,               // %
+}
+
+/// 连接统计
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LinkStats {
+    pub frames_rx: u64,
+    pub frames_tx: u64,
+    pub crc_errors: u64,
+    pub resyncs: u64,
+    pub ping_rtt: Option<Duration>,
+    /// 设备时钟相对主机时间的偏移（us）及漂移（ppm）
+    pub clock: Option<(i64, f64)>,
+    /// 建立连接的次数，首次连接不计为重连
+    pub connects: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+struct DeviceMetrics {
+    /// 是否已连接，断开后只保留计数
+    up: bool,
+    link: LinkStats,
+    bms: Option<BmsGauges>,
+}
+
+/// 各设备的统计数据，按设备类型与编号排序输出
+pub struct Metrics {
+    devices: BTreeMap<(u32, u32), DeviceMetrics>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            devices: BTreeMap::new(),
+        }
+    }
+
+    fn device(&mut self, type_id: u32, device_id: u32) -> &mut DeviceMetrics {
+        self.devices.entry((type_id, device_id)).or_default()
+    }
+
+    pub fn rekey(&mut self, type_id: u32, device_id: u32, new_device_id: u32) {
+        if let Some(device) = self.devices.remove(&(type_id, device_id)) {
+            self.devices.insert((type_id, new_device_id), device);
+        }
+    }
+
+    /// 设备断开后清除遥测数据等瞬时值
+    pub fn disconnect(&mut self, type_id: u32, device_id: u32) {
+        if let Some(device) = self.devices.get_mut(&(type_id, device_id)) {
+            device.up = false;
+            device.bms = None;
+            device.link.ping_rtt = None;
+            device.link.clock = None;
+        }
+    }
+
+    /// 按Prometheus文本格式输出
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let labels = |&(type_id, device_id): &(u32, u32)| {
+            format!(
+                "type=\"{}\",device_id=\"{}\"",
+                type_name(type_id).to_lowercase(),
+                device_id
+            )
+        };
+        let bms: Vec<(String, &BmsGauges)> = self
+            .devices
+            .iter()
+            .filter_map(|(key, device)| device.bms.as_ref().map(|bms| (labels(key), bms)))
+            .collect();
+        let gauges: [Metric<BmsGauges, f32>; 5] = [
+            ("caw_bms_voltage_volts", "Pack voltage.", |b| b.voltage),
+            ("caw_bms_current_amperes", "Pack current.", |b| b.current),
+            (
+                "caw_bms_temperature_celsius",
+                "Highest pack temperature.",
+                |b| b.temperature,
+            ),
+            ("caw_bms_soc_percent", "State of charge.", |b| b.soc),
+            ("caw_bms_soh_percent", "State of health.", |b| b.soh),
+        ];
+        if !bms.is_empty() {
+            write_help(
+                &mut out,
+                "caw_bms_cell_voltage_volts",
+                "Cell voltage.",
+                "gauge",
+            );
+            for (labels, b) in bms.iter() {
+                for (i, v) in b.cell_voltage.iter().enumerate() {
+                    let cell = format!("{},cell=\"{}\"", labels, i + 1);
+                    write_sample(&mut out, "caw_bms_cell_voltage_volts", &cell, *v as f64);
+                }
+            }
+            for (name, help, f) in gauges {
+                write_help(&mut out, name, help, "gauge");
+                for (labels, b) in bms.iter() {
+                    write_sample(&mut out, name, labels, f(b) as f64);
+                }
+            }
+        }
+        if self.devices.is_empty() {
+            return out;
+        }
+        let counters: [Metric<LinkStats, u64>; 3] = [
+            (
+                "caw_link_crc_errors_total",
+                "Received frames with a checksum error.",
+                |l| l.crc_errors,
+            ),
+            (
+                "caw_link_resyncs_total",
+                "Times invalid bytes were discarded to find the next frame.",
+                |l| l.resyncs,
+            ),
+            (
+                "caw_link_reconnects_total",
+                "Times the device was connected again.",
+                |l| l.connects.saturating_sub(1),
+            ),
+        ];
+        write_help(
+            &mut out,
+            "caw_device_up",
+            "Whether the device is connected.",
+            "gauge",
+        );
+        for (key, device) in self.devices.iter() {
+            let up = if device.up { 1.0 } else { 0.0 };
+            write_sample(&mut out, "caw_device_up", &labels(key), up);
+        }
+        write_help(
+            &mut out,
+            "caw_link_frames_total",
+            "Frames on the link.",
+            "counter",
+        );
+        for (key, device) in self.devices.iter() {
+            let labels = labels(key);
+            for (direction, value) in [("rx", device.link.frames_rx), ("tx", device.link.frames_tx)]
+            {
+                let labels = format!("{},direction=\"{}\"", labels, direction);
+                write_sample(&mut out, "caw_link_frames_total", &labels, value as f64);
+            }
+        }
+        for (name, help, f) in counters {
+            write_help(&mut out, name, help, "counter");
+            for (key, device) in self.devices.iter() {
+                write_sample(&mut out, name, &labels(key), f(&device.link) as f64);
+            }
+        }
+        write_help(
+            &mut out,
+            "caw_link_ping_rtt_seconds",
+            "Latest ping round-trip time.",
+            "gauge",
+        );
+        for (key, device) in self.devices.iter() {
+            if let Some(rtt) = device.link.ping_rtt {
+                let name = "caw_link_ping_rtt_seconds";
+                write_sample(&mut out, name, &labels(key), rtt.as_secs_f64());
+            }
+        }
+        let clock: [Metric<(i64, f64), f64>; 2] = [
+            (
+                "caw_device_clock_offset_seconds",
+                "Host time minus device tick, from time sync.",
+                |c| c.0 as f64 / 1e6,
+            ),
+            (
+                "caw_device_clock_drift_ppm",
+                "Device clock drift against the host clock.",
+                |c| c.1,
+            ),
+        ];
+        for (name, help, f) in clock {
+            write_help(&mut out, name, help, "gauge");
+            for (key, device) in self.devices.iter() {
+                if let Some(c) = device.link.clock.as_ref() {
+                    write_sample(&mut out, name, &labels(key), f(c));
+                }
+            }
+        }
+        out
+    }
+}
+
+fn write_help(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn write_sample(out: &mut String, name: &str, labels: &str, value: f64) {
+    let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+}
+
+fn update<F>(type_id: u32, device_id: u32, f: F)
+where
+    F: FnOnce(&mut DeviceMetrics),
+{
+    if let Ok(mut metrics) = METRICS.lock() {
+        f(metrics.device(type_id, device_id));
+    }
+}
+
+/// 记录收发的数据帧
+pub fn count_frame(type_id: u32, device_id: u32, direction: Direction, crc_ok: bool) {
+    update(type_id, device_id, |device| match direction {
+        Direction::Rx => {
+            device.link.frames_rx += 1;
+            if !crc_ok {
+                device.link.crc_errors += 1;
+            }
+        }
+        Direction::Tx => device.link.frames_tx += 1,
+    });
+}
+
+pub fn count_resyncs(type_id: u32, device_id: u32, resyncs: u64) {
+    if resyncs > 0 {
+        update(type_id, device_id, |device| device.link.resyncs += resyncs);
+    }
+}
+
+pub fn set_ping_rtt(type_id: u32, device_id: u32, rtt: Duration) {
+    update(type_id, device_id, |device| {
+        device.link.ping_rtt = Some(rtt)
+    });
+}
+
+/// 记录时间同步估算的设备时钟偏移及漂移
+pub fn set_clock(type_id: u32, device_id: u32, clock: &ClockSync) {
+    let value = (clock.get_offset(), clock.get_drift_ppm());
+    update(type_id, device_id, |device| device.link.clock = Some(value));
+}
+
+/// 记录一次连接，同一设备再次连接时计为重连
+pub fn count_connect(type_id: u32, device_id: u32) {
+    update(type_id, device_id, |device| {
+        device.up = true;
+        device.link.connects += 1;
+    });
+}
+
+/// 记录设备断开
+pub fn disconnect_device(type_id: u32, device_id: u32) {
+    if let Ok(mut metrics) = METRICS.lock() {
+        metrics.disconnect(type_id, device_id);
+    }
+}
+
+pub fn set_bms(type_id: u32, device_id: u32, bms: BmsGauges) {
+    if type_id == TypeId::BMS as u32 {
+        update(type_id, device_id, |device| device.bms = Some(bms));
+    }
+}
+
+/// 设备编号变更后迁移统计数据
+pub fn rekey_device(type_id: u32, device_id: u32, new_device_id: u32) {
+    if let Ok(mut metrics) = METRICS.lock() {
+        metrics.rekey(type_id, device_id, new_device_id);
+    }
+}
+
+/// 命令行参数 --metrics [addr]，未给出地址时使用默认地址
+pub fn addr_arg(args: &[String]) -> Result<Option<SocketAddr>> {
+    match args::value(args, "--metrics") {
+        Ok(Some(addr)) => Ok(Some(api::parse_addr(addr)?)),
+        Ok(None) => Ok(None),
+        Err(_) => Ok(Some(DEFAULT_METRICS_ADDR)),
+    }
+}
+
+/// 启动导出服务，GET /metrics 返回Prometheus文本格式的统计数据
+pub async fn serve(addr: SocketAddr) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    eprintln!("metrics listening on http://{}/metrics", addr);
+    loop {
+        let (mut tcp, _) = listener.accept().await?;
+        tokio::spawn(async move {
+            let Ok(Some(request)) = api::read_request(&mut tcp).await else {
+                return;
+            };
+            let buf = match (request.method.as_str(), request.path.as_str()) {
+                ("GET", "/metrics") => {
+                    let body = METRICS.lock().map(|m| m.render()).unwrap_or_default();
+                    api::http_response(200, CONTENT_TYPE, &body)
+                }
+                _ => api::http_response(404, CONTENT_TYPE, "not found\n"),
+            };
+            let _ = tcp.write_all(&buf).await;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_test() {
+        let mut metrics = Metrics::new();
+        assert_eq!(metrics.render(), "");
+        let device = metrics.device(0, 2);
+        device.up = true;
+        device.link = LinkStats {
+            frames_rx: 10,
+            frames_tx: 4,
+            crc_errors: 1,
+            resyncs: 2,
+            ping_rtt: Some(Duration::from_millis(15)),
+            clock: Some((-2_500, 50.0)),
+            connects: 3,
+        };
+        device.bms = Some(BmsGauges {
+            cell_voltage: vec![3.25, 3.5],
+            voltage: 6.75,
+            current: -1.5,
+            temperature: 25.0,
+            soc: 80.0,
+            soh: 99.0,
+        });
+        metrics.device(1, 1).link.frames_tx = 1;
+        metrics.rekey(0, 2, 7);
+        let text = metrics.render();
+        let lines: Vec<&str> = text.lines().collect();
+        for line in [
+            "# TYPE caw_bms_cell_voltage_volts gauge",
+            "caw_bms_cell_voltage_volts{type=\"bms\",device_id=\"7\",cell=\"2\"} 3.5",
+            "caw_bms_current_amperes{type=\"bms\",device_id=\"7\"} -1.5",
+            "caw_bms_soc_percent{type=\"bms\",device_id=\"7\"} 80",
+            "caw_device_up{type=\"bms\",device_id=\"7\"} 1",
+            "caw_device_up{type=\"motor\",device_id=\"1\"} 0",
+            "# TYPE caw_link_frames_total counter",
+            "caw_link_frames_total{type=\"bms\",device_id=\"7\",direction=\"rx\"} 10",
+            "caw_link_frames_total{type=\"motor\",device_id=\"1\",direction=\"tx\"} 1",
+            "caw_link_crc_errors_total{type=\"bms\",device_id=\"7\"} 1",
+            "caw_link_resyncs_total{type=\"bms\",device_id=\"7\"} 2",
+            "caw_link_reconnects_total{type=\"bms\",device_id=\"7\"} 2",
+            "caw_link_reconnects_total{type=\"motor\",device_id=\"1\"} 0",
+            "caw_link_ping_rtt_seconds{type=\"bms\",device_id=\"7\"} 0.015",
+            "caw_device_clock_offset_seconds{type=\"bms\",device_id=\"7\"} -0.0025",
+            "caw_device_clock_drift_ppm{type=\"bms\",device_id=\"7\"} 50",
+        ] {
+            assert!(lines.contains(&line), "missing {}", line);
+        }
+        assert!(!text.contains("device_id=\"2\""));
+        assert!(!text.contains("caw_link_ping_rtt_seconds{type=\"motor\""));
+        // 每个指标只有一组HELP/TYPE
+        let types = lines.iter().filter(|l| l.starts_with("# TYPE")).count();
+        assert_eq!(types, 14);
+
+        // 断开后不再输出遥测数据，计数保留
+        metrics.disconnect(0, 7);
+        let text = metrics.render();
+        assert!(!text.contains("caw_bms_"));
+        assert!(!text.contains("caw_link_ping_rtt_seconds{"));
+        assert!(!text.contains("caw_device_clock_offset_seconds{"));
+        assert!(text.contains("caw_device_up{type=\"bms\",device_id=\"7\"} 0"));
+        assert!(text.contains("caw_link_crc_errors_total{type=\"bms\",device_id=\"7\"} 1"));
+    }
+
+    #[test]
+    fn addr_arg_test() {
+        let args = |s: &[&str]| s.iter().map(|s| s.to_string()).collect::<Vec<String>>();
+        assert_eq!(addr_arg(&args(&["app"])).unwrap(), None);
+        assert_eq!(
+            addr_arg(&args(&["app", "--metrics"])).unwrap(),
+            Some(DEFAULT_METRICS_ADDR)
+        );
+        assert_eq!(
+            addr_arg(&args(&["app", "--metrics", "0.0.0.0:9100"])).unwrap(),
+            Some("0.0.0.0:9100".parse().unwrap())
+        );
+        assert!(addr_arg(&args(&["app", "--metrics", "x"])).is_err());
+    }
+}
@@ -1,6 +1,22 @@
+pub mod alarm;
+pub mod analytics;
+pub mod api;
+pub mod bms;
 pub mod chart;
+pub mod cli;
+pub mod clock;
+pub mod composer;
 pub mod connector;
 pub mod devices;
 pub mod event;
+pub mod firmware;
+pub mod history;
+pub mod inspector;
+pub mod metrics;
+pub mod mqtt;
+pub mod pcap;
 pub mod protocols;
+pub mod recorder;
+pub mod script;
+pub mod telemetry;
 pub mod utils;
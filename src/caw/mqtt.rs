@@ -0,0 +1,360 @@
+use lazy_static::lazy_static;
+use rumqttc::{AsyncClient, Event, LastWill, MqttOptions, Outgoing, Packet, QoS};
+use serde_json::json;
+use std::{collections::HashSet, sync::Mutex, time::Duration};
+use tokio::sync::Notify;
+
+use crate::caw::{
+    api::{self, ApiContext},
+    protocols::discover::{type_name, TypeId},
+    telemetry::{self, Record},
+    utils::args,
+};
+
+/// 主题前缀，设备主题为 caw/<type>/<device_id>/<name>
+const TOPIC_PREFIX: &str = "caw";
+pub const DEFAULT_MQTT_PORT: u16 = 1883;
+const KEEP_ALIVE: Duration = Duration::from_secs(10);
+/// 检查设备上下线的间隔
+const STATUS_INTERVAL: Duration = Duration::from_secs(1);
+const RECONNECT_DELAY: Duration = Duration::from_secs(3);
+/// 待发送消息队列长度，队列满时丢弃遥测数据
+const QUEUE_SIZE: usize = 256;
+/// 退出时等待离线状态发送完成的最长时间
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
+/// 未指定--mqtt-pass时从此环境变量读取密码
+const PASSWORD_ENV: &str = "CAW_MQTT_PASSWORD";
+
+lazy_static! {
+    static ref CLIENT: Mutex<Option<AsyncClient>> = Mutex::new(None);
+    /// 已发布为online的设备
+    static ref ONLINE: Mutex<HashSet<(u32, u32)>> = Mutex::new(HashSet::new());
+    static ref DISCONNECTED: Notify = Notify::new();
+}
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+#[derive(Debug, Clone)]
+pub enum MqttError {
+    InvalidBroker(String),
+    MissingUser,
+}
+
+impl std::fmt::Display for MqttError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            MqttError::InvalidBroker(ref broker) => {
+                write!(f, "invalid broker address: {}", broker)
+            }
+            MqttError::MissingUser => {
+                write!(f, "--mqtt-pass requires --mqtt-user")
+            }
+        }
+    }
+}
+
+impl std::error::Error for MqttError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            MqttError::InvalidBroker(_) => None,
+            MqttError::MissingUser => None,
+        }
+    }
+}
+
+/// 解析代理服务器地址 host[:port]
+pub fn parse_broker(broker: &str) -> Result<(String, u16)> {
+    let (host, port) = match broker.rsplit_once(':') {
+        Some((host, port)) => match port.parse::<u16>() {
+            Ok(port) => (host, port),
+            Err(_) => return Err(MqttError::InvalidBroker(broker.into()).into()),
+        },
+        None => (broker, DEFAULT_MQTT_PORT),
+    };
+    if host.is_empty() {
+        return Err(MqttError::InvalidBroker(broker.into()).into());
+    }
+    Ok((host.into(), port))
+}
+
+/// 代理服务器连接参数
+#[derive(Debug, Clone, PartialEq)]
+pub struct BrokerOptions {
+    pub host: String,
+    pub port: u16,
+    /// 用户名与密码
+    pub credentials: Option<(String, String)>,
+}
+
+impl BrokerOptions {
+    /// 解析命令行参数，未指定--mqtt时返回None
+    ///
+    /// --mqtt <host[:port]> [--mqtt-user <name>] [--mqtt-pass <password>]，
+    /// 未指定密码时读取环境变量CAW_MQTT_PASSWORD
+    pub fn from_args(args: &[String]) -> Result<Option<Self>> {
+        let Some(broker) = args::value(args, "--mqtt")? else {
+            return Ok(None);
+        };
+        let (host, port) = parse_broker(broker)?;
+        let password = match args::value(args, "--mqtt-pass")? {
+            Some(password) => Some(password.clone()),
+            None => std::env::var(PASSWORD_ENV).ok(),
+        };
+        let credentials = match (args::value(args, "--mqtt-user")?, password) {
+            (Some(user), password) => Some((user.clone(), password.unwrap_or_default())),
+            (None, _) if args::flag(args, "--mqtt-pass") => {
+                return Err(MqttError::MissingUser.into())
+            }
+            (None, _) => None,
+        };
+        Ok(Some(Self {
+            host,
+            port,
+            credentials,
+        }))
+    }
+}
+
+/// 主题中的设备类型，未知类型使用编号
+pub fn type_topic(type_id: u32) -> String {
+    match type_id {
+        id if id == TypeId::BMS as u32 || id == TypeId::Motor as u32 => {
+            type_name(id).to_lowercase()
+        }
+        id => id.to_string(),
+    }
+}
+
+fn parse_type(s: &str) -> Option<u32> {
+    [TypeId::BMS as u32, TypeId::Motor as u32]
+        .into_iter()
+        .find(|&id| type_topic(id) == s)
+        .or_else(|| s.parse::<u32>().ok())
+}
+
+pub fn device_topic(type_id: u32, device_id: u32, name: &str) -> String {
+    format!(
+        "{}/{}/{}/{}",
+        TOPIC_PREFIX,
+        type_topic(type_id),
+        device_id,
+        name
+    )
+}
+
+fn bridge_topic() -> String {
+    format!("{}/bridge/status", TOPIC_PREFIX)
+}
+
+/// 解析设备主题 caw/<type>/<device_id>/<name>
+fn parse_device_topic(topic: &str, name: &str) -> Option<(u32, u32)> {
+    match topic.split('/').collect::<Vec<&str>>().as_slice() {
+        [prefix, type_id, device_id, n] if *prefix == TOPIC_PREFIX && *n == name => {
+            Some((parse_type(type_id)?, device_id.parse::<u32>().ok()?))
+        }
+        _ => None,
+    }
+}
+
+fn publish(topic: String, retain: bool, payload: String) {
+    if let Ok(client) = CLIENT.lock() {
+        if let Some(client) = client.as_ref() {
+            let _ = client.try_publish(topic, QoS::AtMostOnce, retain, payload);
+        }
+    }
+}
+
+/// 遥测数据发布到 caw/<type>/<device_id>/info，应答等事件发布到 caw/<type>/<device_id>/<kind>
+///
+/// 负载与HTTP接口的JSON一致，time为Unix时间戳(ms)，测量值为数值
+fn forward(record: &Record) {
+    let name = if record.kind.is_event() {
+        record.kind.name()
+    } else {
+        "info"
+    };
+    let topic = device_topic(record.type_id, record.device_id, name);
+    publish(topic, false, api::to_json(record).to_string());
+}
+
+/// 发布设备上下线状态，保留消息
+fn update_status(devices: Vec<(u32, u32)>) {
+    let Ok(mut online) = ONLINE.lock() else {
+        return;
+    };
+    let devices: HashSet<(u32, u32)> = devices.into_iter().collect();
+    for &(type_id, device_id) in online.difference(&devices) {
+        publish(
+            device_topic(type_id, device_id, "status"),
+            true,
+            "offline".into(),
+        );
+    }
+    for &(type_id, device_id) in devices.difference(&online) {
+        publish(
+            device_topic(type_id, device_id, "status"),
+            true,
+            "online".into(),
+        );
+    }
+    *online = devices;
+}
+
+/// 执行指令主题收到的指令，结果发布到 caw/<type>/<device_id>/command/result
+fn handle_command(ctx: &ApiContext, topic: &str, payload: &[u8]) {
+    let Some((type_id, device_id)) = parse_device_topic(topic, "command") else {
+        return;
+    };
+    let ret = api::parse_send(payload)
+        .and_then(|(group, code, data)| (ctx.send)(type_id, device_id, group, code, &data));
+    let result = match ret {
+        Ok(_) => json!({ "ok": true }),
+        Err(e) => json!({ "error": e.to_string() }),
+    };
+    publish(format!("{}/result", topic), false, result.to_string());
+}
+
+/// 清除上次异常退出时残留的设备在线状态
+fn clear_stale_status(topic: &str, payload: &[u8]) {
+    let Some(device) = parse_device_topic(topic, "status") else {
+        return;
+    };
+    let online = ONLINE.lock().is_ok_and(|online| online.contains(&device));
+    if !online && payload != b"offline" {
+        publish(topic.into(), true, "offline".into());
+    }
+}
+
+/// 退出前将在线设备与桥接置为offline并断开连接，等待发送完成或超时
+///
+/// 正常断开时代理服务器不发布遗嘱消息
+pub async fn shutdown() {
+    let Some(client) = CLIENT.lock().ok().and_then(|mut client| client.take()) else {
+        return;
+    };
+    let online: Vec<(u32, u32)> = ONLINE
+        .lock()
+        .map(|mut online| online.drain().collect())
+        .unwrap_or_default();
+    for (type_id, device_id) in online {
+        let topic = device_topic(type_id, device_id, "status");
+        let _ = client.try_publish(topic, QoS::AtLeastOnce, true, "offline");
+    }
+    let _ = client.try_publish(bridge_topic(), QoS::AtLeastOnce, true, "offline");
+    if client.try_disconnect().is_ok() {
+        let _ = tokio::time::timeout(SHUTDOWN_TIMEOUT, DISCONNECTED.notified()).await;
+    }
+}
+
+/// 连接代理服务器并持续转发，连接断开后自动重连
+///
+/// 桥接本身的状态发布在 caw/bridge/status，异常断开时由遗嘱消息置为offline，
+/// 此时设备状态不再更新，使用方应以桥接状态为准；重连后残留的设备online状态置为offline
+pub async fn run(broker: BrokerOptions, ctx: ApiContext) {
+    let client_id = format!("caw-link-{}", std::process::id());
+    let mut options = MqttOptions::new(client_id, broker.host, broker.port);
+    options.set_keep_alive(KEEP_ALIVE);
+    if let Some((user, password)) = broker.credentials {
+        options.set_credentials(user, password);
+    }
+    options.set_last_will(LastWill::new(
+        bridge_topic(),
+        "offline",
+        QoS::AtLeastOnce,
+        true,
+    ));
+    let (client, mut eventloop) = AsyncClient::new(options, QUEUE_SIZE);
+    if let Ok(mut c) = CLIENT.lock() {
+        *c = Some(client.clone());
+    }
+    let _subscription = telemetry::subscribe(forward);
+    let mut interval = tokio::time::interval(STATUS_INTERVAL);
+    loop {
+        tokio::select! {
+            event = eventloop.poll() => match event {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    eprintln!("mqtt connected");
+                    let topic = format!("{}/+/+/command", TOPIC_PREFIX);
+                    let _ = client.try_subscribe(topic, QoS::AtLeastOnce);
+                    publish(bridge_topic(), true, "online".into());
+                    // 重连后重新发布全部设备状态
+                    if let Ok(mut online) = ONLINE.lock() {
+                        online.clear();
+                    }
+                    update_status((ctx.devices)());
+                    // 订阅时收到的保留消息用于清除残留状态
+                    let topic = format!("{}/+/+/status", TOPIC_PREFIX);
+                    let _ = client.try_subscribe(topic, QoS::AtLeastOnce);
+                }
+                Ok(Event::Incoming(Packet::Publish(p))) if p.retain => {
+                    clear_stale_status(&p.topic, &p.payload);
+                }
+                Ok(Event::Incoming(Packet::Publish(p))) => {
+                    let topic = p.topic.clone();
+                    tokio::task::spawn_blocking(move || handle_command(&ctx, &topic, &p.payload));
+                }
+                Ok(Event::Outgoing(Outgoing::Disconnect)) => {
+                    DISCONNECTED.notify_one();
+                    return;
+                }
+                Ok(_) => (),
+                Err(e) => {
+                    eprintln!("mqtt connection error: {}", e);
+                    tokio::time::sleep(RECONNECT_DELAY).await;
+                }
+            },
+            _ = interval.tick() => update_status((ctx.devices)()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn topic_test() {
+        assert_eq!(device_topic(0, 3, "info"), "caw/bms/3/info");
+        assert_eq!(device_topic(1, 3, "status"), "caw/motor/3/status");
+        assert_eq!(device_topic(7, 3, "info"), "caw/7/3/info");
+        let command = |topic| parse_device_topic(topic, "command");
+        assert_eq!(command("caw/motor/12/command"), Some((1, 12)));
+        assert_eq!(command("caw/7/1/command"), Some((7, 1)));
+        assert_eq!(command("caw/motor/x/command"), None);
+        assert_eq!(command("caw/motor/1/command/result"), None);
+        assert_eq!(command("other/bms/1/command"), None);
+        assert_eq!(
+            parse_device_topic("caw/bms/2/status", "status"),
+            Some((0, 2))
+        );
+        assert_eq!(parse_device_topic("caw/bms/2/status", "command"), None);
+    }
+
+    #[test]
+    fn parse_broker_test() {
+        assert_eq!(
+            parse_broker("localhost").unwrap(),
+            ("localhost".into(), 1883)
+        );
+        assert_eq!(
+            parse_broker("10.0.0.2:1884").unwrap(),
+            ("10.0.0.2".into(), 1884)
+        );
+        assert!(parse_broker("localhost:x").is_err());
+        assert!(parse_broker(":1883").is_err());
+    }
+
+    #[test]
+    fn broker_options_test() {
+        let args = |s: &[&str]| s.iter().map(|s| s.to_string()).collect::<Vec<String>>();
+        assert_eq!(BrokerOptions::from_args(&args(&["app"])).unwrap(), None);
+        let options = BrokerOptions::from_args(&args(&["app", "--mqtt", "h:1884"])).unwrap();
+        assert_eq!(options.unwrap().port, 1884);
+        let a = args(&["app", "--mqtt", "h", "--mqtt-user", "u", "--mqtt-pass", "p"]);
+        let options = BrokerOptions::from_args(&a).unwrap().unwrap();
+        assert_eq!(options.credentials, Some(("u".into(), "p".into())));
+        let a = args(&["app", "--mqtt", "h", "--mqtt-pass", "p"]);
+        assert!(BrokerOptions::from_args(&a).is_err());
+        assert!(BrokerOptions::from_args(&args(&["app", "--mqtt", "h", "--mqtt-user"])).is_err());
+    }
+}
@@ -0,0 +1,405 @@
+use std::{fs, path::Path};
+
+use crate::caw::{
+    devices::capture::{self, Direction},
+    protocols::{
+        bms::{BMS_INFO_SIZE, BMS_STATES},
+        code::CMD_CODES,
+        protocol::{FrameBuffer, HEADER_SIZE},
+    },
+};
+
+/// pcapng中使用的链路类型LINKTYPE_USER0
+pub const LINKTYPE_USER0: u16 = 147;
+/// 生成的Wireshark解析脚本文件名
+pub const DISSECTOR_FILE: &str = "cawlink.lua";
+
+const BLOCK_SECTION_HEADER: u32 = 0x0a0d0d0a;
+const BLOCK_INTERFACE_DESCRIPTION: u32 = 0x00000001;
+const BLOCK_ENHANCED_PACKET: u32 = 0x00000006;
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b3c4d;
+const OPT_END: u16 = 0;
+const OPT_IF_NAME: u16 = 2;
+const OPT_EPB_FLAGS: u16 = 2;
+const EPB_FLAG_INBOUND: u32 = 0b01;
+const EPB_FLAG_OUTBOUND: u32 = 0b10;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+fn pad4(buf: &mut Vec<u8>) {
+    while !buf.len().is_multiple_of(4) {
+        buf.push(0);
+    }
+}
+
+fn push_option(buf: &mut Vec<u8>, code: u16, value: &[u8]) {
+    buf.extend_from_slice(&code.to_le_bytes());
+    buf.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    buf.extend_from_slice(value);
+    pad4(buf);
+}
+
+/// 打包pcapng块，块长度包含首尾的类型与长度字段
+fn block(block_type: u32, mut body: Vec<u8>) -> Vec<u8> {
+    pad4(&mut body);
+    let size = (body.len() + 12) as u32;
+    let mut buf = Vec::with_capacity(size as usize);
+    buf.extend_from_slice(&block_type.to_le_bytes());
+    buf.extend_from_slice(&size.to_le_bytes());
+    buf.append(&mut body);
+    buf.extend_from_slice(&size.to_le_bytes());
+    buf
+}
+
+fn section_header() -> Vec<u8> {
+    let mut body = vec![];
+    body.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+    body.extend_from_slice(&1u16.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes());
+    // 未指定段长度
+    body.extend_from_slice(&(-1i64).to_le_bytes());
+    block(BLOCK_SECTION_HEADER, body)
+}
+
+/// 接口描述，时间戳精度使用默认的微秒
+fn interface_description(name: &str) -> Vec<u8> {
+    let mut body = vec![];
+    body.extend_from_slice(&LINKTYPE_USER0.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes());
+    body.extend_from_slice(&0u32.to_le_bytes());
+    push_option(&mut body, OPT_IF_NAME, name.as_bytes());
+    push_option(&mut body, OPT_END, &[]);
+    block(BLOCK_INTERFACE_DESCRIPTION, body)
+}
+
+fn enhanced_packet(time: u64, direction: Direction, data: &[u8]) -> Vec<u8> {
+    let mut body = vec![];
+    body.extend_from_slice(&0u32.to_le_bytes());
+    body.extend_from_slice(&((time >> 32) as u32).to_le_bytes());
+    body.extend_from_slice(&(time as u32).to_le_bytes());
+    body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    body.extend_from_slice(data);
+    pad4(&mut body);
+    let flags = match direction {
+        Direction::Rx => EPB_FLAG_INBOUND,
+        Direction::Tx => EPB_FLAG_OUTBOUND,
+    };
+    push_option(&mut body, OPT_EPB_FLAGS, &flags.to_le_bytes());
+    push_option(&mut body, OPT_END, &[]);
+    block(BLOCK_ENHANCED_PACKET, body)
+}
+
+/// 将会话文件转换为pcapng，每个完整的数据帧为一个报文，返回文件内容与报文数
+///
+/// 收发方向分别切分数据帧，指令无法识别的帧也按原始字节写入，
+/// 报文时间为帧最后一个字节到达的时间
+pub fn session_to_pcapng(buf: &[u8]) -> Result<(Vec<u8>, usize)> {
+    let (header, records) = capture::parse_session(buf)?;
+    let (device_id, type_id) = header.get_id();
+    let mut out = section_header();
+    out.append(&mut interface_description(&format!(
+        "cawlink-{}-{}",
+        type_id, device_id
+    )));
+    let mut rx = FrameBuffer::new();
+    let mut tx = FrameBuffer::new();
+    let mut count = 0;
+    for record in records.iter() {
+        let frames = match record.direction {
+            Direction::Rx => &mut rx,
+            Direction::Tx => &mut tx,
+        };
+        frames.push(&record.data);
+        while let Some(frame) = frames.next_raw_frame() {
+            let frame = frame.to_bytes();
+            out.append(&mut enhanced_packet(record.time, record.direction, &frame));
+            count += 1;
+        }
+    }
+    Ok((out, count))
+}
+
+/// 指令的分组编号、分组名、指令编号与指令名，编号与数据帧中的编码一致
+fn cmd_code_table() -> Vec<(u32, String, u32, String)> {
+    CMD_CODES
+        .iter()
+        .filter_map(|code| {
+            let (group, index) = code.to_raw();
+            let name = format!("{:?}", code);
+            let (group_name, cmd_name) = name.trim_end_matches(')').split_once('(')?;
+            Some((group, group_name.into(), index, cmd_name.into()))
+        })
+        .collect()
+}
+
+/// 生成Wireshark的Lua解析脚本，解析协议头与BMSInfo数据体
+pub fn lua_dissector() -> String {
+    let table = cmd_code_table();
+    let mut groups: Vec<(u32, &str)> = table.iter().map(|(g, n, _, _)| (*g, n.as_str())).collect();
+    groups.dedup();
+    let group_names: Vec<String> = groups
+        .iter()
+        .map(|(g, n)| format!("[{}] = \"{}\"", g, n))
+        .collect();
+    let cmd_names: Vec<String> = table
+        .iter()
+        .map(|(g, _, c, n)| format!("    [\"{}:{}\"] = \"{}\",", g, c, n))
+        .collect();
+    let bms_info = table
+        .iter()
+        .find(|(_, g, _, n)| g == "BMS" && n == "Info")
+        .map_or((0, 0), |(g, _, c, _)| (*g, *c));
+    let state_fields: Vec<String> = BMS_STATES
+        .iter()
+        .enumerate()
+        .map(|(i, name)| {
+            format!(
+                "    ProtoField.bool(\"cawlink.bms.state.{}\", \"{}\", 8, nil, 0x{:02x}),",
+                name.to_lowercase(),
+                name,
+                1u8 << i
+            )
+        })
+        .collect();
+    LUA_TEMPLATE
+        .replace("@GROUPS@", &group_names.join(", "))
+        .replace("@COMMANDS@", &cmd_names.join("\n"))
+        .replace("@STATE_FIELDS@", &state_fields.join("\n"))
+        .replace("@HEADER_SIZE@", &HEADER_SIZE.to_string())
+        .replace("@BMS_INFO_SIZE@", &BMS_INFO_SIZE.to_string())
+        .replace("@BMS_GROUP@", &bms_info.0.to_string())
+        .replace("@BMS_INFO@", &bms_info.1.to_string())
+}
+
+/// 导出会话文件为pcapng，并在同一目录生成解析脚本，返回报文数
+pub fn export(session: &Path, out: &Path) -> Result<usize> {
+    let (buf, count) = session_to_pcapng(&fs::read(session)?)?;
+    fs::write(out, buf)?;
+    let dissector = out.parent().unwrap_or(Path::new(".")).join(DISSECTOR_FILE);
+    fs::write(dissector, lua_dissector())?;
+    Ok(count)
+}
+
+const LUA_TEMPLATE: &str = r#"-- CawLink protocol dissector, generated by caw-link-desktop.
+-- Copy to the Wireshark personal plugins folder and open the exported pcapng (DLT USER0).
+local caw = Proto("cawlink", "CawLink")
+
+local groups = { @GROUPS@ }
+local commands = {
+@COMMANDS@
+}
+local sensor_kinds = { [0] = "Cell", [1] = "FET", [2] = "Ambient" }
+
+local f = {
+    magic = ProtoField.string("cawlink.magic", "Magic"),
+    group = ProtoField.uint32("cawlink.cmd_group", "Command group", base.DEC, groups),
+    cmd = ProtoField.uint32("cawlink.cmd", "Command", base.DEC),
+    version = ProtoField.uint16("cawlink.version", "Version", base.HEX),
+    data_size = ProtoField.uint32("cawlink.data_size", "Data size", base.DEC),
+    checksum = ProtoField.uint8("cawlink.checksum", "Checksum (CRC8)", base.HEX),
+    data = ProtoField.bytes("cawlink.data", "Data"),
+    bms_state = ProtoField.uint8("cawlink.bms.state", "State", base.HEX),
+    bms_cell_count = ProtoField.uint8("cawlink.bms.cell_count", "Cell count", base.DEC),
+    bms_cell = ProtoField.int32("cawlink.bms.cell_voltage", "Cell voltage (x0.01V)", base.DEC),
+    bms_balance = ProtoField.uint8("cawlink.bms.balance", "Balance", base.DEC),
+    bms_voltage = ProtoField.int32("cawlink.bms.voltage", "Voltage (x0.01V)", base.DEC),
+    bms_current = ProtoField.int32("cawlink.bms.current", "Current (x0.01A)", base.DEC),
+    bms_temperature = ProtoField.int32("cawlink.bms.temperature", "Temperature (x0.01C)", base.DEC),
+    bms_soc = ProtoField.int32("cawlink.bms.soc", "SOC (x0.01%)", base.DEC),
+    bms_soh = ProtoField.int32("cawlink.bms.soh", "SOH (x0.01%)", base.DEC),
+    bms_dsg = ProtoField.uint8("cawlink.bms.dsg", "DSG", base.DEC),
+    bms_chg = ProtoField.uint8("cawlink.bms.chg", "CHG", base.DEC),
+    bms_sensor_count = ProtoField.uint8("cawlink.bms.sensor_count", "Sensor count", base.DEC),
+    bms_sensor_kind = ProtoField.uint8("cawlink.bms.sensor_kind", "Sensor kind", base.DEC, sensor_kinds),
+    bms_sensor_temperature = ProtoField.int32("cawlink.bms.sensor_temperature", "Sensor temperature (x0.01C)", base.DEC),
+}
+local state_bits = {
+@STATE_FIELDS@
+}
+
+local fields = {}
+for _, field in pairs(f) do table.insert(fields, field) end
+for _, field in ipairs(state_bits) do table.insert(fields, field) end
+caw.fields = fields
+
+local function crc8(tvb)
+    local crc = 0
+    for i = 0, tvb:len() - 1 do
+        crc = bit.bxor(crc, tvb(i, 1):uint())
+        for _ = 1, 8 do
+            if bit.band(crc, 0x80) ~= 0 then
+                crc = bit.band(bit.bxor(bit.lshift(crc, 1), 0x07), 0xff)
+            else
+                crc = bit.band(bit.lshift(crc, 1), 0xff)
+            end
+        end
+    end
+    return crc
+end
+
+local function add_scaled(tree, field, range, unit)
+    local item = tree:add(field, range)
+    item:append_text(string.format(" (%.2f %s)", range:int() / 100, unit))
+    return item
+end
+
+local function dissect_bms_info(buf, tree)
+    local len = buf:len()
+    local state = tree:add(f.bms_state, buf(0, 1))
+    for _, field in ipairs(state_bits) do state:add(field, buf(0, 1)) end
+    local cells, offset
+    if len == @BMS_INFO_SIZE@ then
+        cells, offset = 5, 1
+    else
+        tree:add(f.bms_cell_count, buf(1, 1))
+        cells, offset = buf(1, 1):uint(), 2
+    end
+    if len < offset + cells * 5 + 22 then
+        tree:add_expert_info(PI_MALFORMED, PI_ERROR, "BMSInfo truncated")
+        return
+    end
+    for i = 1, cells do
+        add_scaled(tree, f.bms_cell, buf(offset, 4), "V"):append_text(" [cell " .. i .. "]")
+        offset = offset + 4
+    end
+    for i = 1, cells do
+        tree:add(f.bms_balance, buf(offset, 1)):append_text(" [cell " .. i .. "]")
+        offset = offset + 1
+    end
+    add_scaled(tree, f.bms_voltage, buf(offset, 4), "V")
+    add_scaled(tree, f.bms_current, buf(offset + 4, 4), "A")
+    add_scaled(tree, f.bms_temperature, buf(offset + 8, 4), "C")
+    add_scaled(tree, f.bms_soc, buf(offset + 12, 4), "%")
+    add_scaled(tree, f.bms_soh, buf(offset + 16, 4), "%")
+    tree:add(f.bms_dsg, buf(offset + 20, 1))
+    tree:add(f.bms_chg, buf(offset + 21, 1))
+    offset = offset + 22
+    if offset < len then
+        local sensors = buf(offset, 1):uint()
+        tree:add(f.bms_sensor_count, buf(offset, 1))
+        offset = offset + 1
+        for _ = 1, sensors do
+            if offset + 5 > len then
+                tree:add_expert_info(PI_MALFORMED, PI_ERROR, "sensor data truncated")
+                return
+            end
+            tree:add(f.bms_sensor_kind, buf(offset, 1))
+            add_scaled(tree, f.bms_sensor_temperature, buf(offset + 1, 4), "C")
+            offset = offset + 5
+        end
+    end
+end
+
+function caw.dissector(buf, pinfo, tree)
+    if buf:len() < @HEADER_SIZE@ then return 0 end
+    pinfo.cols.protocol = "CawLink"
+    local subtree = tree:add(caw, buf())
+    subtree:add(f.magic, buf(0, 4))
+    local group, cmd = buf(4, 4):uint(), buf(8, 4):uint()
+    local name = commands[group .. ":" .. cmd] or "Unknown"
+    subtree:add(f.group, buf(4, 4))
+    subtree:add(f.cmd, buf(8, 4)):append_text(" (" .. name .. ")")
+    subtree:add(f.version, buf(12, 2))
+    local size = buf(14, 4):uint()
+    subtree:add(f.data_size, buf(14, 4))
+    local checksum = subtree:add(f.checksum, buf(18, 1))
+    pinfo.cols.info = (groups[group] or "Unknown") .. "." .. name .. " size=" .. size
+    if buf:len() < @HEADER_SIZE@ + size then
+        subtree:add_expert_info(PI_MALFORMED, PI_ERROR, "frame truncated")
+        return buf:len()
+    end
+    if size > 0 then
+        local data = buf(@HEADER_SIZE@, size)
+        if crc8(data:tvb()) ~= buf(18, 1):uint() then
+            checksum:add_expert_info(PI_CHECKSUM, PI_ERROR, "bad checksum")
+        end
+        local data_tree = subtree:add(f.data, data)
+        if group == @BMS_GROUP@ and cmd == @BMS_INFO@ then
+            dissect_bms_info(data:tvb(), data_tree)
+        end
+    end
+    return buf:len()
+end
+
+local encap = wtap_encaps or wtap
+DissectorTable.get("wtap_encap"):add(encap.USER0, caw)
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::caw::{
+        devices::capture::{SessionHeader, SessionRecord},
+        protocols::{
+            code::{BMSCode, CmdCode, SystemCode},
+            protocol::ProtocolHeader,
+        },
+    };
+
+    fn u32_at(buf: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn session_to_pcapng_test() {
+        let frame = ProtocolHeader::pack(CmdCode::BMS(BMSCode::Info), &[1, 2, 3]).unwrap();
+        // 无法识别的指令及错误的校验和按原样保留
+        let mut unknown = ProtocolHeader::pack(CmdCode::System(SystemCode::Pong), b"ab").unwrap();
+        unknown[7] = 0x7f;
+        unknown[18] ^= 0xff;
+        let mut buf = SessionHeader::new(1, 2).encode().unwrap();
+        let records = [
+            (10, Direction::Rx, frame[..5].to_vec()),
+            (
+                20,
+                Direction::Tx,
+                ProtocolHeader::pack(CmdCode::System(SystemCode::Ping), &[]).unwrap(),
+            ),
+            (30, Direction::Rx, frame[5..].to_vec()),
+            (40, Direction::Rx, unknown.clone()),
+        ];
+        for (time, direction, data) in records {
+            buf.append(
+                &mut SessionRecord {
+                    time,
+                    direction,
+                    data,
+                }
+                .encode()
+                .unwrap(),
+            );
+        }
+        let (pcap, count) = session_to_pcapng(&buf).unwrap();
+        assert_eq!(count, 3);
+        assert_eq!(u32_at(&pcap, 0), BLOCK_SECTION_HEADER);
+        // 遍历各块，长度字段首尾一致
+        let mut offset = 0;
+        let mut packets = vec![];
+        while offset < pcap.len() {
+            let size = u32_at(&pcap, offset + 4) as usize;
+            assert_eq!(size % 4, 0);
+            assert_eq!(u32_at(&pcap, offset + size - 4) as usize, size);
+            if u32_at(&pcap, offset) == BLOCK_ENHANCED_PACKET {
+                let time = u32_at(&pcap, offset + 16);
+                let len = u32_at(&pcap, offset + 20) as usize;
+                packets.push((time, pcap[offset + 28..offset + 28 + len].to_vec()));
+            }
+            offset += size;
+        }
+        assert_eq!(offset, pcap.len());
+        assert_eq!(packets[1], (30, frame));
+        assert_eq!(packets[2], (40, unknown));
+    }
+
+    #[test]
+    fn lua_dissector_test() {
+        let table = cmd_code_table();
+        assert_eq!(table.len(), CMD_CODES.len());
+        assert!(table.contains(&(1, "System".into(), 1, "Pong".into())));
+        let lua = lua_dissector();
+        assert!(!lua.contains('@'));
+        assert!(lua.contains("[\"2:0\"] = \"Info\""));
+        assert!(lua.contains("cawlink.bms.state.afeerror"));
+    }
+}
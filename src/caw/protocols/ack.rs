@@ -0,0 +1,166 @@
+use crate::ui::*;
+
+use bincode::{config, Decode, Encode};
+use slint::Weak;
+use std::time::{Duration, Instant};
+
+use super::{
+    code::{CmdCode, SystemCode},
+    protocol::{FrameBuffer, ProtocolError},
+    timesync::host_time,
+};
+use crate::caw::{
+    devices::device::Device,
+    recorder,
+    telemetry::{self, Kind},
+};
+
+pub const ACK_SIZE: usize = 16;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// 应答状态
+#[derive(Encode, Decode, Eq, PartialEq, Debug, Clone, Copy)]
+pub enum AckStatus {
+    Ok = 0,
+    CrcError,
+    Rejected,
+    Unsupported,
+}
+
+/// 通用应答，设备收到指令后回复执行结果
+#[derive(Encode, Decode, PartialEq, Debug, Clone, Copy)]
+pub struct Ack {
+    cmd_code: CmdCode,
+    status: AckStatus,
+    seq: u32,
+}
+
+impl Ack {
+    pub fn new(cmd_code: CmdCode, status: AckStatus, seq: u32) -> Self {
+        Self {
+            cmd_code,
+            status,
+            seq,
+        }
+    }
+
+    pub fn parse(buf: &[u8]) -> Result<Self> {
+        if buf.len() < ACK_SIZE {
+            return Err(ProtocolError::DataTooShort(buf.len(), ACK_SIZE).into());
+        }
+        let config = config::standard()
+            .with_fixed_int_encoding()
+            .with_big_endian();
+        let (ack, _): (Ack, usize) = bincode::decode_from_slice(&buf[..ACK_SIZE], config)?;
+        Ok(ack)
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let config = config::standard()
+            .with_fixed_int_encoding()
+            .with_big_endian();
+        Ok(bincode::encode_to_vec(self, config)?)
+    }
+
+    pub fn get_cmd_code(&self) -> CmdCode {
+        self.cmd_code
+    }
+
+    pub fn get_status(&self) -> AckStatus {
+        self.status
+    }
+
+    pub fn get_seq(&self) -> u32 {
+        self.seq
+    }
+}
+
+/// 等待指定指令的应答，超时返回None
+///
+/// 调用期间应独占设备，其他数据帧会被丢弃
+pub fn wait_ack(
+    device: &mut Box<dyn Device + Send>,
+    frames: &mut FrameBuffer,
+    code: CmdCode,
+    seq: u32,
+    timeout: Duration,
+) -> Result<Option<Ack>> {
+    let start = Instant::now();
+    while let Some(remain) = timeout.checked_sub(start.elapsed()) {
+        match frames.read_frame(device, remain) {
+            Ok((header, data)) => {
+                if header.get_cmd_code() != CmdCode::System(SystemCode::Ack)
+                    || !header.check_checksum(&data[..])
+                {
+                    continue;
+                }
+                if let Ok(ack) = Ack::parse(&data[..]) {
+                    if ack.cmd_code == code && ack.seq == seq {
+                        return Ok(Some(ack));
+                    }
+                }
+            }
+            Err(e) if e.downcast_ref::<ProtocolError>().is_some() => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(None)
+}
+
+/// 解码并发布应答
+fn publish_ack(device: &mut Box<dyn Device + Send>, buf: &[u8]) -> Option<Ack> {
+    let ack = Ack::parse(buf).ok()?;
+    let (device_id, type_id) = device.get_id();
+    let header: Vec<String> = ["time", "device_id", "command", "status", "seq"]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+    let time = host_time();
+    let row = vec![
+        recorder::format_time(time).into(),
+        device_id.into(),
+        format!("{:?}", ack.cmd_code).into(),
+        format!("{:?}", ack.status).into(),
+        ack.seq.into(),
+    ];
+    telemetry::publish(Kind::Ack, type_id, device_id, time, &header, &row);
+    Some(ack)
+}
+
+/// 无界面运行时只发布应答
+pub fn ack_event(device: &mut Box<dyn Device + Send>, buf: Option<&[u8]>, _ui: &Weak<AppWindow>) {
+    if let Some(buf) = buf {
+        publish_ack(device, buf);
+    }
+}
+
+pub fn ack_protocol(device: &mut Box<dyn Device + Send>, buf: Option<&[u8]>, ui: &Weak<AppWindow>) {
+    if let Some(ack) = buf.and_then(|buf| publish_ack(device, buf)) {
+        let (device_id, _) = device.get_id();
+        let message = format!(
+            "device-id: {} {:?} -> {:?}",
+            device_id, ack.cmd_code, ack.status
+        );
+        eprintln!("{}", message);
+        let _ = ui.upgrade_in_event_loop(move |handle| {
+            handle
+                .global::<DeviceModelService>()
+                .set_message(message.into());
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ack_size_test() {
+        let ack = Ack::new(CmdCode::System(SystemCode::Reboot), AckStatus::Ok, 0);
+        let encode = ack.encode().unwrap();
+        assert_eq!(encode.len(), ACK_SIZE);
+        assert_eq!(Ack::parse(&encode[..]).unwrap(), ack);
+        assert!(Ack::parse(&encode[..ACK_SIZE - 1]).is_err());
+    }
+}
@@ -1,19 +1,68 @@
-use crate::ui::*;
-
 use bincode::{
     config::{self},
     Decode, Encode,
 };
-use slint::*;
 
-use crate::caw::devices::device::Device;
+use super::timesync;
+use crate::caw::{metrics::BmsGauges, recorder};
 
+/// 旧版固定5串数据帧的大小
 pub const BMS_INFO_SIZE: usize = 48;
+/// 支持的最大串数
+pub const MAX_CELL_COUNT: usize = 32;
+/// 支持的最大温度传感器数
+pub const MAX_TEMP_SENSOR_COUNT: usize = 16;
+
+/// BMS状态标志位，第n项对应state的第n位
+pub const BMS_STATES: [&str; 8] = [
+    "OverVoltage",
+    "UnderVoltage",
+    "OverCurrent",
+    "ChargeOverCurrent",
+    "ShortCircuit",
+    "OverTemperature",
+    "UnderTemperature",
+    "AfeError",
+];
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
+#[derive(Debug, Clone)]
+pub enum BMSError {
+    CellCount(u8),
+    SensorCount(u8),
+    SensorKind(u8),
+}
+
+impl std::fmt::Display for BMSError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            BMSError::CellCount(count) => {
+                write!(f, "invalid cell count: {}", count)
+            }
+            BMSError::SensorCount(count) => {
+                write!(f, "invalid temperature sensor count: {}", count)
+            }
+            BMSError::SensorKind(kind) => {
+                write!(f, "invalid temperature sensor kind: {}", kind)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BMSError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            BMSError::CellCount(_) => None,
+            BMSError::SensorCount(_) => None,
+            BMSError::SensorKind(_) => None,
+        }
+    }
+}
+
+/// 旧版固定5串的数据帧
 #[derive(Encode, Decode, PartialEq, Debug)]
-pub struct BMSInfo {
+struct BMSInfoV1 {
     state: u8,
     cell_voltage: [i32; 5],
     balance: [u8; 5],
@@ -26,12 +75,114 @@ pub struct BMSInfo {
     chg: u8,
 }
 
+/// 数据帧中电芯数据之后的部分
+#[derive(Encode, Decode, PartialEq, Debug)]
+struct BMSInfoTail {
+    voltage: i32,
+    current: i32,
+    temperature: i32,
+    soc: i32,
+    soh: i32,
+    dsg: u8,
+    chg: u8,
+}
+
+/// 温度传感器类型
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TempSensorKind {
+    Cell = 0,
+    Fet,
+    Ambient,
+}
+
+impl TryFrom<u8> for TempSensorKind {
+    type Error = BMSError;
+
+    fn try_from(kind: u8) -> std::result::Result<Self, Self::Error> {
+        match kind {
+            0 => Ok(TempSensorKind::Cell),
+            1 => Ok(TempSensorKind::Fet),
+            2 => Ok(TempSensorKind::Ambient),
+            _ => Err(BMSError::SensorKind(kind)),
+        }
+    }
+}
+
+impl TempSensorKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TempSensorKind::Cell => "Cell",
+            TempSensorKind::Fet => "FET",
+            TempSensorKind::Ambient => "Ambient",
+        }
+    }
+
+    /// 告警温度范围(x100)
+    pub fn limits(&self) -> (i32, i32) {
+        match self {
+            TempSensorKind::Cell => (-2000, 5500),
+            TempSensorKind::Fet => (-2000, 8500),
+            TempSensorKind::Ambient => (-3000, 6000),
+        }
+    }
+}
+
+/// 温度传感器，温度x100
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TempSensor {
+    kind: TempSensorKind,
+    temperature: i32,
+}
+
+impl TempSensor {
+    pub fn new(kind: TempSensorKind, temperature: i32) -> Self {
+        Self { kind, temperature }
+    }
+
+    pub fn get_kind(&self) -> TempSensorKind {
+        self.kind
+    }
+
+    pub fn get_temperature(&self) -> i32 {
+        self.temperature
+    }
+
+    /// 温度超出该类型传感器的告警范围
+    pub fn is_alarm(&self) -> bool {
+        let (low, high) = self.kind.limits();
+        self.temperature < low || self.temperature > high
+    }
+}
+
+/// BMS信息
+///
+/// 数据帧格式：state(u8) cell_count(u8) cell_voltage(i32 * n) balance(u8 * n)
+/// voltage(i32) current(i32) temperature(i32) soc(i32) soh(i32) dsg(u8) chg(u8)
+/// [sensor_count(u8) (kind(u8) temperature(i32)) * m [device_tick(u64)]]，
+/// 温度传感器部分可省略，device_tick为发送时的设备计数(us)，附带时m可为0，
+/// 长度为BMS_INFO_SIZE时按旧版固定5串解析
+#[derive(PartialEq, Debug, Clone)]
+pub struct BMSInfo {
+    pub state: u8,
+    pub cell_voltage: Vec<i32>,
+    pub balance: Vec<u8>,
+    pub voltage: i32,
+    pub current: i32,
+    pub temperature: i32,
+    pub soc: i32,
+    pub soh: i32,
+    pub dsg: u8,
+    pub chg: u8,
+    pub temp_sensors: Vec<TempSensor>,
+    pub device_tick: Option<u64>,
+}
+
 impl Default for BMSInfo {
     fn default() -> Self {
         Self {
             state: 0,
-            cell_voltage: [0, 0, 0, 0, 0],
-            balance: [0, 0, 0, 0, 0],
+            cell_voltage: vec![0, 0, 0, 0, 0],
+            balance: vec![0, 0, 0, 0, 0],
             voltage: 0,
             current: 0,
             temperature: 0,
@@ -39,52 +190,258 @@ impl Default for BMSInfo {
             soh: 0,
             dsg: 0,
             chg: 0,
+            temp_sensors: vec![],
+            device_tick: None,
         }
     }
 }
 
+impl From<BMSInfoV1> for BMSInfo {
+    fn from(v: BMSInfoV1) -> Self {
+        Self {
+            state: v.state,
+            cell_voltage: v.cell_voltage.to_vec(),
+            balance: v.balance.to_vec(),
+            voltage: v.voltage,
+            current: v.current,
+            temperature: v.temperature,
+            soc: v.soc,
+            soh: v.soh,
+            dsg: v.dsg,
+            chg: v.chg,
+            temp_sensors: vec![],
+            device_tick: None,
+        }
+    }
+}
+
+/// 按串数与温度传感器数计算数据帧大小
+pub fn bms_info_size(cell_count: usize, sensor_count: usize) -> usize {
+    let size = 2 + cell_count * 5 + 22;
+    if sensor_count > 0 {
+        size + 1 + sensor_count * 5
+    } else {
+        size
+    }
+}
+
+fn decode<T: Decode>(buf: &[u8], offset: &mut usize) -> Result<T> {
+    let config = config::standard()
+        .with_fixed_int_encoding()
+        .with_big_endian();
+    let (v, size): (T, usize) = bincode::decode_from_slice(&buf[*offset..], config)?;
+    *offset += size;
+    Ok(v)
+}
+
 impl BMSInfo {
     pub fn parse(buf: &[u8]) -> Result<Self> {
         let config = config::standard()
             .with_fixed_int_encoding()
             .with_big_endian();
-        let (info, _): (BMSInfo, usize) =
-            bincode::decode_from_slice(&buf[..BMS_INFO_SIZE], config)?;
-        Ok(info)
-    }
-}
-
-pub fn bms_info_protocol(
-    device: &mut Box<dyn Device + Send>,
-    buf: Option<&[u8]>,
-    ui: &Weak<AppWindow>,
-) {
-    if let Some(buf) = buf {
-        if let Ok(bms_info) = BMSInfo::parse(buf) {
-            let _ = ui.upgrade_in_event_loop(move |handle| {
-                let service = handle.global::<BMSModelService>();
-                let balance: Vec<i32> = bms_info.balance.iter().map(|&x| x as i32).collect();
-                let cell_voltage: Vec<f32> = bms_info
-                    .cell_voltage
-                    .iter()
-                    .map(|&x| x as f32 / 100.0)
-                    .collect();
-                service.set_bms_info(BMSInfoModel {
-                    balance: VecModel::from_slice(balance.as_slice()),
-                    cell_voltage: VecModel::from_slice(cell_voltage.as_slice()),
-                    chg: bms_info.chg != 0,
-                    current: bms_info.current as f32 / 100.0,
-                    dsg: bms_info.dsg != 0,
-                    soc: bms_info.soc as f32 / 100.0,
-                    soh: bms_info.soh as f32 / 100.0,
-                    state: VecModel::from_slice([0, 0, 0, 0, 0, 0, 0, 0].as_slice()),
-                    temperature: bms_info.temperature as f32 / 100.0,
-                    voltage: bms_info.voltage as f32 / 100.0,
-                });
-            });
-        }
-
-        // println!("{:?}", bms_info);
+        if buf.len() == BMS_INFO_SIZE {
+            let (info, _): (BMSInfoV1, usize) = bincode::decode_from_slice(buf, config)?;
+            return Ok(info.into());
+        }
+        let mut offset = 0usize;
+        let state: u8 = decode(buf, &mut offset)?;
+        let cell_count: u8 = decode(buf, &mut offset)?;
+        if cell_count == 0 || cell_count as usize > MAX_CELL_COUNT {
+            return Err(BMSError::CellCount(cell_count).into());
+        }
+        let cell_voltage = (0..cell_count)
+            .map(|_| decode::<i32>(buf, &mut offset))
+            .collect::<Result<Vec<i32>>>()?;
+        let balance = (0..cell_count)
+            .map(|_| decode::<u8>(buf, &mut offset))
+            .collect::<Result<Vec<u8>>>()?;
+        let tail: BMSInfoTail = decode(buf, &mut offset)?;
+        let mut temp_sensors = vec![];
+        if offset < buf.len() {
+            let sensor_count: u8 = decode(buf, &mut offset)?;
+            if sensor_count as usize > MAX_TEMP_SENSOR_COUNT {
+                return Err(BMSError::SensorCount(sensor_count).into());
+            }
+            for _ in 0..sensor_count {
+                let kind: u8 = decode(buf, &mut offset)?;
+                let temperature: i32 = decode(buf, &mut offset)?;
+                temp_sensors.push(TempSensor::new(kind.try_into()?, temperature));
+            }
+        }
+        let device_tick = timesync::device_tick(buf, offset)?;
+        Ok(Self {
+            state,
+            cell_voltage,
+            balance,
+            voltage: tail.voltage,
+            current: tail.current,
+            temperature: tail.temperature,
+            soc: tail.soc,
+            soh: tail.soh,
+            dsg: tail.dsg,
+            chg: tail.chg,
+            temp_sensors,
+            device_tick,
+        })
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let config = config::standard()
+            .with_fixed_int_encoding()
+            .with_big_endian();
+        let mut buf = vec![self.state, self.cell_voltage.len() as u8];
+        for v in self.cell_voltage.iter() {
+            buf.append(&mut bincode::encode_to_vec(v, config)?);
+        }
+        buf.extend_from_slice(&self.balance[..]);
+        let tail = BMSInfoTail {
+            voltage: self.voltage,
+            current: self.current,
+            temperature: self.temperature,
+            soc: self.soc,
+            soh: self.soh,
+            dsg: self.dsg,
+            chg: self.chg,
+        };
+        buf.append(&mut bincode::encode_to_vec(&tail, config)?);
+        if !self.temp_sensors.is_empty() || self.device_tick.is_some() {
+            buf.push(self.temp_sensors.len() as u8);
+            for sensor in self.temp_sensors.iter() {
+                buf.push(sensor.kind as u8);
+                buf.append(&mut bincode::encode_to_vec(sensor.temperature, config)?);
+            }
+        }
+        if let Some(tick) = self.device_tick {
+            buf.append(&mut bincode::encode_to_vec(tick, config)?);
+        }
+        Ok(buf)
+    }
+
+    /// 导出到Prometheus的遥测数据
+    pub fn gauges(&self) -> BmsGauges {
+        BmsGauges {
+            cell_voltage: self.get_cell_voltage(),
+            voltage: self.voltage as f32 / 100.0,
+            current: self.current as f32 / 100.0,
+            temperature: self.max_temperature() as f32 / 100.0,
+            soc: self.soc as f32 / 100.0,
+            soh: self.soh as f32 / 100.0,
+        }
+    }
+
+    /// 发送时的设备计数，单位us，固件未附带时为None
+    pub fn get_device_tick(&self) -> Option<u64> {
+        self.device_tick
+    }
+
+    pub fn get_cell_count(&self) -> usize {
+        self.cell_voltage.len()
+    }
+
+    /// 各电芯电压(V)
+    pub fn get_cell_voltage(&self) -> Vec<f32> {
+        self.cell_voltage
+            .iter()
+            .map(|&v| v as f32 / 100.0)
+            .collect()
+    }
+
+    pub fn get_temp_sensors(&self) -> &[TempSensor] {
+        &self.temp_sensors
+    }
+
+    /// 温度传感器名称，同类型有多个时按序编号
+    pub fn temp_sensor_names(&self) -> Vec<String> {
+        self.temp_sensors
+            .iter()
+            .enumerate()
+            .map(|(i, sensor)| {
+                let kind = sensor.kind;
+                let same = self.temp_sensors.iter().filter(|s| s.kind == kind).count();
+                if same > 1 {
+                    let number = self.temp_sensors[..i]
+                        .iter()
+                        .filter(|s| s.kind == kind)
+                        .count()
+                        + 1;
+                    std::format!("{} {}", kind.name(), number)
+                } else {
+                    kind.name().to_string()
+                }
+            })
+            .collect()
+    }
+
+    /// 最高温度，无温度传感器时为temperature
+    pub fn max_temperature(&self) -> i32 {
+        self.temp_sensors
+            .iter()
+            .map(|s| s.temperature)
+            .max()
+            .unwrap_or(self.temperature)
+    }
+
+    /// 任一温度传感器超出告警范围
+    pub fn is_temperature_alarm(&self) -> bool {
+        self.temp_sensors.iter().any(|s| s.is_alarm())
+    }
+
+    /// 按状态标志位展开，每一位对应BMS_STATES中的一项
+    pub fn states(&self) -> Vec<i32> {
+        (0..BMS_STATES.len())
+            .map(|i| ((self.state >> i) & 1) as i32)
+            .collect()
+    }
+
+    /// CSV表头，电芯电压与均衡标志交替排列，其后为各温度传感器
+    pub fn csv_header(&self) -> Vec<String> {
+        let mut header: Vec<String> = [
+            "time",
+            "device_id",
+            "voltage",
+            "current",
+            "temperature",
+            "soc",
+            "soh",
+            "state",
+            "dsg",
+            "chg",
+        ]
+        .iter()
+        .map(|&s| s.into())
+        .collect();
+        for i in 1..=self.get_cell_count() {
+            header.push(std::format!("cell_{}", i));
+            header.push(std::format!("balance_{}", i));
+        }
+        for i in 1..=self.temp_sensors.len() {
+            header.push(std::format!("temp_{}", i));
+        }
+        header
+    }
+
+    /// 数据行，时间为字符串，其余为数值
+    pub fn csv_row(&self, time: u64, device_id: u32) -> Vec<serde_json::Value> {
+        let mut row = vec![
+            recorder::format_time(time).into(),
+            device_id.into(),
+            recorder::number(self.voltage as f32 / 100.0),
+            recorder::number(self.current as f32 / 100.0),
+            recorder::number(self.temperature as f32 / 100.0),
+            recorder::number(self.soc as f32 / 100.0),
+            recorder::number(self.soh as f32 / 100.0),
+            self.state.into(),
+            self.dsg.into(),
+            self.chg.into(),
+        ];
+        for (v, b) in self.cell_voltage.iter().zip(self.balance.iter()) {
+            row.push(recorder::number(*v as f32 / 100.0));
+            row.push((*b).into());
+        }
+        for sensor in self.temp_sensors.iter() {
+            row.push(recorder::number(sensor.temperature as f32 / 100.0));
+        }
+        row
     }
 }
 
@@ -97,8 +454,121 @@ mod tests {
         let config = config::standard()
             .with_fixed_int_encoding()
             .with_big_endian();
-        let p = BMSInfo::default();
+        let p = BMSInfoV1 {
+            state: 1,
+            cell_voltage: [370, 371, 372, 373, 374],
+            balance: [0, 1, 0, 0, 0],
+            voltage: 1860,
+            current: -150,
+            temperature: 2500,
+            soc: 8000,
+            soh: 9900,
+            dsg: 1,
+            chg: 0,
+        };
         let encode: Vec<u8> = bincode::encode_to_vec(&p, config).unwrap();
         assert_eq!(encode.len(), BMS_INFO_SIZE);
+        let info = BMSInfo::parse(&encode[..]).unwrap();
+        assert_eq!(info, BMSInfo::from(p));
+    }
+
+    #[test]
+    fn bms_info_cell_count_test() {
+        for count in [4usize, 13, 16] {
+            let p = BMSInfo {
+                cell_voltage: (0..count as i32).map(|x| 360 + x).collect(),
+                balance: (0..count as u8).map(|x| x % 2).collect(),
+                voltage: 4800,
+                ..Default::default()
+            };
+            let encode = p.encode().unwrap();
+            assert_eq!(encode.len(), bms_info_size(count, 0));
+            assert_eq!(BMSInfo::parse(&encode[..]).unwrap(), p);
+            assert!(BMSInfo::parse(&encode[..encode.len() - 1]).is_err());
+        }
+        assert!(BMSInfo::parse(&[0, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn bms_info_temp_sensor_test() {
+        let p = BMSInfo {
+            temperature: 2500,
+            temp_sensors: vec![
+                TempSensor::new(TempSensorKind::Cell, 2500),
+                TempSensor::new(TempSensorKind::Cell, 2650),
+                TempSensor::new(TempSensorKind::Fet, 9000),
+                TempSensor::new(TempSensorKind::Ambient, -500),
+            ],
+            ..Default::default()
+        };
+        let encode = p.encode().unwrap();
+        assert_eq!(encode.len(), bms_info_size(5, 4));
+        assert_eq!(BMSInfo::parse(&encode[..]).unwrap(), p);
+        assert!(BMSInfo::parse(&encode[..encode.len() - 1]).is_err());
+        assert_eq!(
+            p.temp_sensor_names(),
+            vec!["Cell 1", "Cell 2", "FET", "Ambient"]
+        );
+        assert_eq!(p.max_temperature(), 9000);
+        assert!(p.is_temperature_alarm());
+
+        let mut invalid = encode.clone();
+        invalid[bms_info_size(5, 0) + 1] = 3;
+        assert!(BMSInfo::parse(&invalid[..]).is_err());
+    }
+
+    #[test]
+    fn bms_info_device_tick_test() {
+        // 附带设备计数时温度传感器部分不可省略
+        let p = BMSInfo {
+            device_tick: Some(123_456),
+            ..Default::default()
+        };
+        let encode = p.encode().unwrap();
+        assert_eq!(encode.len(), bms_info_size(5, 0) + 1 + 8);
+        assert_eq!(BMSInfo::parse(&encode[..]).unwrap(), p);
+        assert!(BMSInfo::parse(&encode[..encode.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn bms_states_test() {
+        let p = BMSInfo {
+            state: 0b1001_0001,
+            ..Default::default()
+        };
+        assert_eq!(p.states(), vec![1, 0, 0, 0, 1, 0, 0, 1]);
+    }
+
+    #[test]
+    fn bms_info_csv_test() {
+        let p = BMSInfo {
+            cell_voltage: vec![370, 365],
+            balance: vec![0, 1],
+            voltage: 735,
+            current: -150,
+            chg: 1,
+            temp_sensors: vec![
+                TempSensor::new(TempSensorKind::Cell, 2510),
+                TempSensor::new(TempSensorKind::Fet, 4000),
+            ],
+            ..Default::default()
+        };
+        let header = p.csv_header();
+        let row = p.csv_row(0, 3);
+        assert_eq!(header.len(), row.len());
+        assert_eq!(
+            header[10..],
+            [
+                "cell_1",
+                "balance_1",
+                "cell_2",
+                "balance_2",
+                "temp_1",
+                "temp_2"
+            ]
+        );
+        let row: Vec<String> = row.iter().map(recorder::csv_field).collect();
+        assert_eq!(row[1..4], ["3", "7.35", "-1.5"]);
+        assert_eq!(row[10..], ["3.7", "0", "3.65", "1", "25.1", "40"]);
     }
 }
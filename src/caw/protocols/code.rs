@@ -1,4 +1,4 @@
-use bincode::{Decode, Encode};
+use bincode::{config, Decode, Encode};
 
 #[derive(Encode, Decode, Eq, PartialEq, Debug, Clone, Copy, Hash)]
 pub enum CmdCode {
@@ -8,6 +8,35 @@ pub enum CmdCode {
     Motor(MotorCode),
 }
 
+impl CmdCode {
+    /// 指令分组与组内编号，与数据帧中的编码一致
+    pub fn to_raw(self) -> (u32, u32) {
+        let config = config::standard()
+            .with_fixed_int_encoding()
+            .with_big_endian();
+        let buf = bincode::encode_to_vec(self, config).unwrap_or_default();
+        match buf.get(0..8) {
+            Some(buf) => (
+                u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]),
+                u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]),
+            ),
+            None => (0, 0),
+        }
+    }
+
+    /// 由分组与组内编号解析指令，无法识别时返回None
+    pub fn from_raw(group: u32, code: u32) -> Option<Self> {
+        let config = config::standard()
+            .with_fixed_int_encoding()
+            .with_big_endian();
+        let mut buf = group.to_be_bytes().to_vec();
+        buf.extend_from_slice(&code.to_be_bytes());
+        bincode::decode_from_slice(&buf, config)
+            .ok()
+            .map(|(code, _)| code)
+    }
+}
+
 /// 其他指令
 #[derive(Encode, Decode, Eq, PartialEq, Debug, Clone, Copy, Hash)]
 pub enum OtherCode {
@@ -20,6 +49,15 @@ pub enum SystemCode {
     Ping = 0,
     Pong,
     Log,
+    Ack,
+    Reboot,
+    Bootloader,
+    FirmwareChunk,
+    FirmwareVerify,
+    TimeSync,
+    FactoryReset,
+    Identify,
+    Provision,
 }
 
 /// 电源管理系统指令
@@ -30,4 +68,53 @@ pub enum BMSCode {
 
 /// 电机指令
 #[derive(Encode, Decode, Eq, PartialEq, Debug, Clone, Copy, Hash)]
-pub enum MotorCode {}
+pub enum MotorCode {
+    Info = 0,
+    Enable,
+    SetSpeed,
+    SetTorque,
+    SetPosition,
+    Stop,
+    ClearFault,
+}
+
+/// 全部指令，新增指令时需同步加入
+pub const CMD_CODES: [CmdCode; 21] = [
+    CmdCode::Other(OtherCode::Unknown),
+    CmdCode::System(SystemCode::Ping),
+    CmdCode::System(SystemCode::Pong),
+    CmdCode::System(SystemCode::Log),
+    CmdCode::System(SystemCode::Ack),
+    CmdCode::System(SystemCode::Reboot),
+    CmdCode::System(SystemCode::Bootloader),
+    CmdCode::System(SystemCode::FirmwareChunk),
+    CmdCode::System(SystemCode::FirmwareVerify),
+    CmdCode::System(SystemCode::TimeSync),
+    CmdCode::System(SystemCode::FactoryReset),
+    CmdCode::System(SystemCode::Identify),
+    CmdCode::System(SystemCode::Provision),
+    CmdCode::BMS(BMSCode::Info),
+    CmdCode::Motor(MotorCode::Info),
+    CmdCode::Motor(MotorCode::Enable),
+    CmdCode::Motor(MotorCode::SetSpeed),
+    CmdCode::Motor(MotorCode::SetTorque),
+    CmdCode::Motor(MotorCode::SetPosition),
+    CmdCode::Motor(MotorCode::Stop),
+    CmdCode::Motor(MotorCode::ClearFault),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raw_code_test() {
+        for code in CMD_CODES {
+            let (group, index) = code.to_raw();
+            assert_eq!(CmdCode::from_raw(group, index), Some(code));
+        }
+        assert_eq!(CmdCode::System(SystemCode::Pong).to_raw(), (1, 1));
+        assert_eq!(CmdCode::from_raw(1, 0x7f), None);
+        assert_eq!(CmdCode::from_raw(9, 0), None);
+    }
+}
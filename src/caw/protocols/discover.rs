@@ -2,15 +2,26 @@ use bincode::{config, Decode, Encode};
 
 pub const DISCOVER_MAGIC: [u8; 4] = [0xff, 0xff, 0xff, 0x00];
 pub const DEVICE_MAGIC: [u8; 4] = [0xff, 0xff, 0xff, 0x01];
+/// 设备发现使用的波特率
+pub const DISCOVER_BAUD_RATE: u32 = 128000;
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
 #[derive(Encode, Decode, PartialEq, Debug, Clone, Copy)]
-enum TypeId {
+pub enum TypeId {
     BMS = 0,
     Motor,
 }
 
+/// 设备类型名称
+pub fn type_name(type_id: u32) -> String {
+    match type_id {
+        id if id == TypeId::BMS as u32 => "BMS".into(),
+        id if id == TypeId::Motor as u32 => "Motor".into(),
+        id => format!("Unknown({})", id),
+    }
+}
+
 #[derive(Encode, Decode, PartialEq, Debug)]
 pub struct Discover {
     device_id: u32,
@@ -27,7 +38,6 @@ impl Discover {
     }
 
     pub fn parse(buf: &[u8]) -> Result<Self> {
-        println!("{:?}", buf);
         let config = config::standard()
             .with_fixed_int_encoding()
             .with_big_endian();
@@ -0,0 +1,149 @@
+use bincode::{config, Decode, Encode};
+
+use super::{
+    code::{CmdCode, SystemCode},
+    protocol::{ProtocolError, ProtocolHeader},
+};
+use crate::caw::{devices::device::Device, utils::crypto::crc32_slice};
+
+pub const FIRMWARE_INFO_SIZE: usize = 12;
+pub const FIRMWARE_CHUNK_HEADER_SIZE: usize = 8;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// 固件镜像信息，进入Bootloader及校验镜像时发送
+#[derive(Encode, Decode, PartialEq, Debug, Clone, Copy)]
+pub struct FirmwareInfo {
+    size: u32,
+    chunk_size: u32,
+    crc: u32, // 整个镜像的CRC32校验和
+}
+
+impl FirmwareInfo {
+    pub fn new(image: &[u8], chunk_size: u32) -> Self {
+        Self {
+            size: image.len() as u32,
+            chunk_size,
+            crc: crc32_slice(image),
+        }
+    }
+
+    pub fn parse(buf: &[u8]) -> Result<Self> {
+        if buf.len() < FIRMWARE_INFO_SIZE {
+            return Err(ProtocolError::DataTooShort(buf.len(), FIRMWARE_INFO_SIZE).into());
+        }
+        let config = config::standard()
+            .with_fixed_int_encoding()
+            .with_big_endian();
+        let (info, _): (FirmwareInfo, usize) =
+            bincode::decode_from_slice(&buf[..FIRMWARE_INFO_SIZE], config)?;
+        Ok(info)
+    }
+
+    pub fn get_size(&self) -> u32 {
+        self.size
+    }
+
+    pub fn get_chunk_size(&self) -> u32 {
+        self.chunk_size
+    }
+
+    pub fn get_crc(&self) -> u32 {
+        self.crc
+    }
+}
+
+/// 固件分块头，紧随其后的是分块数据
+#[derive(Encode, Decode, PartialEq, Debug, Clone, Copy)]
+pub struct FirmwareChunkHeader {
+    seq: u32,
+    crc: u32, // 分块数据的CRC32校验和
+}
+
+impl FirmwareChunkHeader {
+    pub fn new(seq: u32, data: &[u8]) -> Self {
+        Self {
+            seq,
+            crc: crc32_slice(data),
+        }
+    }
+
+    pub fn parse(buf: &[u8]) -> Result<Self> {
+        if buf.len() < FIRMWARE_CHUNK_HEADER_SIZE {
+            return Err(ProtocolError::DataTooShort(buf.len(), FIRMWARE_CHUNK_HEADER_SIZE).into());
+        }
+        let config = config::standard()
+            .with_fixed_int_encoding()
+            .with_big_endian();
+        let (header, _): (FirmwareChunkHeader, usize) =
+            bincode::decode_from_slice(&buf[..FIRMWARE_CHUNK_HEADER_SIZE], config)?;
+        Ok(header)
+    }
+
+    pub fn get_seq(&self) -> u32 {
+        self.seq
+    }
+
+    /// 校验分块数据的CRC32
+    pub fn check(&self, data: &[u8]) -> bool {
+        self.crc == crc32_slice(data)
+    }
+}
+
+/// 进入Bootloader并告知固件镜像信息
+pub fn enter_bootloader(device: &mut Box<dyn Device + Send>, info: &FirmwareInfo) -> Result<()> {
+    let config = config::standard()
+        .with_fixed_int_encoding()
+        .with_big_endian();
+    let buf: Vec<u8> = bincode::encode_to_vec(info, config)?;
+    ProtocolHeader::write_data(device, CmdCode::System(SystemCode::Bootloader), &buf[..])
+}
+
+/// 写入固件分块
+pub fn write_chunk(device: &mut Box<dyn Device + Send>, seq: u32, data: &[u8]) -> Result<()> {
+    let config = config::standard()
+        .with_fixed_int_encoding()
+        .with_big_endian();
+    let mut buf: Vec<u8> = bincode::encode_to_vec(FirmwareChunkHeader::new(seq, data), config)?;
+    buf.extend_from_slice(data);
+    ProtocolHeader::write_data(device, CmdCode::System(SystemCode::FirmwareChunk), &buf[..])
+}
+
+/// 请求设备校验已写入的固件镜像
+pub fn verify(device: &mut Box<dyn Device + Send>, info: &FirmwareInfo) -> Result<()> {
+    let config = config::standard()
+        .with_fixed_int_encoding()
+        .with_big_endian();
+    let buf: Vec<u8> = bincode::encode_to_vec(info, config)?;
+    ProtocolHeader::write_data(
+        device,
+        CmdCode::System(SystemCode::FirmwareVerify),
+        &buf[..],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn firmware_size_test() {
+        let config = config::standard()
+            .with_fixed_int_encoding()
+            .with_big_endian();
+        let info = FirmwareInfo::new(&[0u8; 100][..], 64);
+        let encode: Vec<u8> = bincode::encode_to_vec(info, config).unwrap();
+        assert_eq!(encode.len(), FIRMWARE_INFO_SIZE);
+        assert_eq!(FirmwareInfo::parse(&encode[..]).unwrap(), info);
+        assert!(FirmwareInfo::parse(&encode[..FIRMWARE_INFO_SIZE - 1]).is_err());
+
+        let data = [1u8, 2, 3];
+        let header = FirmwareChunkHeader::new(1, &data[..]);
+        let encode: Vec<u8> = bincode::encode_to_vec(header, config).unwrap();
+        assert_eq!(encode.len(), FIRMWARE_CHUNK_HEADER_SIZE);
+        assert!(FirmwareChunkHeader::parse(&encode[..])
+            .unwrap()
+            .check(&data[..]));
+        assert!(FirmwareChunkHeader::parse(&encode[..4]).is_err());
+    }
+}
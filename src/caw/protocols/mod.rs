@@ -1,5 +1,10 @@
+pub mod ack;
 pub mod bms;
 pub mod code;
 pub mod discover;
+pub mod firmware;
+pub mod motor;
 pub mod pingpong;
 pub mod protocol;
+pub mod system;
+pub mod timesync;
@@ -0,0 +1,345 @@
+use crate::ui::*;
+
+use bincode::{
+    config::{self},
+    Decode, Encode,
+};
+use lazy_static::lazy_static;
+use slint::*;
+use std::{collections::HashMap, sync::Mutex};
+
+use super::timesync;
+use super::{
+    code::{CmdCode, MotorCode},
+    protocol::{ProtocolError, ProtocolHeader},
+};
+use crate::caw::{
+    alarm::{self, AlarmField},
+    clock,
+    devices::device::Device,
+    recorder,
+    telemetry::{self, Kind},
+};
+
+pub const MOTOR_INFO_SIZE: usize = 34;
+
+lazy_static! {
+    /// 各电机最近一次上报的数据，以(type_id, device_id)为键
+    static ref MOTOR_TELEMETRY: Mutex<HashMap<(u32, u32), MotorInfo>> =
+        Mutex::new(HashMap::new());
+}
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// 电机故障标志位
+pub const MOTOR_FAULTS: [(u32, &str); 7] = [
+    (1 << 0, "OverCurrent"),
+    (1 << 1, "OverVoltage"),
+    (1 << 2, "UnderVoltage"),
+    (1 << 3, "OverTemperature"),
+    (1 << 4, "Stall"),
+    (1 << 5, "EncoderError"),
+    (1 << 6, "PhaseLoss"),
+];
+
+/// 电机信息
+///
+/// 数据帧末尾可附带发送时的设备计数device_tick(u64)，单位us
+#[derive(Encode, Decode, PartialEq, Debug, Clone)]
+pub struct MotorInfo {
+    fault: u32,
+    enable: u8,
+    mode: u8,                // 0:空闲 1:速度 2:力矩 3:位置
+    speed: i32,              // 0.01rpm
+    position: i32,           // 0.01deg
+    phase_current: [i32; 3], // 0.01A
+    bus_voltage: i32,        // 0.01V
+    temperature: i32,        // 0.01℃
+}
+
+impl Default for MotorInfo {
+    fn default() -> Self {
+        Self {
+            fault: 0,
+            enable: 0,
+            mode: 0,
+            speed: 0,
+            position: 0,
+            phase_current: [0, 0, 0],
+            bus_voltage: 0,
+            temperature: 0,
+        }
+    }
+}
+
+impl MotorInfo {
+    pub fn parse(buf: &[u8]) -> Result<Self> {
+        if buf.len() < MOTOR_INFO_SIZE {
+            return Err(ProtocolError::DataTooShort(buf.len(), MOTOR_INFO_SIZE).into());
+        }
+        let config = config::standard()
+            .with_fixed_int_encoding()
+            .with_big_endian();
+        let (info, _): (MotorInfo, usize) =
+            bincode::decode_from_slice(&buf[..MOTOR_INFO_SIZE], config)?;
+        Ok(info)
+    }
+
+    /// 按故障标志位展开，每一位对应MOTOR_FAULTS中的一项
+    pub fn faults(&self) -> Vec<bool> {
+        MOTOR_FAULTS
+            .iter()
+            .map(|(bit, _)| self.fault & bit != 0)
+            .collect()
+    }
+
+    /// CSV表头，与csv_row一一对应
+    pub fn csv_header(&self) -> Vec<String> {
+        [
+            "time",
+            "device_id",
+            "enable",
+            "mode",
+            "speed",
+            "position",
+            "phase_current_a",
+            "phase_current_b",
+            "phase_current_c",
+            "bus_voltage",
+            "temperature",
+            "fault",
+        ]
+        .iter()
+        .map(|&s| s.into())
+        .collect()
+    }
+
+    /// 数据行，时间为字符串，其余为数值
+    pub fn csv_row(&self, time: u64, device_id: u32) -> Vec<serde_json::Value> {
+        let mut row = vec![
+            recorder::format_time(time).into(),
+            device_id.into(),
+            self.enable.into(),
+            self.mode.into(),
+            recorder::number(self.speed as f32 / 100.0),
+            recorder::number(self.position as f32 / 100.0),
+        ];
+        for current in self.phase_current {
+            row.push(recorder::number(current as f32 / 100.0));
+        }
+        row.push(recorder::number(self.bus_voltage as f32 / 100.0));
+        row.push(recorder::number(self.temperature as f32 / 100.0));
+        row.push(self.fault.into());
+        row
+    }
+}
+
+/// 设定值，数值放大100倍传输
+#[derive(Encode, Decode, PartialEq, Debug)]
+struct MotorSetpoint {
+    value: i32,
+}
+
+fn write_setpoint(device: &mut Box<dyn Device + Send>, code: MotorCode, value: f32) -> Result<()> {
+    let config = config::standard()
+        .with_fixed_int_encoding()
+        .with_big_endian();
+    let setpoint = MotorSetpoint {
+        value: (value * 100.0).round() as i32,
+    };
+    let buf: Vec<u8> = bincode::encode_to_vec(&setpoint, config)?;
+    ProtocolHeader::write_data(device, CmdCode::Motor(code), &buf[..])
+}
+
+/// 使能/失能电机
+pub fn enable(device: &mut Box<dyn Device + Send>, enable: bool) -> Result<()> {
+    ProtocolHeader::write_data(device, CmdCode::Motor(MotorCode::Enable), &[enable as u8])
+}
+
+/// 速度模式，设定转速（rpm）
+pub fn set_speed(device: &mut Box<dyn Device + Send>, rpm: f32) -> Result<()> {
+    write_setpoint(device, MotorCode::SetSpeed, rpm)
+}
+
+/// 力矩模式，设定力矩电流（A）
+pub fn set_torque(device: &mut Box<dyn Device + Send>, current: f32) -> Result<()> {
+    write_setpoint(device, MotorCode::SetTorque, current)
+}
+
+/// 位置模式，设定位置（deg）
+pub fn set_position(device: &mut Box<dyn Device + Send>, position: f32) -> Result<()> {
+    write_setpoint(device, MotorCode::SetPosition, position)
+}
+
+pub fn stop(device: &mut Box<dyn Device + Send>) -> Result<()> {
+    ProtocolHeader::write(device, CmdCode::Motor(MotorCode::Stop), 0)
+}
+
+pub fn clear_fault(device: &mut Box<dyn Device + Send>) -> Result<()> {
+    ProtocolHeader::write(device, CmdCode::Motor(MotorCode::ClearFault), 0)
+}
+
+/// 告警规则使用的字段值
+fn alarm_value(info: &MotorInfo, field: AlarmField) -> Option<f32> {
+    match field {
+        AlarmField::MotorSpeed => Some(info.speed as f32 / 100.0),
+        AlarmField::MotorPhaseCurrent => info
+            .phase_current
+            .iter()
+            .map(|c| c.abs())
+            .max()
+            .map(|c| c as f32 / 100.0),
+        AlarmField::MotorBusVoltage => Some(info.bus_voltage as f32 / 100.0),
+        AlarmField::MotorTemperature => Some(info.temperature as f32 / 100.0),
+        _ => None,
+    }
+}
+
+/// 将电机数据显示到电机视图
+fn show_info(handle: &AppWindow, motor_info: &MotorInfo) {
+    let service = handle.global::<MotorModelService>();
+    let fault: Vec<bool> = motor_info.faults();
+    let phase_current: Vec<f32> = motor_info
+        .phase_current
+        .iter()
+        .map(|&x| x as f32 / 100.0)
+        .collect();
+    service.set_motor_info(MotorInfoModel {
+        fault: VecModel::from_slice(fault.as_slice()),
+        enable: motor_info.enable != 0,
+        mode: motor_info.mode as i32,
+        speed: motor_info.speed as f32 / 100.0,
+        position: motor_info.position as f32 / 100.0,
+        phase_current: VecModel::from_slice(phase_current.as_slice()),
+        bus_voltage: motor_info.bus_voltage as f32 / 100.0,
+        temperature: motor_info.temperature as f32 / 100.0,
+    });
+}
+
+/// 故障名称列表，与MOTOR_FAULTS一致
+pub fn show_fault_names(handle: &AppWindow) {
+    let names: Vec<SharedString> = MOTOR_FAULTS.iter().map(|&(_, name)| name.into()).collect();
+    handle
+        .global::<MotorModelService>()
+        .set_fault_names(VecModel::from_slice(names.as_slice()));
+}
+
+/// 选中设备后显示其数据，尚未收到数据时显示默认值
+pub fn show_device(handle: &AppWindow, type_id: u32, device_id: u32) {
+    let motor_info = MOTOR_TELEMETRY
+        .lock()
+        .ok()
+        .and_then(|telemetry| telemetry.get(&(type_id, device_id)).cloned())
+        .unwrap_or_default();
+    show_info(handle, &motor_info);
+}
+
+/// 设备编号变更后迁移数据
+pub fn rekey_device(type_id: u32, device_id: u32, new_device_id: u32) {
+    if let Ok(mut telemetry) = MOTOR_TELEMETRY.lock() {
+        if let Some(info) = telemetry.remove(&(type_id, device_id)) {
+            telemetry.insert((type_id, new_device_id), info);
+        }
+    }
+}
+
+/// 解码并发布电机数据，返回数据及其主机时间
+fn publish_info(device: &mut Box<dyn Device + Send>, buf: &[u8]) -> Option<(MotorInfo, u64)> {
+    let motor_info = MotorInfo::parse(buf).ok()?;
+    let device_tick = timesync::device_tick(buf, MOTOR_INFO_SIZE).ok()?;
+    let (device_id, type_id) = device.get_id();
+    let time = clock::sample_time(type_id, device_id, device_tick);
+    let row = motor_info.csv_row(time, device_id);
+    telemetry::publish(
+        Kind::Motor,
+        type_id,
+        device_id,
+        time,
+        &motor_info.csv_header(),
+        &row,
+    );
+    Some((motor_info, time))
+}
+
+/// 无界面运行时只发布数据
+pub fn motor_info_event(
+    device: &mut Box<dyn Device + Send>,
+    buf: Option<&[u8]>,
+    _ui: &Weak<AppWindow>,
+) {
+    if let Some(buf) = buf {
+        publish_info(device, buf);
+    }
+}
+
+pub fn motor_info_protocol(
+    device: &mut Box<dyn Device + Send>,
+    buf: Option<&[u8]>,
+    ui: &Weak<AppWindow>,
+) {
+    if let Some((motor_info, time)) = buf.and_then(|buf| publish_info(device, buf)) {
+        let (device_id, type_id) = device.get_id();
+        if let Ok(mut telemetry) = MOTOR_TELEMETRY.lock() {
+            telemetry.insert((type_id, device_id), motor_info.clone());
+        }
+        let alarm_changed = alarm::evaluate(type_id, device_id, time, |field| {
+            alarm_value(&motor_info, field)
+        });
+        let _ = ui.upgrade_in_event_loop(move |handle| {
+            if alarm_changed {
+                alarm::show_alarms(&handle);
+            }
+            let device_service = handle.global::<DeviceModelService>();
+            // 未选中设备时默认显示第一个上报数据的设备
+            if device_service.get_current_type_id() < 0 {
+                device_service.set_current_type_id(type_id as i32);
+                device_service.set_current_device_id(device_id as i32);
+            }
+            if device_service.get_current_type_id() == type_id as i32
+                && device_service.get_current_device_id() == device_id as i32
+            {
+                show_info(&handle, &motor_info);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn motor_info_size_test() {
+        let config = config::standard()
+            .with_fixed_int_encoding()
+            .with_big_endian();
+        let p = MotorInfo::default();
+        let encode: Vec<u8> = bincode::encode_to_vec(&p, config).unwrap();
+        assert_eq!(encode.len(), MOTOR_INFO_SIZE);
+        assert_eq!(MotorInfo::parse(&encode[..]).unwrap(), p);
+        assert!(MotorInfo::parse(&encode[..MOTOR_INFO_SIZE - 1]).is_err());
+        assert_eq!(p.csv_header().len(), p.csv_row(0, 1).len());
+    }
+
+    #[test]
+    fn motor_faults_test() {
+        let p = MotorInfo {
+            fault: 0b1001,
+            ..Default::default()
+        };
+        assert_eq!(
+            p.faults(),
+            vec![true, false, false, true, false, false, false]
+        );
+    }
+
+    #[test]
+    fn motor_alarm_value_test() {
+        let p = MotorInfo {
+            phase_current: [120, -350, 230],
+            ..Default::default()
+        };
+        assert_eq!(alarm_value(&p, AlarmField::MotorPhaseCurrent), Some(3.5));
+        assert_eq!(alarm_value(&p, AlarmField::Voltage), None);
+    }
+}
@@ -5,16 +5,25 @@ use bincode::{
     config::{self},
     Decode, Encode,
 };
+use std::{
+    io,
+    time::{Duration, Instant},
+};
 
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
 const MAGIC: [u8; 4] = ['C' as u8, 'A' as u8, 'W' as u8, 'X' as u8];
 const VERSION: u16 = 0x101;
 pub const HEADER_SIZE: usize = 19;
+/// 单个数据帧允许的最大数据体大小
+pub const MAX_DATA_SIZE: u32 = 0x10000;
 
 #[derive(Debug, Clone)]
-enum ProtocolError {
+pub enum ProtocolError {
     ParseHeaderFailed,
+    ReadFrameTimeout,
+    /// 数据体长度不足
+    DataTooShort(usize, usize),
 }
 
 impl std::fmt::Display for ProtocolError {
@@ -23,6 +32,12 @@ impl std::fmt::Display for ProtocolError {
             ProtocolError::ParseHeaderFailed => {
                 write!(f, "parse header failed")
             }
+            ProtocolError::ReadFrameTimeout => {
+                write!(f, "read frame timeout")
+            }
+            ProtocolError::DataTooShort(size, expected) => {
+                write!(f, "data too short: {} < {}", size, expected)
+            }
         }
     }
 }
@@ -31,11 +46,13 @@ impl std::error::Error for ProtocolError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match *self {
             ProtocolError::ParseHeaderFailed => None,
+            ProtocolError::ReadFrameTimeout => None,
+            ProtocolError::DataTooShort(_, _) => None,
         }
     }
 }
 
-#[derive(Encode, Decode, PartialEq, Debug)]
+#[derive(Encode, Decode, PartialEq, Debug, Clone)]
 pub struct ProtocolHeader {
     magic: [u8; 4],
     cmd_code: CmdCode,
@@ -82,6 +99,11 @@ impl ProtocolHeader {
     pub fn get_data_size(&self) -> u32 {
         self.data_size
     }
+
+    /// 校验数据体的CRC8
+    pub fn check_checksum(&self, buf: &[u8]) -> bool {
+        self.checksum == crc8_slice_with_ccitt(buf)
+    }
 }
 
 impl ProtocolHeader {
@@ -107,12 +129,224 @@ impl ProtocolHeader {
         let header_buf: Vec<u8> = bincode::encode_to_vec(&header, config)?;
         device.write(&header_buf[..])
     }
+
+    /// 将协议头及数据体打包为完整的数据帧
+    pub fn pack(code: CmdCode, data: &[u8]) -> Result<Vec<u8>> {
+        let header = ProtocolHeader::default()
+            .set_cmd_code(code)
+            .set_data_size(data.len() as u32)
+            .set_checksum(data);
+        let config = config::standard()
+            .with_fixed_int_encoding()
+            .with_big_endian();
+        let mut buf: Vec<u8> = bincode::encode_to_vec(&header, config)?;
+        buf.extend_from_slice(data);
+        Ok(buf)
+    }
+
+    /// 以原始的指令分组与编号打包数据帧，用于发送尚未定义的指令
+    pub fn pack_raw(group: u32, code: u32, data: &[u8]) -> Vec<u8> {
+        let mut buf = MAGIC.to_vec();
+        buf.extend_from_slice(&group.to_be_bytes());
+        buf.extend_from_slice(&code.to_be_bytes());
+        buf.extend_from_slice(&VERSION.to_be_bytes());
+        buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        buf.push(crc8_slice_with_ccitt(data));
+        buf.extend_from_slice(data);
+        buf
+    }
+
+    /// 写入协议头及数据体
+    pub fn write_data(
+        device: &mut Box<dyn Device + Send>,
+        code: CmdCode,
+        data: &[u8],
+    ) -> Result<()> {
+        device.write(&ProtocolHeader::pack(code, data)?[..])
+    }
+}
+
+/// 未解析指令的原始数据帧
+///
+/// 指令无法识别时cmd_code为None，仍可按原始编号显示
+#[derive(Debug, Clone, PartialEq)]
+pub struct RawFrame {
+    pub header: Option<ProtocolHeader>,
+    /// 指令分组与组内编号
+    pub raw_code: (u32, u32),
+    pub checksum: u8,
+    /// 收到的原始协议头
+    pub raw_header: [u8; HEADER_SIZE],
+    pub data: Vec<u8>,
+}
+
+impl RawFrame {
+    fn parse_u32(buf: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes([
+            buf[offset],
+            buf[offset + 1],
+            buf[offset + 2],
+            buf[offset + 3],
+        ])
+    }
+
+    pub fn get_cmd_code(&self) -> Option<CmdCode> {
+        self.header.as_ref().map(|h| h.get_cmd_code())
+    }
+
+    pub fn check_checksum(&self) -> bool {
+        self.checksum == crc8_slice_with_ccitt(&self.data)
+    }
+
+    /// 完整的数据帧大小
+    pub fn size(&self) -> usize {
+        HEADER_SIZE + self.data.len()
+    }
+
+    /// 收到的完整数据帧
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [&self.raw_header[..], &self.data[..]].concat()
+    }
+}
+
+/// 数据帧缓冲区
+///
+/// 缓存从设备读取的字节流，并从中切分出完整的数据帧
+pub struct FrameBuffer {
+    buf: Vec<u8>,
+    /// 丢弃无效字节重新同步的次数
+    resyncs: u64,
+}
+
+impl Default for FrameBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameBuffer {
+    pub fn new() -> Self {
+        Self {
+            buf: vec![],
+            resyncs: 0,
+        }
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// 取出并清零重新同步的次数
+    pub fn take_resyncs(&mut self) -> u64 {
+        std::mem::take(&mut self.resyncs)
+    }
+
+    /// 取出下一个完整的数据帧，数据不足时返回None
+    ///
+    /// 魔数之前的无效字节会被丢弃，无法识别指令的数据帧被跳过
+    pub fn next_frame(&mut self) -> Option<(ProtocolHeader, Vec<u8>)> {
+        loop {
+            let frame = self.next_raw_frame()?;
+            if let Some(header) = frame.header {
+                return Some((header, frame.data));
+            }
+        }
+    }
+
+    /// 取出下一个完整的原始数据帧，数据不足时返回None
+    ///
+    /// 只按魔数与数据体大小切分，不要求指令可识别
+    pub fn next_raw_frame(&mut self) -> Option<RawFrame> {
+        loop {
+            if self.buf.len() < HEADER_SIZE {
+                return None;
+            }
+            if self.buf[..MAGIC.len()] != MAGIC {
+                self.resyncs += 1;
+                match self.buf[1..].windows(MAGIC.len()).position(|w| w == MAGIC) {
+                    Some(pos) => {
+                        self.buf.drain(..pos + 1);
+                    }
+                    None => {
+                        self.buf.drain(..self.buf.len() - (MAGIC.len() - 1));
+                        return None;
+                    }
+                }
+                continue;
+            }
+            let data_size = RawFrame::parse_u32(&self.buf, 14);
+            if data_size > MAX_DATA_SIZE {
+                self.resyncs += 1;
+                self.buf.drain(..1);
+                continue;
+            }
+            let frame_size = HEADER_SIZE + data_size as usize;
+            if self.buf.len() < frame_size {
+                return None;
+            }
+            let mut raw_header = [0; HEADER_SIZE];
+            raw_header.copy_from_slice(&self.buf[..HEADER_SIZE]);
+            let frame = RawFrame {
+                header: ProtocolHeader::parse(&self.buf[..]).ok(),
+                raw_code: (
+                    RawFrame::parse_u32(&self.buf, 4),
+                    RawFrame::parse_u32(&self.buf, 8),
+                ),
+                checksum: self.buf[HEADER_SIZE - 1],
+                raw_header,
+                data: self.buf[HEADER_SIZE..frame_size].to_vec(),
+            };
+            self.buf.drain(..frame_size);
+            return Some(frame);
+        }
+    }
+
+    /// 从设备读取一个完整的数据帧，超时返回错误
+    pub fn read_frame(
+        &mut self,
+        device: &mut Box<dyn Device + Send>,
+        timeout: Duration,
+    ) -> Result<(ProtocolHeader, Vec<u8>)> {
+        let start = Instant::now();
+        loop {
+            let remain = timeout.saturating_sub(start.elapsed());
+            let frame = self.read_raw_frame(device, remain)?;
+            if let Some(header) = frame.header {
+                return Ok((header, frame.data));
+            }
+        }
+    }
+
+    /// 从设备读取一个完整的原始数据帧，超时返回错误
+    pub fn read_raw_frame(
+        &mut self,
+        device: &mut Box<dyn Device + Send>,
+        timeout: Duration,
+    ) -> Result<RawFrame> {
+        let start = Instant::now();
+        let mut tmp_buf = [0u8; 1024];
+        loop {
+            if let Some(frame) = self.next_raw_frame() {
+                return Ok(frame);
+            }
+            if start.elapsed() > timeout {
+                return Err(ProtocolError::ReadFrameTimeout.into());
+            }
+            match device.read(&mut tmp_buf[..]) {
+                Ok(size) => self.push(&tmp_buf[..size]),
+                Err(e) => match e.downcast_ref::<io::Error>() {
+                    Some(err) if err.kind() == io::ErrorKind::TimedOut => (),
+                    _ => return Err(e),
+                },
+            }
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::caw::protocols::code::OtherCode;
+    use crate::caw::protocols::code::{OtherCode, SystemCode};
     use bincode::config;
 
     #[test]
@@ -134,4 +368,64 @@ mod tests {
         let encode: Vec<u8> = bincode::encode_to_vec(&p, config).unwrap();
         assert_eq!(encode.len(), HEADER_SIZE);
     }
+
+    #[test]
+    fn frame_buffer_test() {
+        let config = config::standard()
+            .with_fixed_int_encoding()
+            .with_big_endian();
+        let data = [1u8, 2, 3];
+        let header = ProtocolHeader::default()
+            .set_cmd_code(CmdCode::Other(OtherCode::Unknown))
+            .set_data_size(data.len() as u32)
+            .set_checksum(&data[..]);
+        let mut frame: Vec<u8> = vec![0xaa, b'C', 0xbb];
+        frame.append(&mut bincode::encode_to_vec(&header, config).unwrap());
+        frame.extend_from_slice(&data[..]);
+        assert_eq!(
+            frame[3..],
+            ProtocolHeader::pack(CmdCode::Other(OtherCode::Unknown), &data[..]).unwrap()[..]
+        );
+
+        let mut frames = FrameBuffer::new();
+        frames.push(&frame[..10]);
+        assert!(frames.next_frame().is_none());
+        frames.push(&frame[10..]);
+        let (h, d) = frames.next_frame().unwrap();
+        assert_eq!(h, header);
+        assert_eq!(d, data);
+        assert!(h.check_checksum(&d[..]));
+        assert!(frames.next_frame().is_none());
+        assert_eq!(frames.take_resyncs(), 1);
+        assert_eq!(frames.take_resyncs(), 0);
+    }
+
+    #[test]
+    fn raw_frame_test() {
+        let data = [1u8, 2];
+        let mut frame = ProtocolHeader::pack(CmdCode::Other(OtherCode::Unknown), &data).unwrap();
+        // 改为无法识别的指令
+        frame[11] = 0x7f;
+        let ping = ProtocolHeader::pack(CmdCode::System(SystemCode::Ping), &[]).unwrap();
+        let mut frames = FrameBuffer::new();
+        frames.push(&frame);
+        frames.push(&ping);
+        let raw = frames.next_raw_frame().unwrap();
+        assert_eq!(raw.header, None);
+        assert_eq!(raw.raw_code, (0, 0x7f));
+        assert_eq!(raw.data, data);
+        assert!(raw.check_checksum());
+        assert_eq!(raw.size(), frame.len());
+        assert_eq!(raw.to_bytes(), frame);
+        assert_eq!(ProtocolHeader::pack_raw(0, 0x7f, &data), frame);
+        // 无法识别的帧被跳过，不影响后续数据帧
+        frames.push(&frame);
+        frames.push(&ping);
+        assert_eq!(
+            frames.next_raw_frame().unwrap().get_cmd_code(),
+            Some(CmdCode::System(SystemCode::Ping))
+        );
+        let (h, _) = frames.next_frame().unwrap();
+        assert_eq!(h.get_cmd_code(), CmdCode::System(SystemCode::Ping));
+    }
 }
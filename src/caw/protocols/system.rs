@@ -0,0 +1,102 @@
+use bincode::{config, Decode, Encode};
+
+use super::{
+    code::{CmdCode, SystemCode},
+    protocol::{ProtocolError, ProtocolHeader},
+};
+use crate::caw::devices::device::Device;
+
+pub const DEVICE_NAME_SIZE: usize = 16;
+pub const PROVISION_SIZE: usize = 20;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// 设备编号烧录请求，名称为UTF-8编码，不足部分补0
+#[derive(Encode, Decode, PartialEq, Debug)]
+pub struct Provision {
+    device_id: u32,
+    name: [u8; DEVICE_NAME_SIZE],
+}
+
+impl Provision {
+    pub fn new(device_id: u32, name: &str) -> Result<Self> {
+        if name.len() > DEVICE_NAME_SIZE {
+            return Err(format!("name longer than {} bytes", DEVICE_NAME_SIZE).into());
+        }
+        let mut buf = [0u8; DEVICE_NAME_SIZE];
+        buf[..name.len()].copy_from_slice(name.as_bytes());
+        Ok(Self {
+            device_id,
+            name: buf,
+        })
+    }
+
+    pub fn parse(buf: &[u8]) -> Result<Self> {
+        if buf.len() < PROVISION_SIZE {
+            return Err(ProtocolError::DataTooShort(buf.len(), PROVISION_SIZE).into());
+        }
+        let config = config::standard()
+            .with_fixed_int_encoding()
+            .with_big_endian();
+        let (v, _): (Provision, usize) =
+            bincode::decode_from_slice(&buf[..PROVISION_SIZE], config)?;
+        Ok(v)
+    }
+
+    pub fn get_device_id(&self) -> u32 {
+        self.device_id
+    }
+
+    pub fn get_name(&self) -> String {
+        let size = self
+            .name
+            .iter()
+            .position(|&b| b == 0)
+            .unwrap_or(DEVICE_NAME_SIZE);
+        String::from_utf8_lossy(&self.name[..size]).into()
+    }
+}
+
+/// 重启设备
+pub fn reboot(device: &mut Box<dyn Device + Send>) -> Result<()> {
+    ProtocolHeader::write(device, CmdCode::System(SystemCode::Reboot), 0)
+}
+
+/// 恢复出厂设置
+pub fn factory_reset(device: &mut Box<dyn Device + Send>) -> Result<()> {
+    ProtocolHeader::write(device, CmdCode::System(SystemCode::FactoryReset), 0)
+}
+
+/// 设备指示灯闪烁，用于在多个设备中定位
+pub fn identify(device: &mut Box<dyn Device + Send>) -> Result<()> {
+    ProtocolHeader::write(device, CmdCode::System(SystemCode::Identify), 0)
+}
+
+/// 将设备编号及名称写入设备非易失存储，名称为空时设备保留原名称
+pub fn provision(device: &mut Box<dyn Device + Send>, device_id: u32, name: &str) -> Result<()> {
+    let config = config::standard()
+        .with_fixed_int_encoding()
+        .with_big_endian();
+    let buf: Vec<u8> = bincode::encode_to_vec(Provision::new(device_id, name)?, config)?;
+    ProtocolHeader::write_data(device, CmdCode::System(SystemCode::Provision), &buf[..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn provision_size_test() {
+        let config = config::standard()
+            .with_fixed_int_encoding()
+            .with_big_endian();
+        let p = Provision::new(7, "pack-07").unwrap();
+        let encode: Vec<u8> = bincode::encode_to_vec(&p, config).unwrap();
+        assert_eq!(encode.len(), PROVISION_SIZE);
+        let p = Provision::parse(&encode[..]).unwrap();
+        assert_eq!(p.get_device_id(), 7);
+        assert_eq!(p.get_name(), "pack-07");
+        assert!(Provision::parse(&encode[..PROVISION_SIZE - 1]).is_err());
+        assert!(Provision::new(7, "a name that is far too long").is_err());
+    }
+}
@@ -0,0 +1,108 @@
+use bincode::{config, Decode, Encode};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{
+    code::{CmdCode, SystemCode},
+    protocol::{ProtocolError, ProtocolHeader},
+};
+use crate::caw::devices::device::Device;
+
+pub const TIME_SYNC_RESPONSE_SIZE: usize = 16;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// 时间同步请求，携带主机发送时刻
+#[derive(Encode, Decode, PartialEq, Debug)]
+struct TimeSyncRequest {
+    host_time: u64, // us
+}
+
+/// 时间同步应答，回传主机发送时刻及设备当前计数
+#[derive(Encode, Decode, PartialEq, Debug)]
+pub struct TimeSyncResponse {
+    host_time: u64,   // us
+    device_tick: u64, // us
+}
+
+impl TimeSyncResponse {
+    pub fn parse(buf: &[u8]) -> Result<Self> {
+        if buf.len() < TIME_SYNC_RESPONSE_SIZE {
+            return Err(ProtocolError::DataTooShort(buf.len(), TIME_SYNC_RESPONSE_SIZE).into());
+        }
+        let config = config::standard()
+            .with_fixed_int_encoding()
+            .with_big_endian();
+        let (v, _): (TimeSyncResponse, usize) =
+            bincode::decode_from_slice(&buf[..TIME_SYNC_RESPONSE_SIZE], config)?;
+        Ok(v)
+    }
+
+    pub fn get_host_time(&self) -> u64 {
+        self.host_time
+    }
+
+    pub fn get_device_tick(&self) -> u64 {
+        self.device_tick
+    }
+}
+
+/// 遥测数据在offset之后附带的发送时设备计数，单位us
+///
+/// 支持时间同步的固件在遥测数据末尾附带设备计数，未附带时为None
+pub fn device_tick(buf: &[u8], offset: usize) -> Result<Option<u64>> {
+    match buf.len().saturating_sub(offset) {
+        0 => Ok(None),
+        n if n < 8 => Err(ProtocolError::DataTooShort(buf.len(), offset + 8).into()),
+        _ => Ok(Some(u64::from_be_bytes(
+            buf[offset..offset + 8].try_into()?,
+        ))),
+    }
+}
+
+/// 主机当前时间，单位us
+pub fn host_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or(0)
+}
+
+/// 发送时间同步请求
+pub fn request(device: &mut Box<dyn Device + Send>) -> Result<()> {
+    let config = config::standard()
+        .with_fixed_int_encoding()
+        .with_big_endian();
+    let req = TimeSyncRequest {
+        host_time: host_time(),
+    };
+    let buf: Vec<u8> = bincode::encode_to_vec(&req, config)?;
+    ProtocolHeader::write_data(device, CmdCode::System(SystemCode::TimeSync), &buf[..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn time_sync_size_test() {
+        let config = config::standard()
+            .with_fixed_int_encoding()
+            .with_big_endian();
+        let v = TimeSyncResponse {
+            host_time: 1,
+            device_tick: 2,
+        };
+        let encode: Vec<u8> = bincode::encode_to_vec(&v, config).unwrap();
+        assert_eq!(encode.len(), TIME_SYNC_RESPONSE_SIZE);
+        assert_eq!(TimeSyncResponse::parse(&encode[..]).unwrap(), v);
+        assert!(TimeSyncResponse::parse(&encode[..8]).is_err());
+    }
+
+    #[test]
+    fn device_tick_test() {
+        let buf = [1, 0, 0, 0, 0, 0, 0, 1, 2];
+        assert_eq!(device_tick(&buf, 1).unwrap(), Some(0x102));
+        assert_eq!(device_tick(&buf, 9).unwrap(), None);
+        assert!(device_tick(&buf, 2).is_err());
+    }
+}
@@ -0,0 +1,370 @@
+use chrono::{Local, TimeZone};
+use serde_json::{Map, Value};
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::caw::utils::args;
+
+/// 默认记录目录
+pub const DEFAULT_RECORD_DIR: &str = "records";
+/// 默认单个文件的最大字节数
+pub const DEFAULT_MAX_SIZE: u64 = 10 * 1024 * 1024;
+/// 默认单个文件的最长记录时间(us)
+pub const DEFAULT_MAX_DURATION: u64 = 60 * 60 * 1_000_000;
+/// 文件名重复时尝试的最大序号
+const MAX_NAME_SUFFIX: u32 = 1000;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+/// 记录选项，文件超过max_size字节或记录超过max_duration(us)后切换到新文件
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordOptions {
+    pub dir: PathBuf,
+    pub max_size: u64,
+    pub max_duration: u64,
+}
+
+impl RecordOptions {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            max_size: DEFAULT_MAX_SIZE,
+            max_duration: DEFAULT_MAX_DURATION,
+        }
+    }
+
+    /// 解析命令行参数，未指定--record时返回None
+    ///
+    /// --record <dir> [--record-max-size <MB>] [--record-max-minutes <min>]
+    pub fn from_args(args: &[String]) -> Result<Option<Self>> {
+        let Some(dir) = args::value(args, "--record")? else {
+            return Ok(None);
+        };
+        let mut options = Self::new(dir);
+        if let Some(mb) = args::positive(args, "--record-max-size")? {
+            options.max_size = (mb * 1024.0 * 1024.0) as u64;
+        }
+        if let Some(minutes) = args::positive(args, "--record-max-minutes")? {
+            options.max_duration = (minutes * 60.0 * 1_000_000.0) as u64;
+        }
+        Ok(Some(options))
+    }
+}
+
+/// CSV记录
+///
+/// 表头只允许在末尾增加列，列数增加时切换到新文件，
+/// 列数较少的行在末尾补空
+pub struct CsvRecorder {
+    options: RecordOptions,
+    prefix: String,
+    header: Vec<String>,
+    writer: Option<BufWriter<File>>,
+    path: Option<PathBuf>,
+    opened: u64,
+    size: u64,
+    rows: u64,
+}
+
+impl CsvRecorder {
+    pub fn new(prefix: &str, options: RecordOptions) -> Result<Self> {
+        fs::create_dir_all(&options.dir)?;
+        Ok(Self {
+            options,
+            prefix: prefix.into(),
+            header: vec![],
+            writer: None,
+            path: None,
+            opened: 0,
+            size: 0,
+            rows: 0,
+        })
+    }
+
+    /// 当前写入的文件
+    pub fn get_path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// 已写入的总行数，不含表头
+    pub fn get_rows(&self) -> u64 {
+        self.rows
+    }
+
+    /// 表头不是当前文件表头的前缀时（如列数增加）切换文件
+    fn need_rotate(&self, time: u64, header: &[String]) -> bool {
+        self.writer.is_none()
+            || !self.header.starts_with(header)
+            || self.size >= self.options.max_size
+            || time.saturating_sub(self.opened) >= self.options.max_duration
+    }
+
+    fn open(&mut self, time: u64, header: Vec<String>) -> Result<()> {
+        self.close()?;
+        let name = Local
+            .timestamp_micros(time as i64)
+            .single()
+            .map(|t| t.format("%Y%m%d-%H%M%S%.3f").to_string())
+            .unwrap_or_else(|| time.to_string());
+        let (file, path) = create_file(&self.options.dir, &format!("{}-{}", self.prefix, name))?;
+        let mut writer = BufWriter::new(file);
+        let line = header.join(",") + "\n";
+        writer.write_all(line.as_bytes())?;
+        self.size = line.len() as u64;
+        self.writer = Some(writer);
+        self.path = Some(path);
+        self.header = header;
+        self.opened = time;
+        Ok(())
+    }
+
+    /// 写入一行，time为主机时间(us)
+    pub fn write(&mut self, time: u64, header: Vec<String>, row: Vec<Value>) -> Result<()> {
+        if self.need_rotate(time, &header) {
+            self.open(time, header)?;
+        }
+        let mut row: Vec<String> = row.iter().map(csv_field).collect();
+        row.resize(self.header.len(), String::new());
+        let line = row.join(",") + "\n";
+        if let Some(writer) = self.writer.as_mut() {
+            writer.write_all(line.as_bytes())?;
+            writer.flush()?;
+        }
+        self.size += line.len() as u64;
+        self.rows += 1;
+        Ok(())
+    }
+
+    pub fn close(&mut self) -> Result<()> {
+        if let Some(mut writer) = self.writer.take() {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for CsvRecorder {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
+
+/// 新建文件，不覆盖已有文件，重名时在末尾加序号
+fn create_file(dir: &Path, name: &str) -> Result<(File, PathBuf)> {
+    for i in 0..MAX_NAME_SUFFIX {
+        let path = match i {
+            0 => dir.join(format!("{}.csv", name)),
+            i => dir.join(format!("{}-{}.csv", name, i)),
+        };
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((file, path)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Err(io::Error::from(io::ErrorKind::AlreadyExists).into())
+}
+
+/// 按数据来源分别记录的CSV
+///
+/// 每个来源写入单独的文件，文件名为prefix-key-时间，
+/// 避免多台设备或多种数据的表头交替导致每行都切换文件
+pub struct RecorderSet {
+    options: RecordOptions,
+    prefix: String,
+    recorders: HashMap<String, CsvRecorder>,
+}
+
+impl RecorderSet {
+    pub fn new(prefix: &str, options: RecordOptions) -> Result<Self> {
+        fs::create_dir_all(&options.dir)?;
+        Ok(Self {
+            options,
+            prefix: prefix.into(),
+            recorders: HashMap::new(),
+        })
+    }
+
+    pub fn get_dir(&self) -> &Path {
+        &self.options.dir
+    }
+
+    /// 各来源当前写入的文件
+    pub fn get_paths(&self) -> Vec<&Path> {
+        self.recorders
+            .values()
+            .filter_map(|recorder| recorder.get_path())
+            .collect()
+    }
+
+    /// 所有来源已写入的总行数
+    pub fn get_rows(&self) -> u64 {
+        self.recorders.values().map(|r| r.get_rows()).sum()
+    }
+
+    /// 写入来源key的一行，首次写入时新建该来源的记录
+    pub fn write(
+        &mut self,
+        key: &str,
+        time: u64,
+        header: Vec<String>,
+        row: Vec<Value>,
+    ) -> Result<()> {
+        if !self.recorders.contains_key(key) {
+            let prefix = format!("{}-{}", self.prefix, key);
+            let recorder = CsvRecorder::new(&prefix, self.options.clone())?;
+            self.recorders.insert(key.into(), recorder);
+        }
+        match self.recorders.get_mut(key) {
+            Some(recorder) => recorder.write(time, header, row),
+            None => Ok(()),
+        }
+    }
+}
+
+/// CSV中的主机时间
+pub fn format_time(time: u64) -> String {
+    Local
+        .timestamp_micros(time as i64)
+        .single()
+        .map(|t| t.format("%Y-%m-%d %H:%M:%S%.3f").to_string())
+        .unwrap_or_default()
+}
+
+/// 缩放后的测量值，按f32的最短表示转为数值，CSV与JSON中一致
+pub fn number(value: f32) -> Value {
+    value
+        .to_string()
+        .parse()
+        .map(Value::Number)
+        .unwrap_or(Value::Null)
+}
+
+/// CSV字段，字符串原样输出，空值为空
+pub fn csv_field(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Null => String::new(),
+        v => v.to_string(),
+    }
+}
+
+/// 由表头与数据行组成的JSON对象，kind写入type字段
+pub fn to_json(kind: &str, header: &[String], row: &[Value]) -> Value {
+    let mut object = Map::new();
+    object.insert("type".into(), kind.into());
+    for (name, value) in header.iter().zip(row.iter()) {
+        object.insert(name.clone(), value.clone());
+    }
+    Value::Object(object)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|&v| v.into()).collect()
+    }
+
+    #[test]
+    fn record_options_test() {
+        let args = strings(&["app", "--record", "out", "--record-max-size", "1"]);
+        let options = RecordOptions::from_args(&args).unwrap().unwrap();
+        assert_eq!(options.dir, PathBuf::from("out"));
+        assert_eq!(options.max_size, 1024 * 1024);
+        assert_eq!(options.max_duration, DEFAULT_MAX_DURATION);
+        assert_eq!(RecordOptions::from_args(&strings(&["app"])).unwrap(), None);
+        assert!(RecordOptions::from_args(&strings(&["app", "--record"])).is_err());
+        let args = strings(&["app", "--record", "out", "--record-max-minutes", "x"]);
+        assert!(RecordOptions::from_args(&args).is_err());
+    }
+
+    #[test]
+    fn csv_recorder_test() {
+        let dir = std::env::temp_dir().join(format!("caw-record-{}", std::process::id()));
+        let mut options = RecordOptions::new(&dir);
+        options.max_duration = 10 * 1_000_000;
+        let mut recorder = CsvRecorder::new("bms", options).unwrap();
+        let s = 1_000_000;
+        recorder
+            .write(0, strings(&["a", "b"]), vec![1.into(), "2".into()])
+            .unwrap();
+        recorder.write(s, strings(&["a"]), vec![3.into()]).unwrap();
+        let first = recorder.get_path().unwrap().to_path_buf();
+        assert_eq!(fs::read_to_string(&first).unwrap(), "a,b\n1,2\n3,\n");
+        // 列数增加时切换文件
+        recorder
+            .write(
+                2 * s,
+                strings(&["a", "b", "c"]),
+                vec![4.into(), 5.into(), 6.into()],
+            )
+            .unwrap();
+        let second = recorder.get_path().unwrap().to_path_buf();
+        assert_ne!(first, second);
+        // 超过最长记录时间时切换文件
+        recorder
+            .write(
+                12 * s,
+                strings(&["a", "b", "c"]),
+                vec![7.into(), 8.into(), 9.into()],
+            )
+            .unwrap();
+        let third = recorder.get_path().unwrap().to_path_buf();
+        assert_ne!(second, third);
+        assert_eq!(fs::read_to_string(&third).unwrap(), "a,b,c\n7,8,9\n");
+        // 列名不一致时切换文件
+        recorder
+            .write(13 * s, strings(&["a", "d"]), vec![1.into(), 2.into()])
+            .unwrap();
+        assert_ne!(recorder.get_path().unwrap(), third);
+        assert_eq!(recorder.get_rows(), 5);
+        drop(recorder);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn recorder_set_test() {
+        let dir = std::env::temp_dir().join(format!("caw-record-set-{}", std::process::id()));
+        let mut set = RecorderSet::new("bms", RecordOptions::new(&dir)).unwrap();
+        // 两个来源交替写入时各自的文件不切换
+        for i in 0..3 {
+            set.write("1", 0, strings(&["a"]), vec![i.into()]).unwrap();
+            set.write("2", 0, strings(&["a", "b"]), vec![i.into(), i.into()])
+                .unwrap();
+        }
+        let mut paths: Vec<PathBuf> = set.get_paths().iter().map(|p| p.to_path_buf()).collect();
+        paths.sort();
+        assert_eq!(paths.len(), 2);
+        assert_eq!(set.get_rows(), 6);
+        assert_eq!(fs::read_to_string(&paths[0]).unwrap(), "a\n0\n1\n2\n");
+        // 同一时刻新建的同名文件不覆盖已有文件
+        let mut other = CsvRecorder::new("bms-1", RecordOptions::new(&dir)).unwrap();
+        other.write(0, strings(&["c"]), vec![9.into()]).unwrap();
+        assert_ne!(other.get_path().unwrap(), paths[0]);
+        assert_eq!(fs::read_to_string(&paths[0]).unwrap(), "a\n0\n1\n2\n");
+        drop(set);
+        drop(other);
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn field_test() {
+        assert_eq!(number(735.0 / 100.0), serde_json::json!(7.35));
+        assert_eq!(number(40.0), serde_json::json!(40));
+        assert_eq!(number(f32::NAN), Value::Null);
+        let row = vec![number(-2.5), "00".into(), Value::Null];
+        let fields: Vec<String> = row.iter().map(csv_field).collect();
+        assert_eq!(fields, ["-2.5", "00", ""]);
+        let header = strings(&["a", "data", "b"]);
+        assert_eq!(
+            to_json("t", &header, &row).to_string(),
+            r#"{"type":"t","a":-2.5,"data":"00","b":null}"#
+        );
+    }
+}
@@ -0,0 +1,630 @@
+use lazy_static::lazy_static;
+use rhai::{Array, Dynamic, Engine, EvalAltResult, FnPtr, Map, NativeCallContext};
+use serde_json::Value;
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::caw::{
+    composer,
+    protocols::{discover::type_name, timesync::host_time},
+    recorder,
+    telemetry::{self, Kind},
+};
+
+/// 等待条件时的检查间隔
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+/// 保留的最近事件数
+const MAX_EVENTS: usize = 64;
+
+lazy_static! {
+    static ref SESSION: Mutex<Option<Session>> = Mutex::new(None);
+}
+
+static RUNNING: AtomicBool = AtomicBool::new(false);
+static STOP: AtomicBool = AtomicBool::new(false);
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+type ScriptResult<T> = std::result::Result<T, Box<EvalAltResult>>;
+
+#[derive(Debug, Clone)]
+pub enum ScriptError {
+    Busy,
+    Stopped,
+}
+
+impl std::fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            ScriptError::Busy => {
+                write!(f, "another script is running")
+            }
+            ScriptError::Stopped => {
+                write!(f, "stopped")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ScriptError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            ScriptError::Busy => None,
+            ScriptError::Stopped => None,
+        }
+    }
+}
+
+/// 设备上报的一条数据：序号、类型、列名、数据
+#[derive(Debug, Clone)]
+struct Record {
+    seq: u64,
+    kind: Kind,
+    header: Vec<String>,
+    row: Vec<Value>,
+}
+
+/// 脚本运行期间接收的设备数据
+struct Session {
+    type_id: u32,
+    device_id: u32,
+    next_seq: u64,
+    telemetry: Option<Record>,
+    events: VecDeque<Record>,
+}
+
+impl Session {
+    fn new(type_id: u32, device_id: u32) -> Self {
+        Self {
+            type_id,
+            device_id,
+            next_seq: 0,
+            telemetry: None,
+            events: VecDeque::new(),
+        }
+    }
+
+    fn record(&mut self, record: &telemetry::Record) -> Record {
+        self.next_seq += 1;
+        Record {
+            seq: self.next_seq,
+            kind: record.kind,
+            header: record.header.to_vec(),
+            row: record.row.to_vec(),
+        }
+    }
+}
+
+/// 向设备发送数据帧：指令分组、组内编号、数据体
+pub type SendFn = Arc<dyn Fn(u32, u32, &[u8]) -> Result<()> + Send + Sync>;
+
+/// 脚本所需的设备操作
+#[derive(Clone)]
+pub struct ScriptContext {
+    pub type_id: u32,
+    pub device_id: u32,
+    pub send: SendFn,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReportKind {
+    Log,
+    Pass,
+    Fail,
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReportEntry {
+    pub time: u64, // us
+    pub kind: ReportKind,
+    pub text: String,
+}
+
+impl ReportEntry {
+    pub fn new(kind: ReportKind, text: &str) -> Self {
+        Self {
+            time: host_time(),
+            kind,
+            text: text.into(),
+        }
+    }
+
+    pub fn to_line(&self) -> String {
+        let kind = match self.kind {
+            ReportKind::Log => "LOG",
+            ReportKind::Pass => "PASS",
+            ReportKind::Fail => "FAIL",
+            ReportKind::Error => "ERROR",
+        };
+        format!(
+            "[{}] {:<5} {}",
+            recorder::format_time(self.time),
+            kind,
+            self.text
+        )
+    }
+}
+
+/// 测试报告，有检查失败或脚本出错时不通过
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub name: String,
+    pub device: String,
+    pub started: u64,
+    pub finished: u64,
+    pub entries: Vec<ReportEntry>,
+}
+
+impl Report {
+    fn new(name: &str, type_id: u32, device_id: u32) -> Self {
+        Self {
+            name: name.into(),
+            device: format!("{} {}", type_name(type_id), device_id),
+            started: host_time(),
+            finished: 0,
+            entries: vec![],
+        }
+    }
+
+    fn count(&self, kind: ReportKind) -> usize {
+        self.entries.iter().filter(|e| e.kind == kind).count()
+    }
+
+    pub fn passed(&self) -> bool {
+        self.count(ReportKind::Fail) == 0 && self.count(ReportKind::Error) == 0
+    }
+
+    pub fn summary(&self) -> String {
+        format!(
+            "result: {} ({} passed, {} failed)",
+            if self.passed() { "PASS" } else { "FAIL" },
+            self.count(ReportKind::Pass),
+            self.count(ReportKind::Fail) + self.count(ReportKind::Error)
+        )
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut lines = vec![
+            format!("script: {}", self.name),
+            format!("device: {}", self.device),
+            format!("started: {}", recorder::format_time(self.started)),
+            format!("finished: {}", recorder::format_time(self.finished)),
+            String::new(),
+        ];
+        lines.extend(self.entries.iter().map(|e| e.to_line()));
+        lines.push(String::new());
+        lines.push(self.summary());
+        lines.join("\n")
+    }
+}
+
+/// 停止正在运行的脚本，在下一步操作或等待中生效
+pub fn stop() {
+    STOP.store(true, Ordering::Relaxed);
+}
+
+fn with_session<T, F>(type_id: u32, device_id: u32, f: F) -> Option<T>
+where
+    F: FnOnce(&mut Session) -> T,
+{
+    let mut session = SESSION.lock().ok()?;
+    session
+        .as_mut()
+        .filter(|s| s.type_id == type_id && s.device_id == device_id)
+        .map(f)
+}
+
+/// 记录脚本所在设备的最新遥测数据及应答等事件
+fn publish(record: &telemetry::Record) {
+    with_session(record.type_id, record.device_id, |session| {
+        let r = session.record(record);
+        if !record.kind.is_event() {
+            session.telemetry = Some(r);
+            return;
+        }
+        if session.events.len() >= MAX_EVENTS {
+            session.events.pop_front();
+        }
+        session.events.push_back(r);
+    });
+}
+
+/// 数值列转为浮点数，文本列为字符串
+fn to_map(record: &Record) -> Map {
+    let mut map = Map::new();
+    for (name, value) in record.header.iter().zip(record.row.iter()) {
+        let value = match value {
+            Value::Number(v) => Dynamic::from_float(v.as_f64().unwrap_or(f64::NAN)),
+            Value::String(s) => Dynamic::from(s.clone()),
+            Value::Bool(b) => Dynamic::from_bool(*b),
+            _ => Dynamic::UNIT,
+        };
+        map.insert(name.into(), value);
+    }
+    map
+}
+
+fn column<'a>(record: &'a Record, name: &str) -> Option<&'a str> {
+    let i = record.header.iter().position(|h| h == name)?;
+    record.row.get(i).and_then(|v| v.as_str())
+}
+
+/// 整数或浮点数秒
+fn seconds(value: &Dynamic) -> ScriptResult<Duration> {
+    let secs = value
+        .as_float()
+        .or_else(|_| value.as_int().map(|v| v as f64))
+        .map_err(|_| format!("expected seconds, got {}", value.type_name()))?;
+    Duration::try_from_secs_f64(secs).map_err(|e| e.to_string().into())
+}
+
+fn check_stop() -> ScriptResult<()> {
+    if STOP.load(Ordering::Relaxed) {
+        return Err(ScriptError::Stopped.to_string().into());
+    }
+    Ok(())
+}
+
+/// 可被停止的等待
+fn sleep(duration: Duration) -> ScriptResult<()> {
+    let start = Instant::now();
+    while let Some(remain) = duration.checked_sub(start.elapsed()) {
+        check_stop()?;
+        thread::sleep(remain.min(POLL_INTERVAL));
+    }
+    Ok(())
+}
+
+fn latest_telemetry() -> Option<Record> {
+    SESSION
+        .lock()
+        .ok()
+        .and_then(|s| s.as_ref().and_then(|s| s.telemetry.clone()))
+}
+
+/// 等待遥测数据满足条件，每收到一次新数据检查一次，超时返回false
+fn wait_until(ctx: &NativeCallContext, f: &FnPtr, timeout: Duration) -> ScriptResult<bool> {
+    let start = Instant::now();
+    let mut last_seq = 0;
+    loop {
+        check_stop()?;
+        if let Some(record) = latest_telemetry().filter(|r| r.seq != last_seq) {
+            last_seq = record.seq;
+            if f.call_within_context::<bool>(ctx, (to_map(&record),))? {
+                return Ok(true);
+            }
+        }
+        if start.elapsed() >= timeout {
+            return Ok(false);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// 等待序号大于from的指定指令的应答，返回应答状态，超时返回空字符串
+fn wait_ack(command: &str, from: u64, timeout: Duration) -> ScriptResult<String> {
+    let start = Instant::now();
+    loop {
+        check_stop()?;
+        let status = SESSION.lock().ok().and_then(|s| {
+            s.as_ref()?
+                .events
+                .iter()
+                .filter(|e| e.seq > from && e.kind == Kind::Ack)
+                .find(|e| column(e, "command") == Some(command))
+                .and_then(|e| column(e, "status").map(|s| s.to_string()))
+        });
+        if let Some(status) = status {
+            return Ok(status);
+        }
+        if start.elapsed() >= timeout {
+            return Ok(String::new());
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+fn current_seq() -> u64 {
+    SESSION
+        .lock()
+        .ok()
+        .and_then(|s| s.as_ref().map(|s| s.next_seq))
+        .unwrap_or(0)
+}
+
+/// 按名称发送已知指令，数据体字段按顺序给出
+fn encode_command(name: &str, values: &Array) -> ScriptResult<(u32, u32, Vec<u8>)> {
+    let code = composer::find_command(name).ok_or(format!("unknown command: {}", name))?;
+    let values: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+    let data = composer::encode_fields(&composer::payload_fields(code), &values)
+        .map_err(|e| e.to_string())?;
+    let (group, code) = code.to_raw();
+    Ok((group, code, data))
+}
+
+/// 创建脚本引擎并注册设备操作
+///
+/// log(msg)、sleep(s)、elapsed()、telemetry()、wait_until(|t| cond, s)、
+/// send(command, [fields])、send_raw(group, code, hex)、wait_ack(command, s)、
+/// check(name, cond)、assert(cond, msg)
+fn build_engine(
+    ctx: ScriptContext,
+    report: Rc<RefCell<Report>>,
+    on_entry: Rc<dyn Fn(&ReportEntry)>,
+) -> Engine {
+    let mut engine = Engine::new();
+    engine.on_progress(|_| {
+        STOP.load(Ordering::Relaxed)
+            .then(|| Dynamic::from(ScriptError::Stopped.to_string()))
+    });
+    let add = move |kind: ReportKind, text: &str| {
+        let entry = ReportEntry::new(kind, text);
+        on_entry(&entry);
+        report.borrow_mut().entries.push(entry);
+    };
+    let add = Rc::new(add);
+
+    let log = add.clone();
+    engine.on_print(move |s| log(ReportKind::Log, s));
+    let log = add.clone();
+    engine.register_fn("log", move |v: Dynamic| {
+        log(ReportKind::Log, &v.to_string())
+    });
+    // 覆盖内置的sleep，使其可被停止
+    engine.register_fn("sleep", |s: i64| sleep(seconds(&Dynamic::from_int(s))?));
+    engine.register_fn("sleep", |s: f64| sleep(seconds(&Dynamic::from_float(s))?));
+    let start = Instant::now();
+    engine.register_fn("elapsed", move || start.elapsed().as_secs_f64());
+    engine.register_fn("telemetry", || {
+        latest_telemetry().map_or(Dynamic::UNIT, |r| Dynamic::from_map(to_map(&r)))
+    });
+    engine.register_fn(
+        "wait_until",
+        |ctx: NativeCallContext, f: FnPtr, s: Dynamic| wait_until(&ctx, &f, seconds(&s)?),
+    );
+
+    // 发送前记录事件序号，wait_ack只匹配之后的应答
+    let sent_seq = Rc::new(RefCell::new(0u64));
+    let send = ctx.send.clone();
+    let seq = sent_seq.clone();
+    let send_command = move |name: &str, values: Array| -> ScriptResult<()> {
+        let (group, code, data) = encode_command(name, &values)?;
+        *seq.borrow_mut() = current_seq();
+        send(group, code, &data).map_err(|e| e.to_string().into())
+    };
+    let send_command = Rc::new(send_command);
+    let f = send_command.clone();
+    engine.register_fn("send", move |name: &str| f(name, Array::new()));
+    let f = send_command.clone();
+    engine.register_fn("send", move |name: &str, values: Array| f(name, values));
+    let send = ctx.send.clone();
+    let seq = sent_seq.clone();
+    engine.register_fn(
+        "send_raw",
+        move |group: i64, code: i64, hex: &str| -> ScriptResult<()> {
+            let data = composer::parse_hex(hex).map_err(|e| e.to_string())?;
+            let group = u32::try_from(group).map_err(|e| e.to_string())?;
+            let code = u32::try_from(code).map_err(|e| e.to_string())?;
+            *seq.borrow_mut() = current_seq();
+            send(group, code, &data).map_err(|e| e.to_string().into())
+        },
+    );
+    let seq = sent_seq.clone();
+    engine.register_fn("wait_ack", move |command: &str, s: Dynamic| {
+        wait_ack(command, *seq.borrow(), seconds(&s)?)
+    });
+
+    let check = add.clone();
+    engine.register_fn("check", move |name: &str, ok: bool| {
+        check(
+            if ok {
+                ReportKind::Pass
+            } else {
+                ReportKind::Fail
+            },
+            name,
+        );
+        ok
+    });
+    let check = add.clone();
+    engine.register_fn(
+        "assert",
+        move |ok: bool, message: &str| -> ScriptResult<()> {
+            if ok {
+                return Ok(());
+            }
+            check(ReportKind::Fail, message);
+            Err(format!("assertion failed: {}", message).into())
+        },
+    );
+    engine
+}
+
+/// 运行脚本，期间记录脚本所在设备的数据，返回测试报告
+///
+/// 同时只能运行一个脚本，on_entry在每条报告记录产生时调用
+pub fn run_source<F>(name: &str, source: &str, ctx: ScriptContext, on_entry: F) -> Report
+where
+    F: Fn(&ReportEntry) + 'static,
+{
+    let on_entry: Rc<dyn Fn(&ReportEntry)> = Rc::new(on_entry);
+    let report = Rc::new(RefCell::new(Report::new(name, ctx.type_id, ctx.device_id)));
+    if RUNNING
+        .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+        .is_err()
+    {
+        let entry = ReportEntry::new(ReportKind::Error, &ScriptError::Busy.to_string());
+        on_entry(&entry);
+        let mut report = report.borrow().clone();
+        report.entries.push(entry);
+        report.finished = host_time();
+        return report;
+    }
+    STOP.store(false, Ordering::Relaxed);
+    if let Ok(mut session) = SESSION.lock() {
+        *session = Some(Session::new(ctx.type_id, ctx.device_id));
+    }
+    let subscription = telemetry::subscribe(publish);
+    let engine = build_engine(ctx, report.clone(), on_entry.clone());
+    if let Err(e) = engine.run(source) {
+        let text = match *e {
+            // 停止可能发生在等待函数内部，此时报错为运行时错误
+            _ if STOP.load(Ordering::Relaxed) => ScriptError::Stopped.to_string(),
+            EvalAltResult::ErrorTerminated(..) => ScriptError::Stopped.to_string(),
+            ref e => e.to_string(),
+        };
+        let entry = ReportEntry::new(ReportKind::Error, &text);
+        on_entry(&entry);
+        report.borrow_mut().entries.push(entry);
+    }
+    drop(engine);
+    drop(subscription);
+    if let Ok(mut session) = SESSION.lock() {
+        *session = None;
+    }
+    RUNNING.store(false, Ordering::SeqCst);
+    let mut report = report.borrow().clone();
+    report.finished = host_time();
+    report
+}
+
+/// 运行脚本文件
+pub fn run<F>(path: &Path, ctx: ScriptContext, on_entry: F) -> Result<Report>
+where
+    F: Fn(&ReportEntry) + 'static,
+{
+    let source = std::fs::read_to_string(path)?;
+    let name = path.file_name().map_or_else(
+        || path.display().to_string(),
+        |n| n.to_string_lossy().into(),
+    );
+    Ok(run_source(&name, &source, ctx, on_entry))
+}
+
+/// 报告保存在脚本旁，文件名为<脚本名>-<时间>.report.txt
+pub fn save_report(path: &Path, report: &Report) -> Result<PathBuf> {
+    let stem = path
+        .file_stem()
+        .map_or_else(|| "script".into(), |s| s.to_string_lossy().to_string());
+    let time = chrono::Local::now().format("%Y%m%d-%H%M%S");
+    let out = path.with_file_name(format!("{}-{}.report.txt", stem, time));
+    std::fs::write(&out, report.to_text())?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(items: &[&str]) -> Vec<String> {
+        items.iter().map(|s| s.to_string()).collect()
+    }
+
+    /// 脚本全局状态，测试需依次运行
+    static TEST_LOCK: Mutex<()> = Mutex::new(());
+
+    fn context() -> ScriptContext {
+        ScriptContext {
+            type_id: 0,
+            device_id: 1,
+            send: Arc::new(|group, code, _| {
+                let header = strings(&["command", "status"]);
+                let row = vec![composer::command_name(group, code).into(), "Ok".into()];
+                telemetry::publish(Kind::Ack, 0, 1, 0, &header, &row);
+                Ok(())
+            }),
+        }
+    }
+
+    fn telemetry(voltage: f32) {
+        let header = strings(&["device_id", "voltage", "state"]);
+        let row = vec![1.into(), recorder::number(voltage), "idle".into()];
+        telemetry::publish(Kind::Bms, 0, 1, 0, &header, &row);
+        // 其他设备的数据不影响脚本
+        let row = [2.into(), 9.into(), "x".into()];
+        telemetry::publish(Kind::Bms, 0, 2, 0, &header, &row);
+    }
+
+    #[test]
+    fn script_test() {
+        let _lock = TEST_LOCK.lock().unwrap();
+        let feeder = thread::spawn(|| {
+            while !RUNNING.load(Ordering::Relaxed) {
+                thread::sleep(POLL_INTERVAL);
+            }
+            for i in 0..10 {
+                telemetry(3.0 + i as f32 * 0.15);
+                thread::sleep(POLL_INTERVAL);
+            }
+        });
+        let source = r#"
+            log("start");
+            check("charged", wait_until(|t| t.voltage >= 4.2, 5));
+            let t = telemetry();
+            check("state", t.state == "idle" && t.device_id == 1);
+            send("Motor(SetSpeed)", [1500]);
+            check("ack", wait_ack("Motor(SetSpeed)", 1) == "Ok");
+            send("System(Ping)");
+            check("no ack", wait_ack("Motor(SetSpeed)", 0.1) == "");
+            check("timeout", !wait_until(|t| t.voltage > 10, 0.1));
+            print("done");
+        "#;
+        let lines = Rc::new(RefCell::new(vec![]));
+        let l = lines.clone();
+        let report = run_source("test.rhai", source, context(), move |e| {
+            l.borrow_mut().push(e.text.clone())
+        });
+        feeder.join().unwrap();
+        assert!(report.passed(), "{}", report.to_text());
+        assert_eq!(report.count(ReportKind::Pass), 5);
+        assert_eq!(
+            *lines.borrow(),
+            ["start", "charged", "state", "ack", "no ack", "timeout", "done"]
+        );
+        assert!(report
+            .to_text()
+            .ends_with("result: PASS (5 passed, 0 failed)"));
+        assert!(!RUNNING.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn script_fail_test() {
+        let _lock = TEST_LOCK.lock().unwrap();
+        let report = run_source("a", r#"check("a", false); log(1);"#, context(), |_| ());
+        assert!(!report.passed());
+        assert_eq!(report.entries.len(), 2);
+
+        let report = run_source("b", r#"assert(false, "x"); log(1);"#, context(), |_| ());
+        assert_eq!(report.entries.len(), 2);
+        assert_eq!(report.entries[0].kind, ReportKind::Fail);
+        assert_eq!(report.entries[1].kind, ReportKind::Error);
+
+        let report = run_source("c", r#"send("Nope");"#, context(), |_| ());
+        assert!(report.entries[0].text.contains("unknown command: Nope"));
+        let report = run_source("d", "let x = ;", context(), |_| ());
+        assert_eq!(report.entries[0].kind, ReportKind::Error);
+
+        let stopper = thread::spawn(|| {
+            while !RUNNING.load(Ordering::Relaxed) {
+                thread::sleep(POLL_INTERVAL);
+            }
+            thread::sleep(Duration::from_millis(100));
+            stop();
+        });
+        let report = run_source("e", "sleep(10); log(1);", context(), |_| ());
+        stopper.join().unwrap();
+        assert_eq!(report.entries.len(), 1);
+        assert_eq!(report.entries[0].text, "stopped");
+        assert!(report.summary().starts_with("result: FAIL"));
+    }
+}
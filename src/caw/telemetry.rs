@@ -0,0 +1,139 @@
+use lazy_static::lazy_static;
+use serde_json::Value;
+use std::sync::Mutex;
+
+use crate::caw::{
+    bms,
+    event::Event,
+    protocols::{
+        ack,
+        code::{BMSCode, CmdCode, MotorCode, SystemCode},
+        motor,
+    },
+};
+
+lazy_static! {
+    /// 下一个订阅编号及全部订阅
+    static ref SUBSCRIBERS: Mutex<(u64, Vec<(u64, Subscriber)>)> = Mutex::new((0, vec![]));
+}
+
+type Subscriber = Box<dyn Fn(&Record) + Send>;
+
+/// 数据类型
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    Bms,
+    Motor,
+    Ack,
+}
+
+impl Kind {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Kind::Bms => "bms",
+            Kind::Motor => "motor",
+            Kind::Ack => "ack",
+        }
+    }
+
+    /// 应答等事件，不作为设备的最新数据
+    pub fn is_event(&self) -> bool {
+        matches!(*self, Kind::Ack)
+    }
+}
+
+/// 设备上报的一条解码后的数据，列名与数据一一对应
+#[derive(Debug, Clone, Copy)]
+pub struct Record<'a> {
+    pub kind: Kind,
+    pub type_id: u32,
+    pub device_id: u32,
+    /// 主机时间(us)
+    pub time: u64,
+    pub header: &'a [String],
+    pub row: &'a [Value],
+}
+
+/// 订阅，释放时注销
+pub struct Subscription {
+    id: u64,
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        if let Ok(mut subscribers) = SUBSCRIBERS.lock() {
+            subscribers.1.retain(|(id, _)| *id != self.id);
+        }
+    }
+}
+
+/// 订阅全部设备的数据，回调在协议处理线程中执行
+pub fn subscribe<F>(f: F) -> Subscription
+where
+    F: Fn(&Record) + Send + 'static,
+{
+    let mut subscribers = SUBSCRIBERS.lock().unwrap_or_else(|e| e.into_inner());
+    let id = subscribers.0;
+    subscribers.0 += 1;
+    subscribers.1.push((id, Box::new(f)));
+    Subscription { id }
+}
+
+/// 发布一条数据给全部订阅者，各协议处理每帧调用一次
+pub fn publish(
+    kind: Kind,
+    type_id: u32,
+    device_id: u32,
+    time: u64,
+    header: &[String],
+    row: &[Value],
+) {
+    let record = Record {
+        kind,
+        type_id,
+        device_id,
+        time,
+        header,
+        row,
+    };
+    if let Ok(subscribers) = SUBSCRIBERS.lock() {
+        for (_, f) in subscribers.1.iter() {
+            f(&record);
+        }
+    }
+}
+
+/// 无界面运行时的事件注册，只解码并发布数据
+pub fn event() -> Event {
+    Event::new()
+        .register(CmdCode::BMS(BMSCode::Info), bms::bms_info_event)
+        .register(CmdCode::Motor(MotorCode::Info), motor::motor_info_event)
+        .register(CmdCode::System(SystemCode::Ack), ack::ack_event)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn subscribe_test() {
+        let received = Arc::new(Mutex::new(vec![]));
+        let r = received.clone();
+        let subscription = subscribe(move |record| {
+            if record.type_id == 9 {
+                r.lock().unwrap().push((record.kind, record.row.to_vec()));
+            }
+        });
+        let header = vec!["seq".to_string()];
+        publish(Kind::Ack, 9, 1, 0, &header, &[1.into()]);
+        publish(Kind::Bms, 9, 1, 0, &header, &[2.into()]);
+        drop(subscription);
+        publish(Kind::Bms, 9, 1, 0, &header, &[3.into()]);
+        assert_eq!(
+            *received.lock().unwrap(),
+            [(Kind::Ack, vec![1.into()]), (Kind::Bms, vec![2.into()])]
+        );
+        assert!(Kind::Ack.is_event() && !Kind::Bms.is_event());
+    }
+}
@@ -0,0 +1,101 @@
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+#[derive(Debug, Clone)]
+pub enum ArgsError {
+    MissingValue(String),
+    InvalidValue(String),
+}
+
+impl std::fmt::Display for ArgsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ArgsError::MissingValue(arg) => {
+                write!(f, "missing value for {}", arg)
+            }
+            ArgsError::InvalidValue(arg) => {
+                write!(f, "invalid value for {}", arg)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ArgsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            ArgsError::MissingValue(_) => None,
+            ArgsError::InvalidValue(_) => None,
+        }
+    }
+}
+
+/// 命令行参数name之后的值，未指定该参数时返回None
+pub fn value<'a>(args: &'a [String], name: &str) -> Result<Option<&'a String>> {
+    match args.iter().position(|a| a == name) {
+        Some(i) => match args.get(i + 1) {
+            Some(v) if !v.starts_with("--") => Ok(Some(v)),
+            _ => Err(ArgsError::MissingValue(name.into()).into()),
+        },
+        None => Ok(None),
+    }
+}
+
+/// 大于0的数值参数
+pub fn positive(args: &[String], name: &str) -> Result<Option<f64>> {
+    match value(args, name)? {
+        Some(v) => match v.parse::<f64>() {
+            Ok(n) if n > 0.0 => Ok(Some(n)),
+            _ => Err(ArgsError::InvalidValue(name.into()).into()),
+        },
+        None => Ok(None),
+    }
+}
+
+/// 是否指定了不带值的参数
+pub fn flag(args: &[String], name: &str) -> bool {
+    args.iter().any(|a| a == name)
+}
+
+/// 从start开始的位置参数，跳过选项及其值，switches为不带值的选项
+pub fn positional<'a>(args: &'a [String], start: usize, switches: &[&str]) -> Vec<&'a String> {
+    let mut ret = vec![];
+    let mut iter = args.iter().skip(start);
+    while let Some(arg) = iter.next() {
+        if !arg.starts_with("--") {
+            ret.push(arg);
+        } else if !switches.contains(&arg.as_str()) {
+            iter.next();
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn args_test() {
+        let args: Vec<String> = ["app", "--a", "x", "--b", "--c", "-1", "--d", "2.5"]
+            .iter()
+            .map(|&s| s.into())
+            .collect();
+        assert_eq!(value(&args, "--a").unwrap().unwrap(), "x");
+        assert!(value(&args, "--b").is_err());
+        assert_eq!(value(&args, "--e").unwrap(), None);
+        assert!(positive(&args, "--a").is_err());
+        assert!(positive(&args, "--c").is_err());
+        assert_eq!(positive(&args, "--d").unwrap(), Some(2.5));
+        assert!(flag(&args, "--b"));
+        assert!(!flag(&args, "--e"));
+    }
+
+    #[test]
+    fn positional_test() {
+        let args: Vec<String> = ["app", "send", "--json", "1", "--baud", "9600", "2", "3"]
+            .iter()
+            .map(|&s| s.into())
+            .collect();
+        assert_eq!(positional(&args, 2, &["--json"]), ["1", "2", "3"]);
+        assert_eq!(positional(&args, 2, &[]), ["2", "3"]);
+    }
+}
@@ -20,6 +20,22 @@ pub fn crc8_slice_with_ccitt(buf: &[u8]) -> u8 {
     return checksum;
 }
 
+/// 计算字节切片的CRC32校验和（IEEE 802.3）
+pub fn crc32_slice(buf: &[u8]) -> u32 {
+    let mut crc = 0xffffffffu32;
+    for b in buf {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            if (crc & 1) != 0 {
+                crc = (crc >> 1) ^ 0xedb88320;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+    !crc
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -30,4 +46,10 @@ mod tests {
         let result = crc8_slice_with_ccitt(&data[..]);
         assert_eq!(result, 0x85);
     }
+
+    #[test]
+    fn crc32_slice_test() {
+        let result = crc32_slice("123456789".as_bytes());
+        assert_eq!(result, 0xcbf43926);
+    }
 }
@@ -0,0 +1,97 @@
+use std::collections::VecDeque;
+
+/// 保留的最大事件数
+const MAX_EVENTS: usize = 100;
+
+/// 标志位变化事件
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FlagEvent {
+    pub index: usize,
+    pub raised: bool,
+    pub time: u64, // us
+}
+
+/// 标志位跟踪
+///
+/// 比较前后两次的标志位，记录每一位置位与清除的时刻
+#[derive(Debug)]
+pub struct FlagTracker {
+    size: usize,
+    bits: u32,
+    raised_time: Vec<Option<u64>>,
+    events: VecDeque<FlagEvent>,
+}
+
+impl FlagTracker {
+    pub fn new(size: usize) -> Self {
+        Self {
+            size,
+            bits: 0,
+            raised_time: vec![None; size],
+            events: VecDeque::new(),
+        }
+    }
+
+    /// 更新标志位，返回本次发生变化的事件
+    pub fn update(&mut self, bits: u32, time: u64) -> Vec<FlagEvent> {
+        let mut events = vec![];
+        let changed = self.bits ^ bits;
+        for index in 0..self.size {
+            if changed & (1 << index) == 0 {
+                continue;
+            }
+            let raised = bits & (1 << index) != 0;
+            self.raised_time[index] = if raised { Some(time) } else { None };
+            events.push(FlagEvent {
+                index,
+                raised,
+                time,
+            });
+        }
+        self.bits = bits;
+        for event in events.iter() {
+            if self.events.len() >= MAX_EVENTS {
+                self.events.pop_front();
+            }
+            self.events.push_back(*event);
+        }
+        events
+    }
+
+    /// 当前置位的标志位被置位的时刻
+    pub fn get_raised_time(&self, index: usize) -> Option<u64> {
+        self.raised_time.get(index).copied().flatten()
+    }
+
+    /// 历史事件，按时间先后排列
+    pub fn get_events(&self) -> &VecDeque<FlagEvent> {
+        &self.events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flag_tracker_test() {
+        let mut tracker = FlagTracker::new(8);
+        let events = tracker.update(0b101, 10);
+        assert_eq!(events.len(), 2);
+        assert!(events.iter().all(|e| e.raised && e.time == 10));
+        assert!(tracker.update(0b101, 20).is_empty());
+
+        let events = tracker.update(0b100, 30);
+        assert_eq!(
+            events,
+            vec![FlagEvent {
+                index: 0,
+                raised: false,
+                time: 30
+            }]
+        );
+        assert_eq!(tracker.get_raised_time(0), None);
+        assert_eq!(tracker.get_raised_time(2), Some(10));
+        assert_eq!(tracker.get_events().len(), 3);
+    }
+}
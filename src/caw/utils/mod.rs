@@ -1 +1,3 @@
+pub mod args;
 pub mod crypto;
+pub mod flags;
@@ -9,32 +9,345 @@ use ui::*;
 
 mod caw;
 use caw::{
+    alarm,
+    api::{self, ApiContext},
+    bms::{self, bms_info_protocol},
+    cli, clock, composer,
     connector::Connector,
-    devices::{self, serial::Serial},
+    devices::{
+        self,
+        capture::{self, Capture},
+        device::Device,
+        monitor::Monitor,
+        replay::Replay,
+        serial::Serial,
+    },
     event::Event,
+    firmware::updater::{FirmwareState, FirmwareUpdater},
+    inspector, metrics, mqtt, pcap,
     protocols::{
-        bms::bms_info_protocol,
-        code::{BMSCode, CmdCode},
-        discover::{self, DISCOVER_MAGIC},
+        ack::{self, ack_protocol, AckStatus},
+        code::{BMSCode, CmdCode, MotorCode, SystemCode},
+        discover::{self, TypeId, DISCOVER_BAUD_RATE, DISCOVER_MAGIC},
+        motor::{self, motor_info_protocol},
+        protocol::FrameBuffer,
+        system,
     },
+    recorder::{self, RecordOptions},
+    script::{self, ScriptContext},
+    utils::args,
 };
 
 use lazy_static::lazy_static;
-use slint::{VecModel, Weak};
+use slint::{SharedString, VecModel, Weak};
 
 lazy_static! {
     static ref CONNECTORS: Mutex<HashMap<u32, HashMap<u32, Connector>>> =
         Mutex::new(HashMap::new());
+    /// 待加入的设备(如回放)，由设备搜索任务在运行时中创建连接
+    static ref PENDING_DEVICES: Mutex<Vec<Box<dyn Device + Send>>> = Mutex::new(vec![]);
 }
 
-use std::{collections::HashMap, sync::Mutex, thread, time::Duration};
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{atomic::Ordering, Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+/// 等待设备编号烧录应答的超时时间
+const PROVISION_TIMEOUT: Duration = Duration::from_secs(2);
 
-use crate::caw::chart;
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
 /// 事件注册
 fn event_build() -> Event {
-    Event::new().register(CmdCode::BMS(BMSCode::Info), bms_info_protocol)
+    Event::new()
+        .register(CmdCode::BMS(BMSCode::Info), bms_info_protocol)
+        .register(CmdCode::Motor(MotorCode::Info), motor_info_protocol)
+        .register(CmdCode::System(SystemCode::Ack), ack_protocol)
+}
+
+/// 查找指定设备的连接
+fn find_connector<T, F>(type_id: u32, device_id: u32, f: F) -> Option<T>
+where
+    F: FnOnce(&Connector) -> T,
+{
+    CONNECTORS.lock().ok().and_then(|type_map| {
+        type_map
+            .get(&type_id)
+            .and_then(|id_map| id_map.get(&device_id))
+            .map(f)
+    })
+}
+
+/// 对指定设备执行操作
+fn with_device<F>(type_id: u32, device_id: u32, f: F) -> Result<()>
+where
+    F: FnOnce(&mut Box<dyn Device + Send>) -> Result<()>,
+{
+    let device =
+        find_connector(type_id, device_id, |conn| conn.get_device()).ok_or("device not found")?;
+    let mut device = device.lock().map_err(|_| "device lock poisoned")?;
+    f(&mut device)
+}
+
+fn set_device_message(ui: &Weak<AppWindow>, message: String) {
+    let _ = ui.upgrade_in_event_loop(move |handle| {
+        handle
+            .global::<DeviceModelService>()
+            .set_message(message.into());
+    });
+}
+
+/// 发送系统指令，执行结果由设备应答
+fn system_command(type_id: i32, device_id: i32, action: SharedString, ui: &Weak<AppWindow>) {
+    let ret = with_device(type_id as u32, device_id as u32, |device| {
+        match action.as_str() {
+            "identify" => system::identify(device),
+            "reboot" => system::reboot(device),
+            "factory-reset" => system::factory_reset(device),
+            _ => Err(format!("unknown action: {}", action).into()),
+        }
+    });
+    println!("system command {} {} -> {:?}", device_id, action, ret);
+    let message = match ret {
+        Ok(_) => format!("device-id: {} {} sent", device_id, action),
+        Err(e) => format!("device-id: {} {} failed: {}", device_id, action, e),
+    };
+    set_device_message(ui, message);
+}
+
+/// 以新的设备编号重新登记连接
+fn rekey_connector(type_id: u32, device_id: u32, new_device_id: u32) -> Result<()> {
+    // 只修改名称时编号不变，无需重新登记
+    if new_device_id == device_id {
+        return Ok(());
+    }
+    let mut type_map = CONNECTORS.lock().map_err(|_| "connectors lock poisoned")?;
+    let id_map = type_map.get_mut(&type_id).ok_or("device not found")?;
+    if id_map.contains_key(&new_device_id) {
+        return Err("device-id already in use".into());
+    }
+    let conn = id_map.remove(&device_id).ok_or("device not found")?;
+    if let Ok(mut device) = conn.get_device().lock() {
+        device.set_id(new_device_id, type_id);
+    }
+    id_map.insert(new_device_id, conn);
+    bms::rekey_device(type_id, device_id, new_device_id);
+    motor::rekey_device(type_id, device_id, new_device_id);
+    inspector::rekey_device(type_id, device_id, new_device_id);
+    api::rekey_device(type_id, device_id, new_device_id);
+    clock::rekey_device(type_id, device_id, new_device_id);
+    metrics::rekey_device(type_id, device_id, new_device_id);
+    Ok(())
+}
+
+/// 烧录设备编号及名称
+///
+/// 设备应答成功后以新编号重新登记连接
+fn provision_device(
+    type_id: i32,
+    device_id: i32,
+    new_device_id: SharedString,
+    name: SharedString,
+    ui: Weak<AppWindow>,
+) {
+    let (type_id, device_id) = (type_id as u32, device_id as u32);
+    let new_device_id = match new_device_id.trim().parse::<u32>() {
+        Ok(v) => v,
+        Err(_) => {
+            set_device_message(&ui, format!("invalid device-id: {}", new_device_id));
+            return;
+        }
+    };
+    if new_device_id != device_id && find_connector(type_id, new_device_id, |_| ()).is_some() {
+        set_device_message(&ui, format!("device-id: {} already in use", new_device_id));
+        return;
+    }
+    let (device, busy) = match find_connector(type_id, device_id, |conn| {
+        (conn.get_device(), conn.get_busy())
+    }) {
+        Some(target) => target,
+        None => {
+            set_device_message(&ui, "device not found".into());
+            return;
+        }
+    };
+    thread::spawn(move || {
+        busy.store(true, Ordering::Relaxed);
+        let ret = match device.lock() {
+            Ok(mut device) => {
+                system::provision(&mut device, new_device_id, name.trim()).and_then(|_| {
+                    let code = CmdCode::System(SystemCode::Provision);
+                    let mut frames = FrameBuffer::new();
+                    match ack::wait_ack(&mut device, &mut frames, code, 0, PROVISION_TIMEOUT)? {
+                        Some(ack) if ack.get_status() == AckStatus::Ok => Ok(()),
+                        Some(ack) => Err(format!("{:?}", ack.get_status()).into()),
+                        None => Err("ack timeout".into()),
+                    }
+                })
+            }
+            Err(_) => Err("device lock poisoned".into()),
+        };
+        busy.store(false, Ordering::Relaxed);
+        let ret = ret.and_then(|_| rekey_connector(type_id, device_id, new_device_id));
+        println!(
+            "provision {}:{} -> {} {:?}",
+            type_id, device_id, new_device_id, ret
+        );
+        match ret {
+            Ok(_) => {
+                let _ = ui.upgrade_in_event_loop(move |handle| {
+                    let service = handle.global::<DeviceModelService>();
+                    if service.get_current_type_id() == type_id as i32
+                        && service.get_current_device_id() == device_id as i32
+                    {
+                        service.set_current_device_id(new_device_id as i32);
+                    }
+                });
+                set_device_message(
+                    &ui,
+                    format!("device-id: {} -> {} provisioned", device_id, new_device_id),
+                );
+                update_device_list(&ui);
+            }
+            Err(e) => set_device_message(
+                &ui,
+                format!("device-id: {} provision failed: {}", device_id, e),
+            ),
+        }
+    });
+}
+
+/// 向设备发送手动编辑的数据帧，等待应答并显示在编辑器中，超时显示no response
+fn send_frame(
+    type_id: i32,
+    device_id: i32,
+    group: SharedString,
+    code: SharedString,
+    payload: SharedString,
+    ui: Weak<AppWindow>,
+) {
+    let set_response = |ui: &Weak<AppWindow>, response: String| {
+        let _ = ui.upgrade_in_event_loop(move |handle| {
+            handle
+                .global::<ComposerModelService>()
+                .set_response(response.into());
+        });
+    };
+    let (type_id, device_id) = (type_id as u32, device_id as u32);
+    let (group, code, payload) = match composer::compose(&group, &code, &payload) {
+        Ok(frame) => frame,
+        Err(e) => {
+            set_response(&ui, e.to_string());
+            return;
+        }
+    };
+    thread::spawn(move || {
+        let waiter = composer::expect_response(type_id, device_id, (group, code));
+        let ret = with_device(type_id, device_id, |device| {
+            composer::send(device, group, code, &payload)
+        });
+        let response = match ret {
+            Ok(_) => waiter
+                .wait(composer::RESPONSE_TIMEOUT)
+                .map_or_else(|| "no response".into(), |frame| inspector::describe(&frame)),
+            Err(e) => format!("send failed: {}", e),
+        };
+        set_response(&ui, response);
+    });
+}
+
+/// 在设备上运行测试脚本，报告逐条显示，结束后保存在脚本旁
+fn run_script(type_id: i32, device_id: i32, path: SharedString, ui: Weak<AppWindow>) {
+    let (type_id, device_id) = (type_id as u32, device_id as u32);
+    let path = PathBuf::from(path.as_str());
+    let _ = ui.upgrade_in_event_loop(|handle| {
+        let service = handle.global::<ScriptModelService>();
+        service.set_running(true);
+        service.set_output("".into());
+        service.set_result("".into());
+        service.set_report_path("".into());
+    });
+    let append = |ui: &Weak<AppWindow>, line: String| {
+        let _ = ui.upgrade_in_event_loop(move |handle| {
+            let service = handle.global::<ScriptModelService>();
+            let output = format!("{}{}\n", service.get_output(), line);
+            service.set_output(output.into());
+        });
+    };
+    thread::spawn(move || {
+        let ctx = ScriptContext {
+            type_id,
+            device_id,
+            send: Arc::new(move |group: u32, code: u32, data: &[u8]| {
+                with_device(type_id, device_id, |device| {
+                    composer::send(device, group, code, data)
+                })
+            }),
+        };
+        let ui_entry = ui.clone();
+        let ret = script::run(&path, ctx, move |entry| append(&ui_entry, entry.to_line()));
+        let (passed, report_path) = match ret {
+            Ok(report) => {
+                append(&ui, report.summary());
+                let report_path = match script::save_report(&path, &report) {
+                    Ok(out) => out.display().to_string(),
+                    Err(e) => format!("save report failed: {}", e),
+                };
+                (report.passed(), report_path)
+            }
+            Err(e) => {
+                append(&ui, format!("script failed: {}", e));
+                (false, String::new())
+            }
+        };
+        let _ = ui.upgrade_in_event_loop(move |handle| {
+            let service = handle.global::<ScriptModelService>();
+            service.set_running(false);
+            service.set_result(if passed { "PASS" } else { "FAIL" }.into());
+            service.set_report_path(report_path.into());
+        });
+    });
+}
+
+/// 已连接设备的类型与编号
+fn connected_devices() -> Vec<(u32, u32)> {
+    let mut devices: Vec<(u32, u32)> = CONNECTORS
+        .lock()
+        .map(|type_map| {
+            type_map
+                .iter()
+                .flat_map(|(&type_id, id_map)| id_map.keys().map(move |&id| (type_id, id)))
+                .collect()
+        })
+        .unwrap_or_default();
+    devices.sort();
+    devices
+}
+
+/// 接口服务发送数据帧
+fn api_send(type_id: u32, device_id: u32, group: u32, code: u32, payload: &[u8]) -> Result<()> {
+    with_device(type_id, device_id, |device| {
+        composer::send(device, group, code, payload)
+    })
+}
+
+/// 发送电机控制指令
+///
+/// 发送结果显示在设备消息栏，执行结果由设备应答
+fn motor_command<F>(device_id: i32, action: &str, ui: &Weak<AppWindow>, f: F)
+where
+    F: FnOnce(&mut Box<dyn Device + Send>) -> Result<()>,
+{
+    let ret = with_device(TypeId::Motor as u32, device_id as u32, f);
+    let message = match ret {
+        Ok(_) => format!("device-id: {} motor {} sent", device_id, action),
+        Err(e) => format!("device-id: {} motor {} failed: {}", device_id, action, e),
+    };
+    set_device_message(ui, message);
 }
 
 /// 硬件发现服务回调函数
@@ -42,37 +355,66 @@ fn discover_callback(mut device: Serial, buf: &[u8], ui: &Weak<AppWindow>) -> Re
     println!("discover thread id:{:?}", thread::current().id());
     discover::Discover::check_device_magic(&buf[0..4])?;
     let v = discover::Discover::parse(&buf[4..12])?;
+    let (device_id, type_id) = v.get_id();
+    device.set_id(device_id, type_id);
+    add_connector(Box::new(Capture::new(Box::new(device))), ui);
+    Ok(())
+}
+
+/// 为设备创建连接，已有相同编号的设备时不加入
+///
+/// 连接上收发的数据帧都会记录到帧查看器与连接统计
+fn add_connector(device: Box<dyn Device + Send>, ui: &Weak<AppWindow>) -> bool {
+    let (device_id, type_id) = device.get_id();
     if let Ok(mut type_map) = CONNECTORS.lock() {
-        let (device_id, type_id) = v.get_id();
         if !type_map.contains_key(&type_id) {
             type_map.insert(type_id, HashMap::new());
         }
         if let Some(id_map) = type_map.get_mut(&type_id) {
             if !id_map.contains_key(&device_id) {
-                device.set_id(device_id, type_id);
-                let mut connector = Connector::new(Box::new(device));
+                let mut connector = Connector::new(Box::new(Monitor::new(device)));
                 connector.event_loop(event_build(), ui.clone());
                 id_map.insert(device_id, connector);
+                metrics::count_connect(type_id, device_id);
                 println!("insert device: type_id:{} device_id:{}", type_id, device_id);
+                return true;
             }
         }
     }
+    false
+}
+
+/// 导出会话文件为pcapng，未指定输出文件时与会话文件同名
+fn export_pcap(session: &str, out: Option<&str>) -> Result<String> {
+    let session = PathBuf::from(session);
+    let out = out.map_or_else(|| session.with_extension("pcapng"), PathBuf::from);
+    let count = pcap::export(&session, &out)?;
+    Ok(format!("exported {} frames to {}", count, out.display()))
+}
+
+/// 回放会话文件，speed为回放倍速
+fn replay_session(path: &str, speed: f32) -> Result<()> {
+    let replay = Replay::open(path, speed)?;
+    let (device_id, type_id) = replay.get_id();
+    if find_connector(type_id, device_id, |_| ()).is_some() {
+        return Err(format!("device {} already connected", device_id).into());
+    }
+    if let Ok(mut pending) = PENDING_DEVICES.lock() {
+        pending.push(Box::new(replay));
+    }
     Ok(())
 }
 
 fn update_device_list(handle: &slint::Weak<AppWindow>) {
     let mut items: Vec<_> = vec![];
-    if let Ok(mut type_map) = CONNECTORS.lock() {
-        for (_, id_map) in type_map.iter_mut() {
-            for (_, conn) in id_map {
-                if let Ok(device) = conn.get_device().lock() {
-                    let (device_id, type_id) = device.get_id();
-                    items.push(DeviceItemData {
-                        device_id: device_id as i32,
-                        type_id: type_id as i32,
-                        soc: 0.0,
-                    });
-                }
+    if let Ok(type_map) = CONNECTORS.lock() {
+        for (type_id, id_map) in type_map.iter() {
+            for device_id in id_map.keys() {
+                items.push(DeviceItemData {
+                    device_id: *device_id as i32,
+                    type_id: *type_id as i32,
+                    soc: bms::get_soc(*type_id, *device_id).unwrap_or(0.0),
+                });
             }
         }
     }
@@ -89,26 +431,515 @@ fn update_device_list(handle: &slint::Weak<AppWindow>) {
     });
 }
 
+fn set_firmware_status(ui: &Weak<AppWindow>, running: bool, progress: f32, status: String) {
+    let _ = ui.upgrade_in_event_loop(move |handle| {
+        let service = handle.global::<FirmwareModelService>();
+        service.set_running(running);
+        service.set_progress(progress);
+        service.set_status(status.into());
+    });
+}
+
+/// 固件升级
+///
+/// 升级期间独占设备，事件循环暂停收发
+fn firmware_update(type_id: i32, device_id: i32, path: SharedString, ui: Weak<AppWindow>) {
+    let target = find_connector(type_id as u32, device_id as u32, |conn| {
+        (conn.get_device(), conn.get_busy())
+    });
+    let (device, busy) = match target {
+        Some(target) => target,
+        None => {
+            set_firmware_status(&ui, false, 0.0, "device not found".into());
+            return;
+        }
+    };
+    set_firmware_status(&ui, true, 0.0, "loading image".into());
+    thread::spawn(move || {
+        busy.store(true, Ordering::Relaxed);
+        let ret = FirmwareUpdater::open(path.as_str()).and_then(|updater| match device.lock() {
+            Ok(mut device) => updater.run(&mut device, |p| {
+                let status = match p.state {
+                    FirmwareState::Bootloader => "entering bootloader".into(),
+                    FirmwareState::Writing if p.retries > 0 => format!(
+                        "writing {}/{} bytes, {} retries",
+                        p.sent, p.total, p.retries
+                    ),
+                    FirmwareState::Writing => format!("writing {}/{} bytes", p.sent, p.total),
+                    FirmwareState::Verifying => "verifying image checksum".into(),
+                    FirmwareState::Rebooting => "rebooting".into(),
+                    FirmwareState::Done => "done".into(),
+                };
+                set_firmware_status(&ui, true, p.percent(), status);
+            }),
+            Err(_) => Err("device lock poisoned".into()),
+        });
+        busy.store(false, Ordering::Relaxed);
+        println!("firmware update {}:{} -> {:?}", type_id, device_id, ret);
+        match ret {
+            Ok(_) => set_firmware_status(&ui, false, 1.0, "done".into()),
+            Err(e) => set_firmware_status(&ui, false, 0.0, format!("failed: {}", e)),
+        }
+    });
+}
+
 fn main() -> std::result::Result<(), slint::PlatformError> {
+    let args: Vec<String> = std::env::args().collect();
+    // 第一个参数为子命令时以命令行模式运行，如 ports、discover、monitor <device>
+    if let Some(ret) = cli::run(&args) {
+        if let Err(e) = ret {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
     println!("main thread id:{:?}", thread::current().id());
+    // --export-pcap <session> [--pcap-out <file>] 导出后直接退出
+    if let Ok(Some(session)) = args::value(&args, "--export-pcap") {
+        let out = args::value(&args, "--pcap-out").ok().flatten();
+        match export_pcap(session, out.map(|s| s.as_str())) {
+            Ok(message) => println!("{}", message),
+            Err(e) => println!("export pcap failed: {}", e),
+        }
+        return Ok(());
+    }
     let ui = AppWindow::new().unwrap();
     let ui_weak = ui.as_weak();
 
-    ui.global::<BMSModelService>()
-        .on_build_v_plot(chart::plot::render_plot);
-    ui.global::<BMSModelService>()
-        .on_build_c_plot(chart::plot::render_plot);
+    let bms_service = ui.global::<BMSModelService>();
+    let ui_plot = ui.as_weak();
+    bms_service.on_build_v_plot(move |width, height, _| {
+        ui_plot
+            .upgrade()
+            .map(|handle| bms::plot::render_voltage_plot(&handle, width, height))
+            .unwrap_or_default()
+    });
+    let ui_plot = ui.as_weak();
+    bms_service.on_build_c_plot(move |width, height, _| {
+        ui_plot
+            .upgrade()
+            .map(|handle| bms::plot::render_current_plot(&handle, width, height))
+            .unwrap_or_default()
+    });
+    let ui_plot = ui.as_weak();
+    bms_service.on_build_s_plot(move |width, height, _| {
+        ui_plot
+            .upgrade()
+            .map(|handle| bms::plot::render_spread_plot(&handle, width, height))
+            .unwrap_or_default()
+    });
+    let ui_plot = ui.as_weak();
+    bms_service.on_toggle_plot_series(move |index| {
+        if let Some(handle) = ui_plot.upgrade() {
+            bms::plot::toggle_plot_series(&handle, index as usize);
+        }
+    });
+    let ui_plot = ui.as_weak();
+    bms_service.on_plot_window(move |minutes| {
+        if let Some(handle) = ui_plot.upgrade() {
+            bms::plot::set_plot_window(&handle, minutes);
+        }
+    });
+    let ui_plot = ui.as_weak();
+    bms_service.on_plot_zoom(move |anchor, steps| {
+        if let Some(handle) = ui_plot.upgrade() {
+            bms::plot::zoom_plot(&handle, anchor, steps);
+        }
+    });
+    let ui_plot = ui.as_weak();
+    bms_service.on_plot_pan(move |ratio| {
+        if let Some(handle) = ui_plot.upgrade() {
+            bms::plot::pan_plot(&handle, ratio);
+        }
+    });
+    let ui_plot = ui.as_weak();
+    bms_service.on_plot_follow_changed(move |follow| {
+        if let Some(handle) = ui_plot.upgrade() {
+            bms::plot::set_plot_follow(&handle, follow);
+        }
+    });
+    let ui_energy = ui.as_weak();
+    bms_service.on_set_capacity(move |type_id, device_id, capacity| {
+        if let Some(handle) = ui_energy.upgrade() {
+            bms::analytics::set_capacity(&handle, type_id as u32, device_id as u32, capacity);
+        }
+    });
+    let ui_energy = ui.as_weak();
+    bms_service.on_reset_energy(move |type_id, device_id| {
+        if let Some(handle) = ui_energy.upgrade() {
+            bms::analytics::reset_energy(&handle, type_id as u32, device_id as u32);
+        }
+    });
+    let ui_plot = ui.as_weak();
+    bms_service.on_plot_hover(move |ratio| {
+        if let Some(handle) = ui_plot.upgrade() {
+            bms::plot::plot_cursor(&handle, ratio);
+        }
+    });
+    let ui_history = ui.as_weak();
+    bms_service.on_refresh_history(move || {
+        if let Some(handle) = ui_history.upgrade() {
+            bms::history::refresh_history(&handle);
+        }
+    });
+
+    let alarm_service = ui.global::<AlarmModelService>();
+    let ui_alarm = ui.as_weak();
+    alarm_service.on_add_rule(
+        move |name, field, index, condition, threshold, hysteresis, duration, device_id| {
+            if let Some(handle) = ui_alarm.upgrade() {
+                if let Err(e) = alarm::add_rule(
+                    &handle, &name, field, index, condition, threshold, hysteresis, duration,
+                    device_id,
+                ) {
+                    println!("add alarm rule failed: {}", e);
+                }
+            }
+        },
+    );
+    let ui_alarm = ui.as_weak();
+    alarm_service.on_remove_rule(move |rule_id| {
+        if let Some(handle) = ui_alarm.upgrade() {
+            alarm::remove_rule(&handle, rule_id as u32);
+        }
+    });
+    let ui_alarm = ui.as_weak();
+    alarm_service.on_set_rule_enabled(move |rule_id, enabled| {
+        if let Some(handle) = ui_alarm.upgrade() {
+            alarm::set_rule_enabled(&handle, rule_id as u32, enabled);
+        }
+    });
+    let ui_alarm = ui.as_weak();
+    alarm_service.on_ack(move |entry_id| {
+        if let Some(handle) = ui_alarm.upgrade() {
+            alarm::ack(&handle, entry_id);
+        }
+    });
+    let ui_alarm = ui.as_weak();
+    alarm_service.on_clear_log(move || {
+        if let Some(handle) = ui_alarm.upgrade() {
+            alarm::clear_log(&handle);
+        }
+    });
+    alarm::show_alarms(&ui);
+
+    let inspector_service = ui.global::<InspectorModelService>();
+    let ui_inspector = ui.as_weak();
+    inspector_service.on_set_filter(move |filter| {
+        if let Some(handle) = ui_inspector.upgrade() {
+            inspector::set_filter(&handle, &filter);
+        }
+    });
+    let ui_inspector = ui.as_weak();
+    inspector_service.on_select(move |index| {
+        if let Some(handle) = ui_inspector.upgrade() {
+            inspector::select(&handle, index);
+        }
+    });
+    let ui_inspector = ui.as_weak();
+    inspector_service.on_pause(move |paused| {
+        if let Some(handle) = ui_inspector.upgrade() {
+            inspector::set_paused(&handle, paused);
+        }
+    });
+    let ui_inspector = ui.as_weak();
+    inspector_service.on_clear(move || {
+        if let Some(handle) = ui_inspector.upgrade() {
+            inspector::clear(&handle);
+        }
+    });
+    let composer_service = ui.global::<ComposerModelService>();
+    let ui_composer = ui.as_weak();
+    composer_service.on_select_command(move |index| {
+        if let Some(handle) = ui_composer.upgrade() {
+            composer::select_command(&handle, index);
+        }
+    });
+    let ui_composer = ui.as_weak();
+    composer_service.on_set_field(move |index, value| {
+        if let Some(handle) = ui_composer.upgrade() {
+            composer::set_field(&handle, index, &value);
+        }
+    });
+    let ui_composer = ui.as_weak();
+    composer_service.on_send(move |group, code, payload| {
+        if let Some(handle) = ui_composer.upgrade() {
+            let device = handle.global::<DeviceModelService>();
+            handle
+                .global::<ComposerModelService>()
+                .set_response("waiting for response...".into());
+            send_frame(
+                device.get_current_type_id(),
+                device.get_current_device_id(),
+                group,
+                code,
+                payload,
+                ui_composer.clone(),
+            );
+        }
+    });
+    let ui_composer = ui.as_weak();
+    composer_service.on_save_snippet(move |name| {
+        if let Some(handle) = ui_composer.upgrade() {
+            composer::save_snippet(&handle, &name);
+        }
+    });
+    let ui_composer = ui.as_weak();
+    composer_service.on_load_snippet(move |index| {
+        if let Some(handle) = ui_composer.upgrade() {
+            composer::load_snippet(&handle, index);
+        }
+    });
+    let ui_composer = ui.as_weak();
+    composer_service.on_remove_snippet(move |index| {
+        if let Some(handle) = ui_composer.upgrade() {
+            composer::remove_snippet(&handle, index);
+        }
+    });
+    composer::show_composer(&ui);
+
+    let script_service = ui.global::<ScriptModelService>();
+    let ui_script = ui.as_weak();
+    script_service.on_run(move |type_id, device_id, path| {
+        run_script(type_id, device_id, path, ui_script.clone())
+    });
+    script_service.on_stop(script::stop);
+    // 帧查看器打开时定时刷新
+    let inspector_timer = slint::Timer::default();
+    let ui_inspector = ui.as_weak();
+    inspector_timer.start(
+        slint::TimerMode::Repeated,
+        Duration::from_millis(500),
+        move || {
+            if let Some(handle) = ui_inspector.upgrade() {
+                if handle.global::<InspectorModelService>().get_open() {
+                    inspector::show_frames(&handle, false);
+                }
+            }
+        },
+    );
+
+    let ui_record = ui.as_weak();
+    bms_service.on_start_record(move |dir, max_size, max_minutes| {
+        let mut options = RecordOptions::new(if dir.is_empty() {
+            recorder::DEFAULT_RECORD_DIR
+        } else {
+            dir.as_str()
+        });
+        if max_size > 0.0 {
+            options.max_size = (max_size as f64 * 1024.0 * 1024.0) as u64;
+        }
+        if max_minutes > 0.0 {
+            options.max_duration = (max_minutes as f64 * 60.0 * 1_000_000.0) as u64;
+        }
+        if let Err(e) = bms::record::start_record(options) {
+            println!("start record failed: {}", e);
+        }
+        if let Some(handle) = ui_record.upgrade() {
+            bms::record::show_record_status(&handle);
+        }
+    });
+    let ui_record = ui.as_weak();
+    bms_service.on_stop_record(move || {
+        bms::record::stop_record();
+        if let Some(handle) = ui_record.upgrade() {
+            bms::record::show_record_status(&handle);
+        }
+    });
+    // 命令行指定--record时启动即开始记录
+    match RecordOptions::from_args(&args) {
+        Ok(Some(options)) => {
+            if let Err(e) = bms::record::start_record(options) {
+                println!("start record failed: {}", e);
+            }
+        }
+        Ok(None) => {}
+        Err(e) => println!("{}", e),
+    }
+    bms::record::show_record_status(&ui);
+
+    let device_service = ui.global::<DeviceModelService>();
+    let ui_session = ui.as_weak();
+    device_service.on_set_capture(move |enable| {
+        let dir = enable.then(|| capture::DEFAULT_CAPTURE_DIR.into());
+        if let Err(e) = capture::set_capture_dir(dir) {
+            set_device_message(&ui_session, format!("capture failed: {}", e));
+        }
+        if let Some(handle) = ui_session.upgrade() {
+            handle
+                .global::<DeviceModelService>()
+                .set_capturing(capture::is_capturing());
+        }
+    });
+    let ui_session = ui.as_weak();
+    device_service.on_replay(move |path, speed| {
+        if let Err(e) = replay_session(&path, speed) {
+            set_device_message(&ui_session, format!("replay {} failed: {}", path, e));
+        }
+    });
+    let ui_session = ui.as_weak();
+    device_service.on_export_pcap(move |path| {
+        let message =
+            export_pcap(&path, None).unwrap_or_else(|e| format!("export pcap failed: {}", e));
+        set_device_message(&ui_session, message);
+    });
+    // 命令行参数：--capture <dir> 抓取收发数据，--replay <file> [--replay-speed <x>] 回放会话
+    match args::value(&args, "--capture") {
+        Ok(Some(dir)) => {
+            if let Err(e) = capture::set_capture_dir(Some(dir.into())) {
+                println!("capture failed: {}", e);
+            }
+        }
+        Ok(None) => {}
+        Err(e) => println!("{}", e),
+    }
+    device_service.set_capturing(capture::is_capturing());
+    let replay = args::value(&args, "--replay").and_then(|path| {
+        let speed = args::positive(&args, "--replay-speed")?.unwrap_or(1.0);
+        path.map_or(Ok(()), |path| replay_session(path, speed as f32))
+    });
+    if let Err(e) = replay {
+        println!("replay failed: {}", e);
+    }
+    // --api [addr] 启用HTTP/WebSocket接口，默认只监听本机
+    let api_addr = match args::value(&args, "--api") {
+        Ok(Some(addr)) => api::parse_addr(addr)
+            .map_err(|e| println!("invalid api address {}: {}", addr, e))
+            .ok(),
+        _ if args::flag(&args, "--api") => Some(api::DEFAULT_API_ADDR),
+        _ => None,
+    };
+    // --metrics [addr] 启用Prometheus指标导出
+    let metrics_addr = metrics::addr_arg(&args).unwrap_or_else(|e| {
+        println!("invalid metrics address: {}", e);
+        None
+    });
+    // --mqtt <host[:port]> [--mqtt-user <name>] [--mqtt-pass <password>]
+    // 将遥测数据发布到MQTT代理服务器
+    let mqtt_broker = mqtt::BrokerOptions::from_args(&args).unwrap_or_else(|e| {
+        println!("{}", e);
+        None
+    });
+
+    let ui_select = ui.as_weak();
+    ui.global::<DeviceModelService>()
+        .on_select(move |type_id, device_id| {
+            if let Some(handle) = ui_select.upgrade() {
+                let service = handle.global::<DeviceModelService>();
+                service.set_current_type_id(type_id);
+                service.set_current_device_id(device_id);
+                if type_id == TypeId::BMS as i32 {
+                    bms::view::show_device(&handle, type_id as u32, device_id as u32);
+                } else if type_id == TypeId::Motor as i32 {
+                    motor::show_device(&handle, type_id as u32, device_id as u32);
+                }
+            }
+        });
+    let ui_system = ui.as_weak();
+    ui.global::<DeviceModelService>()
+        .on_system_command(move |type_id, device_id, action| {
+            system_command(type_id, device_id, action, &ui_system)
+        });
+    let ui_provision = ui.as_weak();
+    ui.global::<DeviceModelService>().on_provision(
+        move |type_id, device_id, new_device_id, name| {
+            provision_device(
+                type_id,
+                device_id,
+                new_device_id,
+                name,
+                ui_provision.clone(),
+            )
+        },
+    );
+
+    motor::show_fault_names(&ui);
+    let motor_service = ui.global::<MotorModelService>();
+    let ui_motor = ui.as_weak();
+    motor_service.on_enable({
+        let ui = ui_motor.clone();
+        move |device_id, enable| {
+            motor_command(device_id, "enable", &ui, |device| {
+                motor::enable(device, enable)
+            })
+        }
+    });
+    motor_service.on_set_speed({
+        let ui = ui_motor.clone();
+        move |device_id, rpm| {
+            motor_command(device_id, "set-speed", &ui, |device| {
+                motor::set_speed(device, rpm)
+            })
+        }
+    });
+    motor_service.on_set_torque({
+        let ui = ui_motor.clone();
+        move |device_id, current| {
+            motor_command(device_id, "set-torque", &ui, |device| {
+                motor::set_torque(device, current)
+            })
+        }
+    });
+    motor_service.on_set_position({
+        let ui = ui_motor.clone();
+        move |device_id, position| {
+            motor_command(device_id, "set-position", &ui, |device| {
+                motor::set_position(device, position)
+            })
+        }
+    });
+    motor_service.on_stop({
+        let ui = ui_motor.clone();
+        move |device_id| motor_command(device_id, "stop", &ui, motor::stop)
+    });
+    motor_service.on_clear_fault(move |device_id| {
+        motor_command(device_id, "clear-fault", &ui_motor, motor::clear_fault)
+    });
+
+    let ui_firmware = ui.as_weak();
+    ui.global::<FirmwareModelService>()
+        .on_start_update(move |type_id, device_id, path| {
+            firmware_update(type_id, device_id, path, ui_firmware.clone())
+        });
 
     let rt = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()
         .unwrap();
     rt.block_on(async move {
+        let ctx = ApiContext {
+            devices: connected_devices,
+            send: api_send,
+        };
+        if let Some(addr) = metrics_addr {
+            tokio::spawn(async move {
+                if let Err(e) = metrics::serve(addr).await {
+                    println!("metrics server failed: {}", e);
+                }
+            });
+        }
+        if let Some(broker) = mqtt_broker {
+            tokio::spawn(mqtt::run(broker, ctx));
+        }
+        if let Some(addr) = api_addr {
+            tokio::spawn(async move {
+                if let Err(e) = api::serve(addr, ctx).await {
+                    println!("api server failed: {}", e);
+                }
+            });
+        }
         tokio::spawn(async move {
             loop {
                 let mut has_change = false;
+                let pending: Vec<Box<dyn Device + Send>> = PENDING_DEVICES
+                    .lock()
+                    .map(|mut pending| pending.drain(..).collect())
+                    .unwrap_or_default();
+                for device in pending {
+                    has_change |= add_connector(device, &ui_weak);
+                }
                 if let Ok(_) = devices::serial::Serial::search(
-                    128000,
+                    DISCOVER_BAUD_RATE,
                     DISCOVER_MAGIC.as_slice(),
                     discover_callback,
                     &ui_weak,
@@ -144,6 +975,12 @@ fn main() -> std::result::Result<(), slint::PlatformError> {
         center_window(ui.window());
         ui.run()
     });
+    // 断开全部设备，保存未写入的历史数据
+    if let Ok(mut type_map) = CONNECTORS.lock() {
+        type_map.clear();
+    }
+    bms::history::sync();
+    rt.block_on(mqtt::shutdown());
     rt.shutdown_background();
     ret
 }